@@ -1,22 +1,172 @@
+mod config;
+
+pub use config::{Bookmark, Config, ConfigStore};
+
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use log::{warn, LevelFilter};
+use structopt::StructOpt;
+
 use crate::coffee_audio::AudioController;
-use crate::coffee_network::NetworkController;
+use crate::coffee_network::{
+    Identity, NetworkController, TextChatController, TransportError, TrustStore,
+    DEFAULT_PEER_TIMEOUT,
+};
+
+const CONFIG_FILE: &str = "config.toml";
+const LOG_FILE: &str = "coffeeshop.log";
+// Each room's chat, kept next to the config file
+const HISTORY_DIR: &str = "history";
+
+// How to start the app. Anything left out is asked for at startup. (A doc
+// comment here would replace the app's own description in --help.)
+#[derive(StructOpt, Clone, Debug)]
+pub struct AppOptions {
+    /// Name to show everyone else
+    #[structopt(short, long)]
+    pub nickname: Option<String>,
+    /// Address to accept peers on [default: 0.0.0.0]
+    #[structopt(short, long)]
+    pub bind: Option<IpAddr>,
+    /// Port to accept peers on; 0 picks any free one
+    #[structopt(short, long)]
+    pub port: Option<u16>,
+    /// Peers to connect to once started
+    #[structopt(short, long, number_of_values = 1)]
+    pub connect: Vec<String>,
+    /// Seconds a peer can go unheard before it's dropped [default: 10]
+    #[structopt(long)]
+    pub peer_timeout: Option<u64>,
+    /// Config file to use, with our identity kept alongside it [default:
+    /// config.toml in the app's config directory]
+    #[structopt(long, parse(from_os_str))]
+    pub config: Option<PathBuf>,
+    /// How much to log: off, error, warn, info, debug or trace
+    #[structopt(long, default_value = "info")]
+    pub log_level: LevelFilter,
+    /// File to append the log to [default: coffeeshop.log in the app's
+    /// config directory, or none for a relay]
+    #[structopt(long, parse(from_os_str))]
+    pub log_file: Option<PathBuf>,
+}
+
+impl AppOptions {
+    /// The config file, if we have anywhere to put one.
+    pub fn config_file(&self) -> Option<PathBuf> {
+        self.config
+            .clone()
+            .or_else(|| config_dir().map(|dir| dir.join(CONFIG_FILE)))
+    }
+
+    /// The log file, if we have anywhere to put one.
+    pub fn log_file(&self) -> Option<PathBuf> {
+        self.log_file
+            .clone()
+            .or_else(|| config_dir().map(|dir| dir.join(LOG_FILE)))
+    }
+
+    /// How long a peer can go unheard before it's dropped.
+    pub fn peer_timeout(&self) -> Duration {
+        self.peer_timeout
+            .map_or(DEFAULT_PEER_TIMEOUT, Duration::from_secs)
+    }
+
+    /// Where to accept peers, from the command line or else the config.
+    pub fn address(&self, config: &Config, port: u16) -> SocketAddr {
+        let bind = self
+            .bind
+            .or(config.bind)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        SocketAddr::new(bind, port)
+    }
+}
 
 #[derive(Clone)]
 pub struct CoffeeAppContext {
+    config: ConfigStore,
     net_controller: NetworkController,
+    chat_controller: TextChatController,
     audio_controller: AudioController,
 }
 
 impl CoffeeAppContext {
-    pub fn construct(port_num: u16, username: String) -> Self {
-        let net_controller = NetworkController::new_with_port_and_username(port_num, username);
-        CoffeeAppContext {
+    /// Starts everything up with the settings in `config`, keeping our
+    /// identity wherever it says and each room's chat next to the config
+    /// file. Fails if we can't listen for peers.
+    pub fn construct(
+        address: SocketAddr,
+        username: String,
+        peer_timeout: Duration,
+        config: ConfigStore,
+    ) -> Result<Self, TransportError> {
+        let settings = config.get();
+        let identity_dir = settings.identity_dir(config.path().as_deref());
+        let (identity, trust) = load_identity(identity_dir.as_deref());
+        let net_controller = NetworkController::new(
+            address,
+            username,
+            identity,
+            trust,
+            settings.trusted_relay_keys(),
+            peer_timeout,
+        )?;
+        let history_dir = config
+            .path()
+            .as_deref()
+            .and_then(Path::parent)
+            .map(|dir| dir.join(HISTORY_DIR));
+        let chat_controller = TextChatController::new(net_controller.clone(), history_dir);
+        let audio_controller = AudioController::new(net_controller.clone(), settings.audio);
+        Ok(CoffeeAppContext {
+            config,
             net_controller,
-            audio_controller: AudioController::new(),
-        }
+            chat_controller,
+            audio_controller,
+        })
+    }
+
+    pub fn get_config(&self) -> &ConfigStore {
+        &self.config
     }
 
     pub fn get_net_controller(&self) -> &NetworkController {
         &self.net_controller
     }
+
+    pub fn get_chat_controller(&self) -> &TextChatController {
+        &self.chat_controller
+    }
+
+    pub fn get_audio_controller(&self) -> &AudioController {
+        &self.audio_controller
+    }
+}
+
+/// Where coffeeshop keeps its files, e.g. `~/.config/coffeeshop` on Linux.
+pub fn config_dir() -> Option<PathBuf> {
+    dirs::config_dir().map(|dir| dir.join("coffeeshop"))
+}
+
+/// Our identity, kept in `dir`, and the ones we've seen before. If they
+/// can't be stored we carry on with throwaway ones, rather than not
+/// starting at all.
+pub fn load_identity(dir: Option<&Path>) -> (Identity, TrustStore) {
+    let dir = match dir {
+        Some(dir) => dir,
+        None => {
+            warn!("No config directory; using a temporary identity");
+            return (Identity::generate(), TrustStore::in_memory());
+        }
+    };
+    let identity = Identity::load_or_create(dir).unwrap_or_else(|e| {
+        warn!("Unable to load identity, using a temporary one: {}", e);
+        Identity::generate()
+    });
+    let trust = TrustStore::load(dir).unwrap_or_else(|e| {
+        warn!("Unable to load known identities: {}", e);
+        TrustStore::in_memory()
+    });
+    (identity, trust)
 }
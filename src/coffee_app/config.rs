@@ -0,0 +1,448 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+use crate::coffee_audio::settings::AudioSettings;
+use crate::coffee_network::{parse_identity_key, IDENTITY_KEY_SIZE};
+
+/// Layout of the config files this build writes. Bump it for any change
+/// older builds would misread, and add a step to `MIGRATIONS` that brings
+/// the previous layout up to date.
+pub const CONFIG_VERSION: u32 = 1;
+
+// Brings a config file's contents up one version
+type Migration = fn(toml::Value) -> toml::Value;
+
+// Each step takes a file from the version at its index + 1 to the next one.
+// Version 1 is the first layout, so there's nothing to migrate from yet.
+const MIGRATIONS: &[Migration] = &[];
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(toml::de::Error),
+    Write(toml::ser::Error),
+    /// Written by a newer coffeeshop, which may have put things in it we
+    /// don't know about
+    TooNew(u32),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Io(e) => write!(f, "config file I/O error: {}", e),
+            ConfigError::Parse(e) => write!(f, "config file is invalid: {}", e),
+            ConfigError::Write(e) => write!(f, "unable to write config: {}", e),
+            ConfigError::TooNew(version) => write!(
+                f,
+                "config file is version {}, but this build only knows up to {}",
+                version, CONFIG_VERSION
+            ),
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<toml::de::Error> for ConfigError {
+    fn from(e: toml::de::Error) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+impl From<toml::ser::Error> for ConfigError {
+    fn from(e: toml::ser::Error) -> Self {
+        ConfigError::Write(e)
+    }
+}
+
+/// A peer worth coming back to, shown by name in the bookmarks dialog.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct Bookmark {
+    pub name: String,
+    /// Anything `connect_to` accepts
+    pub address: String,
+}
+
+/// Everything we remember between launches. Anything left out of the file
+/// takes its default, and the command line overrides the lot.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default)]
+pub struct Config {
+    pub version: u32,
+    pub nickname: Option<String>,
+    pub bind: Option<IpAddr>,
+    pub port: Option<u16>,
+    /// Where our identity is kept, if not next to the config file
+    pub identity_dir: Option<PathBuf>,
+    pub bookmarks: Vec<Bookmark>,
+    /// Identity keys, in hex, of the relays we believe about who the chat
+    /// and voice they pass on is from
+    pub trusted_relays: Vec<String>,
+    pub audio: AudioSettings,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            version: CONFIG_VERSION,
+            nickname: None,
+            bind: None,
+            port: None,
+            identity_dir: None,
+            bookmarks: vec![],
+            trusted_relays: vec![],
+            audio: AudioSettings::default(),
+        }
+    }
+}
+
+impl Config {
+    /// Reads the config at `path`, bringing it up to date if an older build
+    /// wrote it. Returns None if there's no file yet, and the version the
+    /// file was written as otherwise.
+    pub fn load(path: &Path) -> Result<Option<(Config, u32)>, ConfigError> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let value: toml::Value = contents.parse()?;
+        // Files from before there was a version are the first layout
+        let version = value
+            .get("version")
+            .and_then(toml::Value::as_integer)
+            .unwrap_or(1) as u32;
+        if version > CONFIG_VERSION {
+            return Err(ConfigError::TooNew(version));
+        }
+        let mut config: Config = migrate(value, version, MIGRATIONS).try_into()?;
+        config.version = CONFIG_VERSION;
+        Ok(Some((config, version)))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        // Going through a Value puts plain values ahead of tables, which
+        // TOML needs and the field order of Config doesn't guarantee
+        let contents = toml::to_string_pretty(&toml::Value::try_from(self)?)?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // Write it all out before replacing the old one, so a crash can't
+        // leave a half-written config behind
+        let temp_path = beside(path, ".new");
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// The keys in `trusted_relays`, leaving out any that aren't keys.
+    pub fn trusted_relay_keys(&self) -> HashSet<[u8; IDENTITY_KEY_SIZE]> {
+        self.trusted_relays
+            .iter()
+            .filter_map(|text| {
+                let key = parse_identity_key(text);
+                if key.is_err() {
+                    warn!("Ignoring trusted relay that isn't a key: {}", text);
+                }
+                key.ok()
+            })
+            .collect()
+    }
+
+    /// The directory our identity lives in: wherever the config says, or
+    /// next to the config file itself.
+    pub fn identity_dir(&self, config_file: Option<&Path>) -> Option<PathBuf> {
+        self.identity_dir
+            .clone()
+            .or_else(|| config_file.and_then(Path::parent).map(Path::to_path_buf))
+    }
+}
+
+/// The config, shared by everything that can change it, and saved back to
+/// its file whenever it does.
+#[derive(Clone, Debug)]
+pub struct ConfigStore {
+    inner: Arc<Mutex<ConfigStorePrivate>>,
+}
+
+#[derive(Debug)]
+struct ConfigStorePrivate {
+    // Where the config lives; None keeps everything in memory
+    path: Option<PathBuf>,
+    config: Config,
+    // False if the file was there but unusable, so we don't save over it
+    writable: bool,
+}
+
+impl ConfigStore {
+    /// Loads the config at `path`. If it can't be read we carry on with
+    /// the defaults, but leave the file alone so nothing in it is lost.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let (config, writable) = match &path {
+            Some(path) => match Config::load(path) {
+                Ok(Some((config, version))) => {
+                    if version < CONFIG_VERSION {
+                        upgrade(path, &config, version);
+                    }
+                    (config, true)
+                }
+                Ok(None) => (Config::default(), true),
+                Err(e) => {
+                    warn!("Not using {}: {}", path.display(), e);
+                    (Config::default(), false)
+                }
+            },
+            None => (Config::default(), false),
+        };
+        ConfigStore {
+            inner: Arc::new(Mutex::new(ConfigStorePrivate {
+                path,
+                config,
+                writable,
+            })),
+        }
+    }
+
+    fn lock_ref(&self) -> MutexGuard<'_, ConfigStorePrivate> {
+        // Config is only ever replaced wholesale, so it's never left half
+        // changed by a panic
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    /// The file this config comes from, if any.
+    pub fn path(&self) -> Option<PathBuf> {
+        self.lock_ref().path.clone()
+    }
+
+    pub fn get(&self) -> Config {
+        self.lock_ref().config.clone()
+    }
+
+    /// Changes the config and saves it, if anything actually changed.
+    pub fn update<F: FnOnce(&mut Config)>(&self, change: F) {
+        let mut inner = self.lock_ref();
+        let mut config = inner.config.clone();
+        change(&mut config);
+        if config == inner.config {
+            return;
+        }
+        inner.config = config;
+        if !inner.writable {
+            return;
+        }
+        if let Some(path) = &inner.path {
+            if let Err(e) = inner.config.save(path) {
+                warn!("Unable to save {}: {}", path.display(), e);
+            }
+        }
+    }
+}
+
+// Runs the steps that take a file written as `version` up to date
+fn migrate(value: toml::Value, version: u32, migrations: &[Migration]) -> toml::Value {
+    migrations
+        .iter()
+        .skip(version.saturating_sub(1) as usize)
+        .fold(value, |value, step| step(value))
+}
+
+// Rewrites an older config in the current layout, keeping the original
+// alongside it in case an older build still needs it
+fn upgrade(path: &Path, config: &Config, version: u32) {
+    let backup = beside(path, &format!(".v{}", version));
+    if let Err(e) = fs::copy(path, &backup) {
+        warn!("Unable to back up {}: {}", path.display(), e);
+        return;
+    }
+    match config.save(path) {
+        Ok(()) => info!(
+            "Upgraded {} from version {} (the old one is in {})",
+            path.display(),
+            version,
+            backup.display()
+        ),
+        Err(e) => warn!("Unable to upgrade {}: {}", path.display(), e),
+    }
+}
+
+// Another file next to `path`, named after it
+fn beside(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(suffix);
+    path.with_file_name(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct TempConfig {
+        dir: PathBuf,
+    }
+
+    impl TempConfig {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "coffeeshop-config-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            TempConfig { dir }
+        }
+
+        fn path(&self) -> PathBuf {
+            self.dir.join("config.toml")
+        }
+
+        fn write(&self, contents: &str) {
+            fs::write(self.path(), contents).unwrap();
+        }
+
+        fn read(&self) -> String {
+            fs::read_to_string(self.path()).unwrap()
+        }
+    }
+
+    impl Drop for TempConfig {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    // Stand-in migrations that leave a mark, so we can see which ran
+    fn to_v2(mut value: toml::Value) -> toml::Value {
+        value["steps"] = toml::Value::from("2");
+        value
+    }
+
+    fn to_v3(mut value: toml::Value) -> toml::Value {
+        let steps = value
+            .get("steps")
+            .and_then(toml::Value::as_str)
+            .unwrap_or("");
+        value["steps"] = toml::Value::from(format!("{}3", steps));
+        value
+    }
+
+    fn steps_after_migrating_from(version: u32) -> String {
+        let value: toml::Value = "steps = \"\"".parse().unwrap();
+        let migrated = migrate(value, version, &[to_v2, to_v3]);
+        migrated["steps"].as_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn a_file_without_a_version_is_the_first_layout() {
+        let temp = TempConfig::new("unversioned");
+        temp.write("nickname = \"alice\"\n");
+        let (config, version) = Config::load(&temp.path()).unwrap().unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.nickname.as_deref(), Some("alice"));
+    }
+
+    #[test]
+    fn a_missing_file_is_not_an_error() {
+        let temp = TempConfig::new("missing");
+        assert!(Config::load(&temp.path()).unwrap().is_none());
+    }
+
+    #[test]
+    fn migrations_run_from_the_files_version_on() {
+        assert_eq!(steps_after_migrating_from(1), "23");
+        assert_eq!(steps_after_migrating_from(2), "3");
+        assert_eq!(steps_after_migrating_from(3), "");
+        // Before versions were written down is the same as version 1
+        assert_eq!(steps_after_migrating_from(0), "23");
+    }
+
+    #[test]
+    fn a_newer_file_is_refused_and_left_alone() {
+        let temp = TempConfig::new("too-new");
+        let contents = format!(
+            "version = {}\nnickname = \"alice\"\nfrom_the_future = true\n",
+            CONFIG_VERSION + 1
+        );
+        temp.write(&contents);
+        assert!(matches!(
+            Config::load(&temp.path()),
+            Err(ConfigError::TooNew(v)) if v == CONFIG_VERSION + 1
+        ));
+
+        let store = ConfigStore::load(Some(temp.path()));
+        assert_eq!(store.get(), Config::default());
+        store.update(|config| config.nickname = Some("bob".to_string()));
+        assert_eq!(store.get().nickname.as_deref(), Some("bob"));
+        assert_eq!(temp.read(), contents);
+    }
+
+    #[test]
+    fn upgrading_keeps_the_old_file_beside_the_new_one() {
+        let temp = TempConfig::new("upgrade");
+        let old = "nickname = \"alice\"\n";
+        temp.write(old);
+        let (config, _) = Config::load(&temp.path()).unwrap().unwrap();
+        upgrade(&temp.path(), &config, 1);
+
+        assert_eq!(
+            fs::read_to_string(beside(&temp.path(), ".v1")).unwrap(),
+            old
+        );
+        let (upgraded, version) = Config::load(&temp.path()).unwrap().unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(upgraded, config);
+    }
+
+    #[test]
+    fn saved_config_loads_back_the_same() {
+        let temp = TempConfig::new("round-trip");
+        let mut config = Config {
+            nickname: Some("alice".to_string()),
+            bind: Some(IpAddr::from([192, 168, 1, 20])),
+            port: Some(47001),
+            identity_dir: Some(PathBuf::from("/somewhere/else")),
+            bookmarks: vec![Bookmark {
+                name: "the usual".to_string(),
+                address: "192.168.1.21:47001".to_string(),
+            }],
+            trusted_relays: vec!["ab".repeat(IDENTITY_KEY_SIZE)],
+            ..Config::default()
+        };
+        config.audio.capture_device = Some("USB mic".to_string());
+        let peer = uuid::Uuid::from_u128(7);
+        config.audio.muted.insert(peer);
+        config.audio.peer_volumes.insert(peer, 0.5);
+        config.save(&temp.path()).unwrap();
+
+        let (loaded, version) = Config::load(&temp.path()).unwrap().unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(loaded, config);
+        assert_eq!(loaded.trusted_relay_keys().len(), 1);
+
+        // And through the store, which saves on every change
+        let store = ConfigStore::load(Some(temp.path()));
+        assert_eq!(store.get(), config);
+        store.update(|config| config.port = Some(47002));
+        let (reloaded, _) = Config::load(&temp.path()).unwrap().unwrap();
+        assert_eq!(reloaded.port, Some(47002));
+    }
+}
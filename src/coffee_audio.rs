@@ -1,24 +1,386 @@
+pub mod backends;
+pub mod capture;
 pub mod layers;
+pub mod mixer;
+pub mod settings;
 pub mod sources;
 pub mod types;
+pub mod ui;
+pub mod voice_codec;
 
-use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use tokio::sync::{broadcast, RwLock};
 
-#[derive(Clone, Debug)]
+use log::{debug, error, info, warn};
+use uuid::Uuid;
+
+use self::backends::{AudioBackend, SfmlBackend};
+use self::capture::{CaptureDevice, CaptureError, SfmlCaptureDevice, DEFAULT_CAPTURE_SAMPLE_RATE};
+use self::layers::{Position, SpatialHandle, SpatialLayer};
+use self::mixer::{AudioMixer, MixerHandle, MixerInput, MixerInputId};
+use self::settings::{AudioSettings, LayerSettings};
+use self::sources::{frames_for_duration, MicSource, VoiceSource};
+use self::voice_codec::{
+    VoiceCodecError, VoiceCodecSettings, VoiceDecoder, VoiceEncoder, VOICE_FRAME_MS,
+};
+use crate::coffee_network::{JitterBuffer, Message, NetworkController, VoicePacket};
+
+// Remote voices are always decoded to mono; spatial effects make them stereo
+const VOICE_CHANNEL_COUNT: u32 = 1;
+const VOICE_SAMPLE_RATE: u32 = DEFAULT_CAPTURE_SAMPLE_RATE;
+// Anyone louder than this (about -34dBFS) counts as speaking
+const SPEAKING_LEVEL: f32 = 0.02;
+// How far away everyone sits, in meters, when voices are seated around us.
+// They're heard at their own volume from there.
+const SEAT_DISTANCE: f32 = 1.5;
+// How far round to either side the outermost seats are
+const SEAT_SPREAD_DEGREES: f32 = 75.0;
+
+/// Why voice can't be captured, encoded, decoded or played.
+#[derive(Debug)]
+pub enum AudioError {
+    Capture(CaptureError),
+    Codec(VoiceCodecError),
+    /// The output device stopped taking samples
+    Output(io::Error),
+}
+
+impl fmt::Display for AudioError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioError::Capture(e) => write!(f, "capture error: {}", e),
+            AudioError::Codec(e) => write!(f, "voice codec error: {}", e),
+            AudioError::Output(e) => write!(f, "audio output error: {}", e),
+        }
+    }
+}
+
+impl Error for AudioError {}
+
+impl From<CaptureError> for AudioError {
+    fn from(e: CaptureError) -> Self {
+        AudioError::Capture(e)
+    }
+}
+
+impl From<VoiceCodecError> for AudioError {
+    fn from(e: VoiceCodecError) -> Self {
+        AudioError::Codec(e)
+    }
+}
+
+impl From<io::Error> for AudioError {
+    fn from(e: io::Error) -> Self {
+        AudioError::Output(e)
+    }
+}
+
+// The network side of one remote speaker; the decoding side lives in the
+// VoiceSource that the mixer is playing
+struct RemoteVoice {
+    buffer: Arc<Mutex<JitterBuffer>>,
+    input: MixerInputId,
+    // Where they sit, if voices are seated around us
+    seat: Option<SpatialHandle>,
+}
+
+#[derive(Clone)]
 pub struct AudioController {
-    inner: Arc<RwLock<AudioController_Inner>>,
+    inner: Arc<RwLock<AudioControllerInner>>,
 }
 
-#[derive(Debug)]
-struct AudioController_Inner {}
+struct AudioControllerInner {
+    settings: AudioSettings,
+    mixer: MixerHandle,
+    voices: HashMap<Uuid, RemoteVoice>,
+    // Kept alive for as long as we're capturing
+    mic: Option<MicSource>,
+}
 
 impl AudioController {
-    pub fn new() -> Self {
-        AudioController {
-            inner: Arc::new(RwLock::new(AudioController_Inner {})),
+    pub fn new(net: NetworkController, settings: AudioSettings) -> Self {
+        let mixer = AudioMixer::new_with_format(2, VOICE_SAMPLE_RATE);
+        mixer.handle().set_output_filters(settings.layers.build());
+        let controller = AudioController {
+            inner: Arc::new(RwLock::new(AudioControllerInner {
+                settings,
+                mixer: mixer.handle(),
+                voices: HashMap::new(),
+                mic: None,
+            })),
+        };
+
+        // Play the room on its own thread, since the backend blocks
+        start_output(mixer);
+
+        // Decode voice coming in from the network
+        controller.start_voice_receiver(net.clone());
+
+        // Capture, encode and send our own voice
+        controller.start_voice_capture(net);
+
+        controller
+    }
+
+    pub async fn get_codec_settings(&self) -> VoiceCodecSettings {
+        self.inner.read().await.settings.codec
+    }
+
+    /// New settings are picked up by the encoder on the next frame.
+    pub async fn set_codec_settings(&self, settings: VoiceCodecSettings) {
+        self.inner.write().await.settings.codec = settings;
+    }
+
+    pub async fn get_settings(&self) -> AudioSettings {
+        self.inner.read().await.settings.clone()
+    }
+
+    /// Changes how loud one peer is, whether or not they're talking yet.
+    pub async fn set_peer_volume(&self, id: Uuid, volume: f32) {
+        let mut inner = self.inner.write().await;
+        inner.settings.set_peer_volume(id, volume);
+        let gain = inner.settings.peer_gain(id);
+        if let Some(voice) = inner.voices.get(&id) {
+            inner.mixer.set_input_gain(voice.input, gain);
+        }
+    }
+
+    /// Silences one peer without forgetting their volume.
+    pub async fn set_peer_muted(&self, id: Uuid, muted: bool) {
+        let mut inner = self.inner.write().await;
+        inner.settings.set_muted(id, muted);
+        let gain = inner.settings.peer_gain(id);
+        if let Some(voice) = inner.voices.get(&id) {
+            inner.mixer.set_input_gain(voice.input, gain);
+        }
+    }
+
+    /// The peers whose voices can be heard right now, muted or not.
+    pub async fn get_speaking_peers(&self) -> HashSet<Uuid> {
+        let inner = self.inner.read().await;
+        inner
+            .voices
+            .iter()
+            .filter(|(_, voice)| {
+                inner.mixer.input_level(voice.input).unwrap_or(0.0) >= SPEAKING_LEVEL
+            })
+            .map(|(id, _)| *id)
+            .collect()
+    }
+
+    pub async fn set_layer_settings(&self, layers: LayerSettings) {
+        let mut inner = self.inner.write().await;
+        inner.mixer.set_output_filters(layers.build());
+        inner.settings.layers = layers;
+        inner.seat_voices();
+    }
+
+    /// Only remembered for now; the microphone in use is picked at startup.
+    pub async fn set_capture_device(&self, device: Option<String>) {
+        self.inner.write().await.settings.capture_device = device;
+    }
+
+    fn start_voice_receiver(&self, net: NetworkController) {
+        let audio = self.clone();
+        tokio::spawn(async move {
+            let local_id = net.get_local_id().await;
+            let mut receiver = net.get_broadcast_receiver().await;
+            loop {
+                match receiver.recv().await {
+                    Ok(Message::VoiceChat(sender, packet)) => {
+                        if sender != local_id {
+                            audio.receive_voice_packet(sender, packet).await;
+                        }
+                    }
+                    Ok(Message::Disconnect(sender, _)) => {
+                        audio.remove_voice(sender).await;
+                    }
+                    Ok(_) => {}
+                    Err(broadcast::RecvError::Lagged(_)) => {}
+                    Err(broadcast::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    fn start_voice_capture(&self, net: NetworkController) {
+        let audio = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = audio.capture_voice(net).await {
+                error!("Not capturing voice: {}", e);
+            }
+        });
+    }
+
+    // Sends everything the microphone hears until it stops
+    async fn capture_voice(&self, net: NetworkController) -> Result<(), AudioError> {
+        let name = self.get_settings().await.capture_device;
+        let device = match SfmlCaptureDevice::new(name.as_deref()) {
+            // Better to be heard through some microphone than none
+            Err(CaptureError::DeviceNotFound(name)) => {
+                warn!("No capture device named \"{}\", using the default", name);
+                SfmlCaptureDevice::new(None)
+            }
+            result => result,
+        }?;
+        let frame_size = frames_for_duration(device.sample_rate(), VOICE_FRAME_MS);
+        let (channel_count, sample_rate) = (device.channel_count(), device.sample_rate());
+        info!("Capturing voice from {}", device.name());
+        let mut mic = MicSource::start(device, frame_size);
+        let mut frames = match mic.take_receiver() {
+            Some(rx) => rx,
+            None => return Ok(()),
+        };
+        self.inner.write().await.mic = Some(mic);
+
+        let mut settings = self.get_codec_settings().await;
+        let mut encoder = VoiceEncoder::new(channel_count, sample_rate, settings)?;
+
+        let mut previous: Option<Vec<u8>> = None;
+        while let Some(frame) = frames.recv().await {
+            let latest = self.get_codec_settings().await;
+            if latest != settings {
+                settings = latest;
+                if let Err(e) = encoder.apply_settings(settings) {
+                    warn!("Error applying voice codec settings: {}", e);
+                }
+            }
+            match encoder.encode(&frame) {
+                Ok(packet) => {
+                    let redundant = if settings.wants_redundancy() {
+                        previous.take()
+                    } else {
+                        None
+                    };
+                    previous = Some(packet.clone());
+                    net.send_voice_data(packet, redundant).await;
+                }
+                Err(e) => {
+                    // The next packet can't vouch for a frame we never sent
+                    previous = None;
+                    debug!("Error encoding voice frame: {}", e);
+                }
+            }
+        }
+        debug!("Voice capture stopped");
+        Ok(())
+    }
+
+    async fn receive_voice_packet(&self, sender: Uuid, packet: VoicePacket) {
+        let arrival = Instant::now();
+        let mut inner = self.inner.write().await;
+        if !inner.voices.contains_key(&sender) {
+            let decoder = match VoiceDecoder::new(VOICE_CHANNEL_COUNT, VOICE_SAMPLE_RATE) {
+                Ok(decoder) => decoder,
+                Err(e) => {
+                    error!("Unable to create voice decoder: {}", AudioError::from(e));
+                    return;
+                }
+            };
+            let buffer = Arc::new(Mutex::new(JitterBuffer::new(VOICE_FRAME_MS)));
+            let frame_size = frames_for_duration(VOICE_SAMPLE_RATE, VOICE_FRAME_MS);
+            let source = VoiceSource::new(buffer.clone(), decoder, frame_size);
+            let mut input = MixerInput::new(source);
+            input.set_gain(inner.settings.peer_gain(sender));
+            let input = inner.mixer.add_input(input);
+            let seat = None;
+            inner.voices.insert(
+                sender,
+                RemoteVoice {
+                    buffer,
+                    input,
+                    seat,
+                },
+            );
+            inner.seat_voices();
+        }
+
+        if let Some(voice) = inner.voices.get(&sender) {
+            if let Ok(mut buffer) = voice.buffer.lock() {
+                if let Some(redundant) = packet.redundant {
+                    if packet.sequence > 0 {
+                        buffer.recover(packet.sequence - 1, redundant);
+                    }
+                }
+                buffer.push(packet.sequence, packet.data, arrival);
+            }
+        }
+    }
+
+    async fn remove_voice(&self, sender: Uuid) {
+        let mut inner = self.inner.write().await;
+        if let Some(voice) = inner.voices.remove(&sender) {
+            inner.mixer.remove_input(voice.input);
+            if let Ok(mut buffer) = voice.buffer.lock() {
+                buffer.close();
+                debug!(
+                    "Voice from {} ended with {:.1}ms jitter: {:?}",
+                    sender,
+                    buffer.jitter_ms(),
+                    buffer.stats()
+                );
+            }
+            inner.seat_voices();
         }
     }
 }
 
-struct AudioNetReceiver {}
+impl AudioControllerInner {
+    // Spreads everyone evenly around the front of us, always in the same
+    // order, or puts them all back in the middle if seating is off. Anyone
+    // who already has a seat glides to their new one.
+    fn seat_voices(&mut self) {
+        let spatial = self.settings.layers.spatial;
+        let mut ids: Vec<Uuid> = self.voices.keys().copied().collect();
+        ids.sort();
+        let count = ids.len();
+        for (index, id) in ids.into_iter().enumerate() {
+            let voice = match self.voices.get_mut(&id) {
+                Some(voice) => voice,
+                None => continue,
+            };
+            if !spatial {
+                if voice.seat.take().is_some() {
+                    self.mixer.set_input_filters(voice.input, vec![]);
+                }
+                continue;
+            }
+            let position = seat_position(index, count);
+            match &voice.seat {
+                Some(seat) => {
+                    // We always sit in the middle of the arc
+                    seat.set_listener_position(Position::default());
+                    seat.set_source_position(position);
+                }
+                None => {
+                    let mut layer = SpatialLayer::new(Position::default(), position);
+                    layer.set_reference_distance(SEAT_DISTANCE);
+                    voice.seat = Some(layer.handle());
+                    self.mixer
+                        .set_input_filters(voice.input, vec![Box::new(layer)]);
+                }
+            }
+        }
+    }
+}
+
+// Where the `index`th of `count` seats is, on an arc in front of us
+fn seat_position(index: usize, count: usize) -> Position {
+    let spread = SEAT_SPREAD_DEGREES.to_radians();
+    let angle = -spread + 2.0 * spread * (index as f32 + 0.5) / count.max(1) as f32;
+    Position::new(SEAT_DISTANCE * angle.sin(), SEAT_DISTANCE * angle.cos())
+}
+
+fn start_output(mut mixer: AudioMixer) {
+    std::thread::spawn(move || {
+        let mut backend = SfmlBackend::new();
+        if let Err(e) = backend.run(&mut mixer) {
+            error!("Audio output stopped: {}", AudioError::from(e));
+        }
+    });
+}
@@ -0,0 +1,24 @@
+mod null_backend;
+mod sfml_backend;
+mod wav_backend;
+
+pub use null_backend::NullBackend;
+pub use sfml_backend::SfmlBackend;
+pub use wav_backend::WavBackend;
+
+use std::io;
+
+use crate::coffee_audio::types::AudioSource;
+
+/// An AudioBackend is where audio finally ends up: speakers, a file, or
+/// nowhere at all. Running a backend pulls from the source until the source
+/// finishes or the backend's own frame limit is reached.
+pub trait AudioBackend {
+    /// Plays `source` to completion, returning how many frames were consumed.
+    fn run(&mut self, source: &mut dyn AudioSource) -> io::Result<u64>;
+}
+
+// Counts whole frames in a block of interleaved samples
+fn frame_count(samples: &[i16], channel_count: u32) -> u64 {
+    (samples.len() / channel_count.max(1) as usize) as u64
+}
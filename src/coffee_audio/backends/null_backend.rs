@@ -0,0 +1,41 @@
+use std::io;
+
+use crate::coffee_audio::backends::{frame_count, AudioBackend};
+use crate::coffee_audio::types::AudioSource;
+
+/// Pulls samples and throws them away. Handy for exercising a pipeline on a
+/// machine without any audio hardware.
+pub struct NullBackend {
+    max_frames: Option<u64>,
+}
+
+impl NullBackend {
+    /// With no `max_frames`, runs until the source finishes; live sources
+    /// like the mixer never do, so give those a limit.
+    pub fn new(max_frames: Option<u64>) -> Self {
+        NullBackend { max_frames }
+    }
+}
+
+impl AudioBackend for NullBackend {
+    fn run(&mut self, source: &mut dyn AudioSource) -> io::Result<u64> {
+        let channels = source.channel_count();
+        let mut frames = 0u64;
+        loop {
+            if let Some(max) = self.max_frames {
+                if frames >= max {
+                    break;
+                }
+            }
+            let (data, has_more) = source.pull_samples();
+            frames += frame_count(data, channels);
+            if !has_more {
+                break;
+            }
+        }
+        Ok(match self.max_frames {
+            Some(max) => frames.min(max),
+            None => frames,
+        })
+    }
+}
@@ -0,0 +1,58 @@
+use std::io;
+
+use sfml::audio::{SoundStatus, SoundStream, SoundStreamPlayer};
+
+use crate::coffee_audio::backends::AudioBackend;
+use crate::coffee_audio::types::AudioSource;
+
+const POLL_INTERVAL_MS: u64 = 100;
+
+/// Adapts any AudioSource into an SFML SoundStream so it can be handed to
+/// a SoundStreamPlayer directly.
+pub struct SfmlStream<'a> {
+    source: &'a mut dyn AudioSource,
+}
+
+impl<'a> SfmlStream<'a> {
+    pub fn new(source: &'a mut dyn AudioSource) -> Self {
+        SfmlStream { source }
+    }
+}
+
+impl SoundStream for SfmlStream<'_> {
+    fn get_data(&mut self) -> (&mut [i16], bool) {
+        self.source.pull_samples()
+    }
+    // Seek does nothing, sources just play until they end
+    fn seek(&mut self, _: sfml::system::Time) {}
+    fn channel_count(&self) -> u32 {
+        self.source.channel_count()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+}
+
+/// Plays a source through the default output device, blocking until it
+/// finishes.
+pub struct SfmlBackend {}
+
+impl SfmlBackend {
+    pub fn new() -> Self {
+        SfmlBackend {}
+    }
+}
+
+impl AudioBackend for SfmlBackend {
+    fn run(&mut self, source: &mut dyn AudioSource) -> io::Result<u64> {
+        let sample_rate = source.sample_rate();
+        let mut stream = SfmlStream::new(source);
+        let mut player = SoundStreamPlayer::new(&mut stream);
+        player.play();
+        while player.status() == SoundStatus::Playing {
+            std::thread::sleep(std::time::Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        let seconds = f64::from(player.playing_offset().as_seconds());
+        Ok((seconds * f64::from(sample_rate)) as u64)
+    }
+}
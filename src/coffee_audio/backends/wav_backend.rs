@@ -0,0 +1,175 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use crate::coffee_audio::backends::{frame_count, AudioBackend};
+use crate::coffee_audio::types::AudioSource;
+
+const BITS_PER_SAMPLE: u16 = 16;
+const WAV_HEADER_LEN: u32 = 44;
+
+/// Renders a source to a 16-bit PCM WAV file, so the whole pipeline can be
+/// run offline and the output compared against a known-good render.
+pub struct WavBackend {
+    path: PathBuf,
+    max_frames: Option<u64>,
+}
+
+impl WavBackend {
+    /// With no `max_frames`, renders until the source finishes; live
+    /// sources like the mixer never do, so give those a limit.
+    pub fn new<P: Into<PathBuf>>(path: P, max_frames: Option<u64>) -> Self {
+        WavBackend {
+            path: path.into(),
+            max_frames,
+        }
+    }
+}
+
+fn write_header<W: Write>(
+    out: &mut W,
+    channel_count: u32,
+    sample_rate: u32,
+    data_len: u32,
+) -> io::Result<()> {
+    let block_align = channel_count as u16 * BITS_PER_SAMPLE / 8;
+    let byte_rate = sample_rate * u32::from(block_align);
+    out.write_all(b"RIFF")?;
+    out.write_all(&(WAV_HEADER_LEN - 8 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVE")?;
+    out.write_all(b"fmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&(channel_count as u16).to_le_bytes())?;
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&byte_rate.to_le_bytes())?;
+    out.write_all(&block_align.to_le_bytes())?;
+    out.write_all(&BITS_PER_SAMPLE.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
+
+impl AudioBackend for WavBackend {
+    fn run(&mut self, source: &mut dyn AudioSource) -> io::Result<u64> {
+        let channels = source.channel_count().max(1);
+        let sample_rate = source.sample_rate();
+        let mut out = BufWriter::new(File::create(&self.path)?);
+
+        // Sizes aren't known yet; the header is rewritten at the end
+        write_header(&mut out, channels, sample_rate, 0)?;
+
+        let mut frames = 0u64;
+        loop {
+            let remaining = match self.max_frames {
+                Some(max) if frames >= max => break,
+                Some(max) => Some(max - frames),
+                None => None,
+            };
+            let (data, has_more) = source.pull_samples();
+            let mut take = frame_count(data, channels);
+            if let Some(remaining) = remaining {
+                take = take.min(remaining);
+            }
+            for sample in &data[..take as usize * channels as usize] {
+                out.write_all(&sample.to_le_bytes())?;
+            }
+            frames += take;
+            if !has_more {
+                break;
+            }
+        }
+
+        let data_len = frames * u64::from(channels) * u64::from(BITS_PER_SAMPLE / 8);
+        if data_len > u64::from(u32::MAX - WAV_HEADER_LEN) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "rendered audio is too long for a WAV file",
+            ));
+        }
+        out.seek(SeekFrom::Start(0))?;
+        write_header(&mut out, channels, sample_rate, data_len as u32)?;
+        out.flush()?;
+        Ok(frames)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Counts up from zero, a chunk of `chunk_frames` stereo frames at a time
+    struct Ramp {
+        chunks_left: usize,
+        next: i16,
+        chunk: Vec<i16>,
+        chunk_frames: usize,
+    }
+
+    impl Ramp {
+        fn new(chunks: usize, chunk_frames: usize) -> Self {
+            Ramp {
+                chunks_left: chunks,
+                next: 0,
+                chunk: vec![],
+                chunk_frames,
+            }
+        }
+    }
+
+    impl AudioSource for Ramp {
+        fn pull_samples(&mut self) -> (&mut [i16], bool) {
+            self.chunk.clear();
+            for _ in 0..self.chunk_frames * 2 {
+                self.chunk.push(self.next);
+                self.next += 1;
+            }
+            self.chunks_left -= 1;
+            (&mut self.chunk, self.chunks_left > 0)
+        }
+        fn channel_count(&self) -> u32 {
+            2
+        }
+        fn sample_rate(&self) -> u32 {
+            48_000
+        }
+    }
+
+    fn u32_at(bytes: &[u8], at: usize) -> u32 {
+        u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+    }
+
+    fn render(name: &str, source: &mut Ramp, max_frames: Option<u64>) -> (u64, Vec<u8>) {
+        let path =
+            std::env::temp_dir().join(format!("coffeeshop-{}-{}.wav", name, std::process::id()));
+        let frames = WavBackend::new(&path, max_frames).run(source).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+        (frames, bytes)
+    }
+
+    #[test]
+    fn writes_a_complete_header_and_every_sample() {
+        let (frames, bytes) = render("whole", &mut Ramp::new(3, 10), None);
+        assert_eq!(frames, 30);
+        assert_eq!(&bytes[0..4], b"RIFF");
+        assert_eq!(&bytes[8..16], b"WAVEfmt ");
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(u32_at(&bytes, 24), 48_000);
+        assert_eq!(u32_at(&bytes, 28), 48_000 * 4);
+        assert_eq!(u32_at(&bytes, 40), 30 * 4);
+        let samples: Vec<i16> = bytes[WAV_HEADER_LEN as usize..]
+            .chunks(2)
+            .map(|pair| i16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(samples, (0..60).collect::<Vec<i16>>());
+    }
+
+    #[test]
+    fn stops_part_way_through_a_chunk_at_the_limit() {
+        let (frames, bytes) = render("limited", &mut Ramp::new(100, 10), Some(25));
+        assert_eq!(frames, 25);
+        assert_eq!(u32_at(&bytes, 40), 25 * 4);
+        assert_eq!(bytes.len(), WAV_HEADER_LEN as usize + 25 * 4);
+    }
+}
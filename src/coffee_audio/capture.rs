@@ -0,0 +1,57 @@
+mod sfml_device;
+#[cfg(test)]
+mod source_device;
+
+pub use sfml_device::SfmlCaptureDevice;
+#[cfg(test)]
+pub use source_device::SourceCaptureDevice;
+
+use std::error::Error;
+use std::fmt;
+
+/// Voice is captured at 48kHz, which every codec we care about handles
+pub const DEFAULT_CAPTURE_SAMPLE_RATE: u32 = 48_000;
+
+/// A CaptureDevice is anything that produces audio as it happens, like a
+/// microphone. Devices are moved onto their own thread and run from there.
+pub trait CaptureDevice: Send + 'static {
+    fn name(&self) -> String;
+    fn channel_count(&self) -> u32;
+    fn sample_rate(&self) -> u32;
+
+    /// Captures audio, handing each block of interleaved samples to `sink`
+    /// as it arrives. Blocks until `sink` returns false or the device has
+    /// nothing more to give.
+    fn run(&mut self, sink: &mut (dyn FnMut(&[i16]) -> bool + Send));
+}
+
+#[derive(Debug)]
+pub enum CaptureError {
+    /// Audio capture isn't supported on this system at all
+    Unavailable,
+    /// No capture device with the requested name exists
+    DeviceNotFound(String),
+}
+
+impl fmt::Display for CaptureError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CaptureError::Unavailable => write!(f, "audio capture is not available"),
+            CaptureError::DeviceNotFound(name) => {
+                write!(f, "no capture device named \"{}\"", name)
+            }
+        }
+    }
+}
+
+impl Error for CaptureError {}
+
+/// Lists the names of all capture devices on this system.
+pub fn available_devices() -> Vec<String> {
+    sfml::audio::capture::available_devices()
+}
+
+/// The name of the capture device used when none is picked explicitly.
+pub fn default_device() -> String {
+    sfml::audio::capture::default_device()
+}
@@ -0,0 +1,101 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use log::error;
+use sfml::audio::capture::{self, SoundRecorder, SoundRecorderDriver};
+
+use crate::coffee_audio::capture::{CaptureDevice, CaptureError, DEFAULT_CAPTURE_SAMPLE_RATE};
+
+const POLL_INTERVAL_MS: u64 = 10;
+const PROCESSING_INTERVAL_MS: i32 = 10;
+
+// Forwards SFML's recorder callbacks (which arrive on SFML's own capture
+// thread) to the sink handed to `run`
+struct SinkRecorder<'a> {
+    sink: &'a mut (dyn FnMut(&[i16]) -> bool + Send),
+    running: Arc<AtomicBool>,
+}
+
+impl SoundRecorder for SinkRecorder<'_> {
+    fn on_process_samples(&mut self, samples: &[i16]) -> bool {
+        let keep_going = (self.sink)(samples);
+        if !keep_going {
+            self.running.store(false, Ordering::SeqCst);
+        }
+        keep_going
+    }
+
+    fn on_stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+    }
+}
+
+/// A real capture device (microphone, line in, ...), recorded through SFML.
+pub struct SfmlCaptureDevice {
+    name: String,
+    channel_count: u32,
+    sample_rate: u32,
+}
+
+impl SfmlCaptureDevice {
+    /// Opens the capture device called `name`, or the system default if no
+    /// name is given.
+    pub fn new(name: Option<&str>) -> Result<Self, CaptureError> {
+        if !capture::is_available() {
+            return Err(CaptureError::Unavailable);
+        }
+        let name = match name {
+            Some(name) => {
+                if !capture::available_devices().iter().any(|d| d == name) {
+                    return Err(CaptureError::DeviceNotFound(name.to_string()));
+                }
+                name.to_string()
+            }
+            None => capture::default_device(),
+        };
+        Ok(SfmlCaptureDevice {
+            name,
+            channel_count: 1,
+            sample_rate: DEFAULT_CAPTURE_SAMPLE_RATE,
+        })
+    }
+}
+
+impl CaptureDevice for SfmlCaptureDevice {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn channel_count(&self) -> u32 {
+        self.channel_count
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn run(&mut self, sink: &mut (dyn FnMut(&[i16]) -> bool + Send)) {
+        let running = Arc::new(AtomicBool::new(true));
+        let mut recorder = SinkRecorder {
+            sink,
+            running: running.clone(),
+        };
+        let mut driver = SoundRecorderDriver::new(&mut recorder);
+        if driver.set_device(&self.name).is_err() {
+            error!("Unable to open capture device: {}", self.name);
+            return;
+        }
+        driver.set_channel_count(self.channel_count);
+        driver.set_processing_interval(sfml::system::Time::milliseconds(PROCESSING_INTERVAL_MS));
+        if !driver.start(self.sample_rate) {
+            error!("Unable to start capture on: {}", self.name);
+            return;
+        }
+
+        // SFML captures on its own thread; just wait for it to be told to stop
+        while running.load(Ordering::SeqCst) {
+            std::thread::sleep(std::time::Duration::from_millis(POLL_INTERVAL_MS));
+        }
+        driver.stop();
+    }
+}
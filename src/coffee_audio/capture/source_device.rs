@@ -0,0 +1,68 @@
+use std::time::{Duration, Instant};
+
+use crate::coffee_audio::capture::CaptureDevice;
+use crate::coffee_audio::types::AudioSource;
+
+/// A fake capture device that "records" whatever an AudioSource plays, such
+/// as a file or a generated tone. Useful for testing the voice pipeline
+/// without a microphone.
+pub struct SourceCaptureDevice<S: AudioSource + Send + 'static> {
+    name: String,
+    source: S,
+    realtime: bool,
+}
+
+impl<S: AudioSource + Send + 'static> SourceCaptureDevice<S> {
+    pub fn new(name: &str, source: S) -> Self {
+        SourceCaptureDevice {
+            name: name.to_string(),
+            source,
+            realtime: false,
+        }
+    }
+
+    /// When enabled, samples are handed out no faster than they would play,
+    /// like a real microphone. Otherwise the source is drained as fast as
+    /// the sink will take it.
+    pub fn set_realtime(&mut self, realtime: bool) {
+        self.realtime = realtime
+    }
+}
+
+impl<S: AudioSource + Send + 'static> CaptureDevice for SourceCaptureDevice<S> {
+    fn name(&self) -> String {
+        self.name.clone()
+    }
+
+    fn channel_count(&self) -> u32 {
+        self.source.channel_count()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.source.sample_rate()
+    }
+
+    fn run(&mut self, sink: &mut (dyn FnMut(&[i16]) -> bool + Send)) {
+        let samples_per_second =
+            u64::from(self.source.channel_count().max(1)) * u64::from(self.source.sample_rate());
+        let started = Instant::now();
+        let mut delivered = 0u64;
+        loop {
+            let (data, has_more) = self.source.pull_samples();
+            if !sink(data) {
+                break;
+            }
+            delivered += data.len() as u64;
+            if !has_more {
+                break;
+            }
+            if self.realtime && samples_per_second > 0 {
+                let due = Duration::from_micros(delivered * 1_000_000 / samples_per_second);
+                let elapsed = started.elapsed();
+                if due > elapsed {
+                    std::thread::sleep(due - elapsed);
+                }
+            }
+        }
+    }
+}
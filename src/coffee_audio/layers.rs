@@ -1,5 +1,9 @@
+mod crossfeed;
 mod passthrough;
+mod spatial;
 mod swap_left_right;
 
+pub use crossfeed::CrossfeedLayer;
 pub use passthrough::PassthroughLayer;
+pub use spatial::{Position, SpatialHandle, SpatialLayer};
 pub use swap_left_right::SwapLRLayer;
@@ -0,0 +1,206 @@
+use std::collections::VecDeque;
+
+use crate::coffee_audio::types::{AudioChunk, AudioLayer};
+
+const DEFAULT_DELAY_MS: f32 = 0.3;
+const DEFAULT_CUTOFF_HZ: f32 = 700.0;
+const DEFAULT_FEED_LEVEL: f32 = 0.3;
+
+/// Mixes a delayed, low-passed copy of each stereo channel into the
+/// opposite channel, roughly simulating how sound from a speaker on one
+/// side of your head also reaches the other ear a little later and with
+/// less high-frequency content. Only stereo chunks are modified; anything
+/// else passes through untouched.
+///
+/// Filter and delay state is kept between calls, so a continuous stream
+/// split into many chunks sounds the same as one big chunk.
+pub struct CrossfeedLayer {
+    delay_ms: f32,
+    cutoff_hz: f32,
+    feed_level: f32,
+
+    // Sample rate the state below was built for (0 means "not yet")
+    sample_rate: u32,
+    lowpass_coefficient: f32,
+    lowpass_state: [f32; 2],
+    delay_lines: [VecDeque<f32>; 2],
+}
+
+impl Default for CrossfeedLayer {
+    fn default() -> Self {
+        CrossfeedLayer::new(DEFAULT_DELAY_MS, DEFAULT_CUTOFF_HZ, DEFAULT_FEED_LEVEL)
+    }
+}
+
+impl CrossfeedLayer {
+    /// * `delay_ms` - how long the crossed signal lags behind the direct one
+    /// * `cutoff_hz` - cutoff frequency of the low-pass on the crossed signal
+    /// * `feed_level` - gain of the crossed signal relative to the direct one
+    pub fn new(delay_ms: f32, cutoff_hz: f32, feed_level: f32) -> Self {
+        CrossfeedLayer {
+            delay_ms: delay_ms.max(0.0),
+            cutoff_hz: cutoff_hz.max(1.0),
+            feed_level: feed_level.max(0.0),
+            sample_rate: 0,
+            lowpass_coefficient: 0.0,
+            lowpass_state: [0.0; 2],
+            delay_lines: [VecDeque::new(), VecDeque::new()],
+        }
+    }
+
+    pub fn delay_ms(&self) -> f32 {
+        self.delay_ms
+    }
+
+    pub fn cutoff_hz(&self) -> f32 {
+        self.cutoff_hz
+    }
+
+    pub fn feed_level(&self) -> f32 {
+        self.feed_level
+    }
+
+    fn prepare(&mut self, sample_rate: u32) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+
+        // One-pole low-pass: y += a * (x - y)
+        let dt = 1.0 / sample_rate as f32;
+        let rc = 1.0 / (2.0 * std::f32::consts::PI * self.cutoff_hz);
+        self.lowpass_coefficient = dt / (rc + dt);
+        self.lowpass_state = [0.0; 2];
+
+        let delay_samples = (self.delay_ms * sample_rate as f32 / 1000.0).round() as usize;
+        for line in self.delay_lines.iter_mut() {
+            line.clear();
+            line.resize(delay_samples, 0.0);
+        }
+    }
+}
+
+impl AudioLayer for CrossfeedLayer {
+    fn modulate_chunk(&mut self, chunk: &mut AudioChunk) {
+        if chunk.channel_count() != 2 {
+            return;
+        }
+        self.prepare(chunk.sample_rate());
+
+        // Keep overall loudness about the same as the dry signal
+        let normalize = 1.0 / (1.0 + self.feed_level);
+        let a = self.lowpass_coefficient;
+
+        for frame in chunk.buffer_mut().chunks_exact_mut(2) {
+            let dry = [f32::from(frame[0]), f32::from(frame[1])];
+            let mut crossed = [0.0f32; 2];
+            for ch in 0..2 {
+                self.lowpass_state[ch] += a * (dry[ch] - self.lowpass_state[ch]);
+                self.delay_lines[ch].push_back(self.lowpass_state[ch]);
+                crossed[ch] = self.delay_lines[ch].pop_front().unwrap_or(0.0);
+            }
+
+            let left = (dry[0] + self.feed_level * crossed[1]) * normalize;
+            let right = (dry[1] + self.feed_level * crossed[0]) * normalize;
+            frame[0] = left.round().clamp(-32768.0, 32767.0) as i16;
+            frame[1] = right.round().clamp(-32768.0, 32767.0) as i16;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+    const IMPULSE: i16 = 16_000;
+
+    // A stereo chunk of `frames` frames, silent apart from an impulse on
+    // the left channel of the first frame
+    fn left_impulse(frames: usize) -> AudioChunk {
+        let mut samples = vec![0i16; frames * 2];
+        samples[0] = IMPULSE;
+        AudioChunk::new_from_data(2, RATE, samples)
+    }
+
+    fn channel(chunk: &AudioChunk, ch: usize) -> Vec<i16> {
+        chunk.buffer().iter().skip(ch).step_by(2).copied().collect()
+    }
+
+    #[test]
+    fn crossed_signal_arrives_after_the_delay() {
+        // 1ms at 48kHz
+        let mut layer = CrossfeedLayer::new(1.0, 700.0, 0.5);
+        let mut chunk = left_impulse(256);
+        layer.modulate_chunk(&mut chunk);
+
+        let left = channel(&chunk, 0);
+        let right = channel(&chunk, 1);
+        let normalize = 1.0 / 1.5;
+        assert_eq!(left[0], (f32::from(IMPULSE) * normalize).round() as i16);
+        assert!(left[1..].iter().all(|s| *s == 0));
+        assert!(right[..48].iter().all(|s| *s == 0));
+        assert!(right[48] > 0);
+    }
+
+    #[test]
+    fn crossed_signal_has_the_feed_level_at_dc() {
+        // A one-pole low-pass passes DC at unity gain, so the crossed
+        // impulse response should add up to feed level times the impulse
+        let feed_level = 0.4;
+        let mut layer = CrossfeedLayer::new(0.3, 700.0, feed_level);
+        let mut chunk = left_impulse(4_096);
+        layer.modulate_chunk(&mut chunk);
+
+        let normalize = 1.0 / (1.0 + feed_level);
+        let expected = f32::from(IMPULSE) * feed_level * normalize;
+        let total: f32 = channel(&chunk, 1).iter().map(|s| f32::from(*s)).sum();
+        // Each sample is rounded on its way back to i16
+        assert!((total - expected).abs() < expected * 0.05);
+    }
+
+    #[test]
+    fn low_pass_softens_the_crossed_impulse() {
+        let mut layer = CrossfeedLayer::new(0.0, 700.0, 1.0);
+        let mut chunk = left_impulse(64);
+        layer.modulate_chunk(&mut chunk);
+
+        // Spread out over many samples, so no single one gets near the
+        // impulse itself
+        let right = channel(&chunk, 1);
+        let peak = right.iter().copied().max().unwrap_or(0);
+        assert!(peak > 0);
+        assert!(peak < IMPULSE / 10);
+        assert!(right[1] > 0);
+    }
+
+    #[test]
+    fn state_carries_across_chunk_boundaries() {
+        let mut whole = CrossfeedLayer::default();
+        let mut one_chunk = left_impulse(300);
+        whole.modulate_chunk(&mut one_chunk);
+
+        let mut split = CrossfeedLayer::default();
+        let samples = left_impulse(300).buffer().clone();
+        let mut pieces = vec![];
+        let mut start = 0;
+        // Odd sizes, so the delay line straddles the boundaries
+        for frames in [7, 1, 50, 242].iter() {
+            let range = start * 2..(start + frames) * 2;
+            start += frames;
+            let mut chunk = AudioChunk::new_from_data(2, RATE, samples[range].to_vec());
+            split.modulate_chunk(&mut chunk);
+            pieces.extend_from_slice(chunk.buffer());
+        }
+
+        assert_eq!(&pieces, one_chunk.buffer());
+    }
+
+    #[test]
+    fn mono_passes_through() {
+        let mut layer = CrossfeedLayer::default();
+        let mut chunk = AudioChunk::new_from_data(1, RATE, vec![IMPULSE, 0, -5, 3]);
+        layer.modulate_chunk(&mut chunk);
+        assert_eq!(chunk.buffer(), &vec![IMPULSE, 0, -5, 3]);
+    }
+}
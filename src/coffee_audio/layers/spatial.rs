@@ -0,0 +1,366 @@
+use std::sync::{Arc, Mutex};
+
+use crate::coffee_audio::types::{AudioChunk, AudioLayer};
+
+// Roughly an average human head, in meters
+const HEAD_RADIUS: f32 = 0.0875;
+// Speed of sound in air, in meters per second
+const SPEED_OF_SOUND: f32 = 343.0;
+// Distance (in meters) at which a source plays at full volume
+const DEFAULT_REFERENCE_DISTANCE: f32 = 1.0;
+
+/// A point in the room, in meters. The listener faces +y, so a source with
+/// a larger x than the listener is heard on the right.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Position {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Position {
+    pub fn new(x: f32, y: f32) -> Self {
+        Position { x, y }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Placement {
+    listener: Position,
+    source: Position,
+    reference_distance: f32,
+}
+
+/// A cloneable handle for moving a `SpatialLayer`'s listener and source
+/// around after the layer has been handed off to a `FilteredSource`. New
+/// positions are picked up at the start of the next chunk.
+#[derive(Clone, Debug)]
+pub struct SpatialHandle {
+    placement: Arc<Mutex<Placement>>,
+}
+
+impl SpatialHandle {
+    pub fn set_listener_position(&self, position: Position) {
+        if let Ok(mut p) = self.placement.lock() {
+            p.listener = position;
+        }
+    }
+
+    pub fn set_source_position(&self, position: Position) {
+        if let Ok(mut p) = self.placement.lock() {
+            p.source = position;
+        }
+    }
+
+    pub fn set_reference_distance(&self, distance: f32) {
+        if let Ok(mut p) = self.placement.lock() {
+            p.reference_distance = distance.max(0.01);
+        }
+    }
+
+    fn get(&self) -> Placement {
+        match self.placement.lock() {
+            Ok(p) => *p,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+}
+
+// Everything needed to render one output frame, derived from a Placement
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct SpatialParams {
+    gains: [f32; 2],
+    // Per-ear delay, in (fractional) samples
+    delays: [f32; 2],
+}
+
+impl SpatialParams {
+    fn from_placement(placement: &Placement, sample_rate: u32) -> Self {
+        let dx = placement.source.x - placement.listener.x;
+        let dy = placement.source.y - placement.listener.y;
+        let distance = (dx * dx + dy * dy).sqrt();
+
+        // Inverse distance law, clamped so that nothing gets louder than
+        // it would be at the reference distance
+        let attenuation = placement.reference_distance / distance.max(placement.reference_distance);
+
+        // Azimuth relative to straight ahead; positive is to the right.
+        // Sources behind the listener are folded to the front, since
+        // panning alone can't tell front from back anyway.
+        let azimuth = if distance > 0.0 { dx.atan2(dy) } else { 0.0 };
+        let lateral = azimuth.sin();
+
+        // Constant-power pan law
+        let pan_angle = (lateral + 1.0) * std::f32::consts::FRAC_PI_4;
+        let gains = [attenuation * pan_angle.cos(), attenuation * pan_angle.sin()];
+
+        // Woodworth's approximation of the interaural time difference,
+        // applied as a delay on the ear farther from the source
+        let side_angle = lateral.asin();
+        let itd = HEAD_RADIUS / SPEED_OF_SOUND * (side_angle.abs() + side_angle.abs().sin());
+        let itd_samples = itd * sample_rate as f32;
+        let delays = if lateral >= 0.0 {
+            [itd_samples, 0.0]
+        } else {
+            [0.0, itd_samples]
+        };
+
+        SpatialParams { gains, delays }
+    }
+}
+
+/// Places a mono voice somewhere in a 2D room relative to the listener,
+/// applying inverse-distance attenuation, constant-power panning and an
+/// interaural time difference. Output chunks are always stereo; stereo
+/// input is downmixed to mono first.
+///
+/// Changing positions never jumps: gains and delays are ramped from their
+/// previous values across the following chunk.
+pub struct SpatialLayer {
+    handle: SpatialHandle,
+    current: Option<SpatialParams>,
+    sample_rate: u32,
+    // The last few mono input samples, needed to delay across chunk edges
+    history: Vec<f32>,
+    mono: Vec<f32>,
+}
+
+impl SpatialLayer {
+    pub fn new(listener: Position, source: Position) -> Self {
+        SpatialLayer {
+            handle: SpatialHandle {
+                placement: Arc::new(Mutex::new(Placement {
+                    listener,
+                    source,
+                    reference_distance: DEFAULT_REFERENCE_DISTANCE,
+                })),
+            },
+            current: None,
+            sample_rate: 0,
+            history: vec![],
+            mono: vec![],
+        }
+    }
+
+    /// Returns a handle that can move this layer's positions from another
+    /// thread while it is playing.
+    pub fn handle(&self) -> SpatialHandle {
+        self.handle.clone()
+    }
+
+    pub fn set_reference_distance(&mut self, distance: f32) {
+        self.handle.set_reference_distance(distance)
+    }
+
+    fn prepare(&mut self, sample_rate: u32) {
+        if sample_rate == self.sample_rate {
+            return;
+        }
+        self.sample_rate = sample_rate;
+        self.current = None;
+        let max_delay = HEAD_RADIUS / SPEED_OF_SOUND * (std::f32::consts::FRAC_PI_2 + 1.0);
+        let history_len = (max_delay * sample_rate as f32).ceil() as usize + 2;
+        self.history = vec![0.0; history_len];
+    }
+
+    // Reads the mono input at `index` relative to the start of this chunk,
+    // where negative indices reach back into the previous chunks.
+    fn sample_at(&self, index: isize) -> f32 {
+        if index >= 0 {
+            self.mono.get(index as usize).copied().unwrap_or(0.0)
+        } else {
+            let back = (-index) as usize;
+            if back <= self.history.len() {
+                self.history[self.history.len() - back]
+            } else {
+                0.0
+            }
+        }
+    }
+
+    fn delayed_sample(&self, frame: usize, delay: f32) -> f32 {
+        let position = frame as f32 - delay;
+        let base = position.floor();
+        let frac = position - base;
+        let base = base as isize;
+        let a = self.sample_at(base);
+        let b = self.sample_at(base + 1);
+        a + (b - a) * frac
+    }
+}
+
+impl AudioLayer for SpatialLayer {
+    fn modulate_chunk(&mut self, chunk: &mut AudioChunk) {
+        let channels = chunk.channel_count().max(1) as usize;
+        self.prepare(chunk.sample_rate());
+
+        // Downmix to mono
+        self.mono.clear();
+        for frame in chunk.buffer().chunks_exact(channels) {
+            let sum: f32 = frame.iter().map(|s| f32::from(*s)).sum();
+            self.mono.push(sum / channels as f32);
+        }
+
+        let target = SpatialParams::from_placement(&self.handle.get(), self.sample_rate);
+        let start = self.current.unwrap_or(target);
+        let frames = self.mono.len();
+
+        let mut output = Vec::with_capacity(frames * 2);
+        for i in 0..frames {
+            let t = (i + 1) as f32 / frames as f32;
+            for ear in 0..2 {
+                let gain = start.gains[ear] + (target.gains[ear] - start.gains[ear]) * t;
+                let delay = start.delays[ear] + (target.delays[ear] - start.delays[ear]) * t;
+                let value = self.delayed_sample(i, delay) * gain;
+                output.push(value.round().clamp(-32768.0, 32767.0) as i16);
+            }
+        }
+        self.current = Some(target);
+
+        // Keep the tail of this chunk around for the next one's delays
+        let keep = self.history.len();
+        if frames >= keep {
+            self.history.clear();
+            self.history.extend_from_slice(&self.mono[frames - keep..]);
+        } else {
+            self.history.drain(..frames);
+            self.history.extend_from_slice(&self.mono);
+        }
+
+        *chunk.buffer_mut() = output;
+        chunk.set_channel_count(2);
+    }
+
+    fn output_channel_count(&self, _input: u32) -> u32 {
+        2
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+    const IMPULSE: i16 = 16_000;
+
+    // A mono chunk of `frames` frames, silent apart from an impulse on the
+    // first frame
+    fn impulse(frames: usize) -> AudioChunk {
+        let mut samples = vec![0i16; frames];
+        samples[0] = IMPULSE;
+        AudioChunk::new_from_data(1, RATE, samples)
+    }
+
+    fn channel(chunk: &AudioChunk, ch: usize) -> Vec<i16> {
+        chunk.buffer().iter().skip(ch).step_by(2).copied().collect()
+    }
+
+    fn render(layer: &mut SpatialLayer, mut chunk: AudioChunk) -> (Vec<i16>, Vec<i16>) {
+        layer.modulate_chunk(&mut chunk);
+        assert_eq!(chunk.channel_count(), 2);
+        (channel(&chunk, 0), channel(&chunk, 1))
+    }
+
+    fn scaled(gain: f32) -> i16 {
+        (f32::from(IMPULSE) * gain).round() as i16
+    }
+
+    #[test]
+    fn gain_falls_off_with_distance_past_the_reference() {
+        let centre = std::f32::consts::FRAC_1_SQRT_2;
+        for (distance, attenuation) in &[(0.5, 1.0), (1.0, 1.0), (2.0, 0.5), (4.0, 0.25)] {
+            let mut layer = SpatialLayer::new(Position::default(), Position::new(0.0, *distance));
+            let (left, _) = render(&mut layer, impulse(64));
+            assert_eq!(left[0], scaled(centre * attenuation), "at {}m", distance);
+        }
+    }
+
+    #[test]
+    fn source_straight_ahead_is_even() {
+        let mut layer = SpatialLayer::new(Position::default(), Position::new(0.0, 3.0));
+        let (left, right) = render(&mut layer, impulse(64));
+        assert_eq!(left, right);
+        assert!(left[0] > 0);
+        assert!(left[1..].iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn pan_keeps_constant_power() {
+        for x in &[-1.0, -0.5, 0.0, 0.3, 1.0] {
+            let placement = Placement {
+                listener: Position::default(),
+                source: Position::new(*x, 0.5),
+                reference_distance: 10.0,
+            };
+            let params = SpatialParams::from_placement(&placement, RATE);
+            let power = params.gains[0].powi(2) + params.gains[1].powi(2);
+            assert!((power - 1.0).abs() < 1e-5, "{} at x = {}", power, x);
+        }
+    }
+
+    #[test]
+    fn hard_left_and_right_only_reach_one_ear() {
+        let mut layer = SpatialLayer::new(Position::default(), Position::new(1.0, 0.0));
+        let (left, right) = render(&mut layer, impulse(64));
+        assert!(left.iter().all(|s| *s == 0));
+        assert_eq!(right[0], IMPULSE);
+
+        let mut layer = SpatialLayer::new(Position::default(), Position::new(-1.0, 0.0));
+        let (left, right) = render(&mut layer, impulse(64));
+        assert_eq!(left[0], IMPULSE);
+        assert!(right.iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn far_ear_hears_it_late() {
+        // 45 degrees to the right, close enough not to be attenuated
+        let mut layer = SpatialLayer::new(Position::default(), Position::new(1.0, 1.0));
+        layer.set_reference_distance(10.0);
+        let (left, right) = render(&mut layer, impulse(64));
+
+        let angle = std::f32::consts::FRAC_PI_4;
+        let itd = HEAD_RADIUS / SPEED_OF_SOUND * (angle + angle.sin()) * RATE as f32;
+        let pan_angle = (angle.sin() + 1.0) * std::f32::consts::FRAC_PI_4;
+        let arrival = itd.floor() as usize;
+
+        // The near ear gets it straight away
+        assert_eq!(right[0], scaled(pan_angle.sin()));
+        assert!(right[1..].iter().all(|s| *s == 0));
+
+        // The far ear gets it split across the two samples either side of
+        // the fractional delay
+        assert!(left[..arrival].iter().all(|s| *s == 0));
+        let spread = i32::from(left[arrival]) + i32::from(left[arrival + 1]);
+        assert!((spread - i32::from(scaled(pan_angle.cos()))).abs() <= 1);
+        assert!(left[arrival + 2..].iter().all(|s| *s == 0));
+    }
+
+    #[test]
+    fn moving_between_chunks_ramps_without_clicks() {
+        const LEVEL: i16 = 10_000;
+        let steady = || AudioChunk::new_from_data(1, RATE, vec![LEVEL; 480]);
+
+        let mut layer = SpatialLayer::new(Position::default(), Position::new(0.0, 1.0));
+        let handle = layer.handle();
+        let (first_left, first_right) = render(&mut layer, steady());
+
+        // Step to the side, so the source ends up hard right
+        handle.set_listener_position(Position::new(-1.0, 1.0));
+        let (second_left, second_right) = render(&mut layer, steady());
+
+        for (first, second) in &[(&first_left, &second_left), (&first_right, &second_right)] {
+            let joined: Vec<i32> = first
+                .iter()
+                .chain(second.iter())
+                .map(|s| i32::from(*s))
+                .collect();
+            let biggest_step = joined
+                .windows(2)
+                .map(|w| (w[1] - w[0]).abs())
+                .max()
+                .unwrap_or(0);
+            assert!(biggest_step < 50, "jumped by {}", biggest_step);
+        }
+        assert_eq!(second_left.last().copied(), Some(0));
+        assert_eq!(second_right.last().copied(), Some(LEVEL));
+    }
+}
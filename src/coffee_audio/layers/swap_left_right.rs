@@ -4,6 +4,10 @@ pub struct SwapLRLayer {}
 
 impl AudioLayer for SwapLRLayer {
     fn modulate_chunk(&mut self, chunk: &mut AudioChunk) {
+        // There's no left and right to a mono chunk
+        if chunk.channel_count() != 2 {
+            return;
+        }
         let data = chunk.buffer_mut();
         for c in data.chunks_exact_mut(2) {
             c.swap(0, 1);
@@ -0,0 +1,417 @@
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use crate::coffee_audio::types::{AudioChunk, AudioLayer, AudioSource};
+
+const DEFAULT_FRAMES_PER_CHUNK: usize = 1024;
+// About -3dB, leaving some room for several voices talking at once
+const DEFAULT_MASTER_GAIN: f32 = 0.7;
+// Above this level (as a fraction of full scale) the soft clipper kicks in
+const SOFT_CLIP_KNEE: f32 = 0.8;
+// How much of an input's level is left after each chunk it's quiet for, so
+// the meter falls back over a couple of hundred milliseconds
+const LEVEL_DECAY: f32 = 0.85;
+
+/// Identifies an input that was added to an `AudioMixer`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct MixerInputId(u64);
+
+/// One voice (or any other stream) feeding into an `AudioMixer`, with its
+/// own chain of layers and its own gain.
+pub struct MixerInput {
+    source: Box<dyn AudioSource + Send>,
+    filters: Vec<Box<dyn AudioLayer + Send>>,
+    gain: f32,
+    // Recent peak of the source, as a fraction of full scale
+    level: f32,
+    // Samples already pulled and filtered, but not mixed yet
+    pending: VecDeque<f32>,
+    finished: bool,
+}
+
+impl MixerInput {
+    pub fn new<S: 'static + AudioSource + Send>(source: S) -> Self {
+        MixerInput {
+            source: Box::new(source),
+            filters: vec![],
+            gain: 1.0,
+            level: 0.0,
+            pending: VecDeque::new(),
+            finished: false,
+        }
+    }
+
+    pub fn set_gain(&mut self, gain: f32) {
+        self.gain = gain.max(0.0);
+    }
+
+    /// How loud the source has been lately, before gain, from 0 to 1.
+    pub fn level(&self) -> f32 {
+        self.level
+    }
+
+    // Pulls from the source until at least `samples` mixer samples are
+    // pending, or the source runs dry.
+    fn fill(&mut self, samples: usize, channel_count: u32) {
+        while self.pending.len() < samples && !self.finished {
+            let channels = self.source.channel_count();
+            let rate = self.source.sample_rate();
+            let (data, has_more) = self.source.pull_samples();
+            let mut chunk = AudioChunk::new_from_data(channels, rate, data.to_vec());
+            self.finished = !has_more;
+            let peak = chunk
+                .buffer()
+                .iter()
+                .map(|s| (f32::from(*s) / 32768.0).abs())
+                .fold(0.0, f32::max);
+            self.level = peak.max(self.level * LEVEL_DECAY);
+            for f in self.filters.iter_mut() {
+                f.modulate_chunk(&mut chunk);
+            }
+            push_converted(&mut self.pending, &chunk, channel_count);
+
+            // A stream that says it has more but hands back nothing would
+            // spin here forever, so treat it as an underrun instead
+            if chunk.buffer().is_empty() {
+                break;
+            }
+        }
+    }
+}
+
+// Appends `chunk` to `out` as normalized floats, up- or down-mixing it to
+// `channel_count` channels on the way.
+fn push_converted(out: &mut VecDeque<f32>, chunk: &AudioChunk, channel_count: u32) {
+    let in_channels = chunk.channel_count().max(1) as usize;
+    let out_channels = channel_count.max(1) as usize;
+    for frame in chunk.buffer().chunks_exact(in_channels) {
+        if in_channels == out_channels {
+            out.extend(frame.iter().map(|s| f32::from(*s) / 32768.0));
+        } else {
+            let mono =
+                frame.iter().map(|s| f32::from(*s)).sum::<f32>() / (in_channels as f32 * 32768.0);
+            out.extend(std::iter::repeat_n(mono, out_channels));
+        }
+    }
+}
+
+// Leaves quiet signals alone and smoothly squashes anything above the knee
+// so that it never exceeds full scale.
+fn soft_clip(sample: f32) -> f32 {
+    let magnitude = sample.abs();
+    if magnitude <= SOFT_CLIP_KNEE {
+        return sample;
+    }
+    let range = 1.0 - SOFT_CLIP_KNEE;
+    let squashed = SOFT_CLIP_KNEE + range * ((magnitude - SOFT_CLIP_KNEE) / range).tanh();
+    squashed.copysign(sample)
+}
+
+struct MixerInputs {
+    next_id: u64,
+    inputs: Vec<(MixerInputId, MixerInput)>,
+    master_gain: f32,
+    // Applied to the finished mix, after clipping
+    output_filters: Vec<Box<dyn AudioLayer + Send>>,
+}
+
+/// A cloneable handle for adding, removing and adjusting the inputs of an
+/// `AudioMixer`, usable from any thread while the mixer is being played.
+#[derive(Clone)]
+pub struct MixerHandle {
+    inner: Arc<Mutex<MixerInputs>>,
+}
+
+impl MixerHandle {
+    fn lock_ref(&self) -> MutexGuard<'_, MixerInputs> {
+        // A panic mid-mix leaves the inputs in a perfectly usable state
+        match self.inner.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn add_input(&self, input: MixerInput) -> MixerInputId {
+        let mut inner = self.lock_ref();
+        let id = MixerInputId(inner.next_id);
+        inner.next_id += 1;
+        inner.inputs.push((id, input));
+        id
+    }
+
+    /// Removes an input, returning it if it was still in the mixer.
+    pub fn remove_input(&self, id: MixerInputId) -> Option<MixerInput> {
+        let mut inner = self.lock_ref();
+        let index = inner.inputs.iter().position(|(i, _)| *i == id)?;
+        Some(inner.inputs.remove(index).1)
+    }
+
+    pub fn set_input_gain(&self, id: MixerInputId, gain: f32) {
+        if let Some((_, input)) = self.lock_ref().inputs.iter_mut().find(|(i, _)| *i == id) {
+            input.set_gain(gain);
+        }
+    }
+
+    /// How loud an input has been lately (see `MixerInput::level`), or None
+    /// if it's no longer in the mixer.
+    pub fn input_level(&self, id: MixerInputId) -> Option<f32> {
+        self.lock_ref()
+            .inputs
+            .iter()
+            .find(|(i, _)| *i == id)
+            .map(|(_, input)| input.level())
+    }
+
+    /// Replaces the layers one input goes through before it's mixed.
+    pub fn set_input_filters(&self, id: MixerInputId, filters: Vec<Box<dyn AudioLayer + Send>>) {
+        if let Some((_, input)) = self.lock_ref().inputs.iter_mut().find(|(i, _)| *i == id) {
+            input.filters = filters;
+        }
+    }
+
+    /// Replaces the layers the whole mix goes through on its way out. They
+    /// see chunks at the mixer's channel count, so stereo-only layers like
+    /// crossfeed belong here rather than on a mono input.
+    pub fn set_output_filters(&self, filters: Vec<Box<dyn AudioLayer + Send>>) {
+        self.lock_ref().output_filters = filters;
+    }
+}
+
+/// Sums any number of input streams into a single stream, so that one
+/// player can drive the whole room. Inputs are mixed at the mixer's channel
+/// count (mono and stereo inputs are converted) and are expected to run at
+/// the mixer's sample rate.
+///
+/// Inputs that finish are dropped automatically. The mixer itself never
+/// finishes; with no inputs it simply plays silence.
+pub struct AudioMixer {
+    handle: MixerHandle,
+    frames_per_chunk: usize,
+    chunk: AudioChunk,
+    mix_buffer: Vec<f32>,
+}
+
+impl AudioMixer {
+    pub fn new_with_format(channel_count: u32, sample_rate: u32) -> Self {
+        AudioMixer {
+            handle: MixerHandle {
+                inner: Arc::new(Mutex::new(MixerInputs {
+                    next_id: 0,
+                    inputs: vec![],
+                    master_gain: DEFAULT_MASTER_GAIN,
+                    output_filters: vec![],
+                })),
+            },
+            frames_per_chunk: DEFAULT_FRAMES_PER_CHUNK,
+            chunk: AudioChunk::new_from_data(channel_count.max(1), sample_rate, vec![]),
+            mix_buffer: vec![],
+        }
+    }
+
+    /// Returns a handle for managing inputs while the mixer is playing.
+    pub fn handle(&self) -> MixerHandle {
+        self.handle.clone()
+    }
+
+    /// Mixes the next chunk from all inputs.
+    pub fn mix_chunk(&mut self) -> &mut AudioChunk {
+        let channel_count = self.chunk.channel_count();
+        let samples = self.frames_per_chunk * channel_count as usize;
+        self.mix_buffer.clear();
+        self.mix_buffer.resize(samples, 0.0);
+
+        {
+            let mut inner = self.handle.lock_ref();
+            for (_, input) in inner.inputs.iter_mut() {
+                input.fill(samples, channel_count);
+                let available = input.pending.len().min(samples);
+                for (mixed, sample) in self
+                    .mix_buffer
+                    .iter_mut()
+                    .zip(input.pending.drain(..available))
+                {
+                    *mixed += sample * input.gain;
+                }
+            }
+            inner
+                .inputs
+                .retain(|(_, input)| !(input.finished && input.pending.is_empty()));
+
+            let master_gain = inner.master_gain;
+            for mixed in self.mix_buffer.iter_mut() {
+                *mixed *= master_gain;
+            }
+        }
+
+        let buffer = self.chunk.buffer_mut();
+        buffer.clear();
+        buffer.extend(
+            self.mix_buffer
+                .iter()
+                .map(|s| (soft_clip(*s) * 32767.0).round() as i16),
+        );
+        for f in self.handle.lock_ref().output_filters.iter_mut() {
+            f.modulate_chunk(&mut self.chunk);
+        }
+        &mut self.chunk
+    }
+}
+
+impl AudioSource for AudioMixer {
+    fn pull_samples(&mut self) -> (&mut [i16], bool) {
+        (&mut self.mix_chunk().buffer_mut()[..], true)
+    }
+    fn channel_count(&self) -> u32 {
+        self.chunk.channel_count()
+    }
+    fn sample_rate(&self) -> u32 {
+        self.chunk.sample_rate()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48_000;
+
+    // Hands out the same mono chunk over and over, optionally only a few
+    // times before saying it's done
+    struct Steady {
+        buffer: Vec<i16>,
+        chunks_left: Option<usize>,
+    }
+
+    impl Steady {
+        fn new(value: i16) -> Self {
+            Steady {
+                buffer: vec![value; 256],
+                chunks_left: None,
+            }
+        }
+
+        fn finite(value: i16, chunks: usize) -> Self {
+            Steady {
+                chunks_left: Some(chunks),
+                ..Steady::new(value)
+            }
+        }
+    }
+
+    impl AudioSource for Steady {
+        fn pull_samples(&mut self) -> (&mut [i16], bool) {
+            let has_more = match self.chunks_left.as_mut() {
+                Some(left) => {
+                    *left = left.saturating_sub(1);
+                    *left > 0
+                }
+                None => true,
+            };
+            (&mut self.buffer[..], has_more)
+        }
+        fn channel_count(&self) -> u32 {
+            1
+        }
+        fn sample_rate(&self) -> u32 {
+            RATE
+        }
+    }
+
+    // What the mixer should put out for a mix of `level` (in samples, before
+    // the master gain), as long as it stays under the clipping knee
+    fn expected(level: f32) -> i16 {
+        (level / 32768.0 * DEFAULT_MASTER_GAIN * 32767.0).round() as i16
+    }
+
+    fn close_to(actual: i16, expected: i16) -> bool {
+        (i32::from(actual) - i32::from(expected)).abs() <= 1
+    }
+
+    #[test]
+    fn inputs_are_summed() {
+        let mut mixer = AudioMixer::new_with_format(1, RATE);
+        let handle = mixer.handle();
+        handle.add_input(MixerInput::new(Steady::new(1_000)));
+        handle.add_input(MixerInput::new(Steady::new(2_000)));
+
+        let chunk = mixer.mix_chunk();
+        assert_eq!(chunk.buffer().len(), DEFAULT_FRAMES_PER_CHUNK);
+        assert!(chunk
+            .buffer()
+            .iter()
+            .all(|s| close_to(*s, expected(3_000.0))));
+    }
+
+    #[test]
+    fn each_input_has_its_own_gain() {
+        let mut mixer = AudioMixer::new_with_format(1, RATE);
+        let handle = mixer.handle();
+        let mut halved = MixerInput::new(Steady::new(4_000));
+        halved.set_gain(0.5);
+        handle.add_input(halved);
+        let muted = handle.add_input(MixerInput::new(Steady::new(4_000)));
+        handle.set_input_gain(muted, 0.0);
+
+        let chunk = mixer.mix_chunk();
+        assert!(chunk
+            .buffer()
+            .iter()
+            .all(|s| close_to(*s, expected(2_000.0))));
+    }
+
+    #[test]
+    fn full_scale_is_squashed_rather_than_wrapped() {
+        let mut mixer = AudioMixer::new_with_format(1, RATE);
+        let handle = mixer.handle();
+        handle.add_input(MixerInput::new(Steady::new(i16::MAX)));
+        handle.add_input(MixerInput::new(Steady::new(i16::MAX)));
+        let loud = mixer.mix_chunk().buffer().clone();
+
+        let knee = (SOFT_CLIP_KNEE * 32767.0) as i16;
+        assert!(loud.iter().all(|s| *s > knee));
+
+        let mut mixer = AudioMixer::new_with_format(1, RATE);
+        let handle = mixer.handle();
+        handle.add_input(MixerInput::new(Steady::new(i16::MIN)));
+        handle.add_input(MixerInput::new(Steady::new(i16::MIN)));
+        let quiet = mixer.mix_chunk().buffer().clone();
+        assert!(quiet.iter().all(|s| *s < -knee && *s >= -32767));
+
+        // Below the knee nothing changes, above it nothing passes full scale
+        assert_eq!(soft_clip(0.5), 0.5);
+        assert!(soft_clip(100.0) <= 1.0);
+        assert!(soft_clip(-100.0) >= -1.0);
+    }
+
+    #[test]
+    fn finished_inputs_are_dropped() {
+        let mut mixer = AudioMixer::new_with_format(1, RATE);
+        let handle = mixer.handle();
+        let short = handle.add_input(MixerInput::new(Steady::finite(1_000, 2)));
+
+        let chunk = mixer.mix_chunk();
+        assert!(chunk.buffer()[..512]
+            .iter()
+            .all(|s| close_to(*s, expected(1_000.0))));
+        assert!(chunk.buffer()[512..].iter().all(|s| *s == 0));
+        assert_eq!(handle.input_level(short), None);
+    }
+
+    #[test]
+    fn inputs_can_be_removed_between_chunks() {
+        let mut mixer = AudioMixer::new_with_format(1, RATE);
+        let handle = mixer.handle();
+        handle.add_input(MixerInput::new(Steady::new(1_000)));
+        let leaving = handle.add_input(MixerInput::new(Steady::new(2_000)));
+
+        assert!(close_to(mixer.mix_chunk().buffer()[0], expected(3_000.0)));
+        assert!(handle.remove_input(leaving).is_some());
+        assert!(handle.remove_input(leaving).is_none());
+
+        let chunk = mixer.mix_chunk();
+        assert!(chunk
+            .buffer()
+            .iter()
+            .all(|s| close_to(*s, expected(1_000.0))));
+    }
+}
@@ -0,0 +1,118 @@
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+use crate::coffee_audio::layers::{CrossfeedLayer, SwapLRLayer};
+use crate::coffee_audio::types::AudioLayer;
+use crate::coffee_audio::voice_codec::VoiceCodecSettings;
+
+/// Volume for anyone we haven't turned up or down
+pub const DEFAULT_PEER_VOLUME: f32 = 1.0;
+
+/// Everything about how the room sounds that's worth remembering between
+/// launches.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct AudioSettings {
+    /// Microphone to capture from; None uses the system's default
+    pub capture_device: Option<String>,
+    /// Peers we don't want to hear at all, whatever their volume
+    pub muted: BTreeSet<Uuid>,
+    /// Gain for each peer we've changed the volume of, by peer id
+    pub peer_volumes: BTreeMap<Uuid, f32>,
+    pub layers: LayerSettings,
+    /// How our own voice is encoded
+    pub codec: VoiceCodecSettings,
+}
+
+impl AudioSettings {
+    pub fn peer_volume(&self, id: Uuid) -> f32 {
+        self.peer_volumes
+            .get(&id)
+            .copied()
+            .unwrap_or(DEFAULT_PEER_VOLUME)
+    }
+
+    pub fn is_muted(&self, id: Uuid) -> bool {
+        self.muted.contains(&id)
+    }
+
+    pub fn set_muted(&mut self, id: Uuid, muted: bool) {
+        if muted {
+            self.muted.insert(id);
+        } else {
+            self.muted.remove(&id);
+        }
+    }
+
+    /// The gain a peer's voice is actually mixed at.
+    pub fn peer_gain(&self, id: Uuid) -> f32 {
+        if self.is_muted(id) {
+            0.0
+        } else {
+            self.peer_volume(id)
+        }
+    }
+
+    pub fn set_peer_volume(&mut self, id: Uuid, volume: f32) {
+        let volume = volume.max(0.0);
+        // No need to remember anyone who's back to normal
+        if (volume - DEFAULT_PEER_VOLUME).abs() < f32::EPSILON {
+            self.peer_volumes.remove(&id);
+        } else {
+            self.peer_volumes.insert(id, volume);
+        }
+    }
+}
+
+/// Which layers the whole room goes through on its way to the speakers.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(default)]
+pub struct LayerSettings {
+    // Plain values have to come before tables in TOML
+    pub swap_left_right: bool,
+    /// Seats each voice at its own place around us, rather than all of
+    /// them in the middle. Applied to each voice, not the whole room.
+    pub spatial: bool,
+    pub crossfeed: CrossfeedSettings,
+}
+
+impl LayerSettings {
+    /// Builds the layers these settings ask for, in the order they apply.
+    pub fn build(&self) -> Vec<Box<dyn AudioLayer + Send>> {
+        let mut layers: Vec<Box<dyn AudioLayer + Send>> = vec![];
+        if self.crossfeed.enabled {
+            layers.push(Box::new(CrossfeedLayer::new(
+                self.crossfeed.delay_ms,
+                self.crossfeed.cutoff_hz,
+                self.crossfeed.feed_level,
+            )));
+        }
+        if self.swap_left_right {
+            layers.push(Box::new(SwapLRLayer {}));
+        }
+        layers
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct CrossfeedSettings {
+    pub enabled: bool,
+    pub delay_ms: f32,
+    pub cutoff_hz: f32,
+    pub feed_level: f32,
+}
+
+impl Default for CrossfeedSettings {
+    fn default() -> Self {
+        let layer = CrossfeedLayer::default();
+        CrossfeedSettings {
+            enabled: false,
+            delay_ms: layer.delay_ms(),
+            cutoff_hz: layer.cutoff_hz(),
+            feed_level: layer.feed_level(),
+        }
+    }
+}
@@ -1,5 +1,13 @@
 mod file_source;
 mod filtered_source;
+mod mic_source;
+#[cfg(test)]
+mod tone_source;
+mod voice_source;
 
 pub use file_source::FileSource;
 pub use filtered_source::FilteredSource;
+pub use mic_source::{frames_for_duration, MicSource};
+#[cfg(test)]
+pub use tone_source::ToneSource;
+pub use voice_source::VoiceSource;
@@ -1,9 +1,8 @@
 use std::fmt;
 
 use sfml::audio::SoundBuffer;
-use sfml::audio::SoundStream;
 
-use crate::coffee_audio::types::AudioChunk;
+use crate::coffee_audio::types::{AudioChunk, AudioSource};
 
 const SAMPLES_PER_CHUNK: usize = 20000;
 
@@ -46,11 +45,8 @@ impl FileSource {
     }
 }
 
-impl SoundStream for FileSource {
-    // Seek does nothing, this stream just plays until it ends
-    fn seek(&mut self, _: sfml::system::Time) {}
-
-    fn get_data(&mut self) -> (&mut [i16], bool) {
+impl AudioSource for FileSource {
+    fn pull_samples(&mut self) -> (&mut [i16], bool) {
         // Calculate remaining samples
         let remaining = self.data.buffer().len() - self.play_head;
         let (size, keep_playing) = if remaining >= SAMPLES_PER_CHUNK {
@@ -1,14 +1,12 @@
-use sfml::audio::SoundStream;
+use crate::coffee_audio::types::{AudioChunk, AudioLayer, AudioSource};
 
-use crate::coffee_audio::types::{AudioChunk, AudioLayer};
-
-pub struct FilteredSource<S: SoundStream> {
+pub struct FilteredSource<S: AudioSource> {
     base: Box<S>,
-    filters: Vec<Box<dyn AudioLayer>>,
+    filters: Vec<Box<dyn AudioLayer + Send>>,
     chunk: AudioChunk,
 }
 
-impl<T: SoundStream> FilteredSource<T> {
+impl<T: AudioSource> FilteredSource<T> {
     pub fn new(base: T) -> Self {
         FilteredSource {
             base: Box::new(base),
@@ -17,30 +15,39 @@ impl<T: SoundStream> FilteredSource<T> {
         }
     }
 
-    pub fn add_filter<A: 'static + AudioLayer>(&mut self, filter: A) {
+    pub fn add_filter<A: 'static + AudioLayer + Send>(&mut self, filter: A) {
         self.filters.push(Box::from(filter));
     }
+
+    /// Replaces the layers the source's audio goes through.
+    pub fn set_filters(&mut self, filters: Vec<Box<dyn AudioLayer + Send>>) {
+        self.filters = filters;
+    }
 }
 
-impl<T: SoundStream> SoundStream for FilteredSource<T> {
-    fn get_data(&mut self) -> (&mut [i16], bool) {
+impl<T: AudioSource> AudioSource for FilteredSource<T> {
+    fn pull_samples(&mut self) -> (&mut [i16], bool) {
         let channels = self.base.channel_count();
         let rate = self.base.sample_rate();
-        let (data, has_more) = self.base.get_data();
-        self.chunk = AudioChunk::new_from_data(channels, rate, data.to_vec());
+        let (data, has_more) = self.base.pull_samples();
+        // Reuse the last chunk's buffer rather than allocating every pull
+        self.chunk.set_channel_count(channels);
+        self.chunk.set_sample_rate(rate);
+        let buffer = self.chunk.buffer_mut();
+        buffer.clear();
+        buffer.extend_from_slice(data);
         for f in self.filters.iter_mut() {
             f.modulate_chunk(&mut self.chunk);
         }
 
         (&mut self.chunk.buffer_mut()[..], has_more)
     }
-    fn seek(&mut self, time: sfml::system::Time) {
-        self.base.seek(time)
-    }
     fn channel_count(&self) -> u32 {
-        // TODO: Precalculate final filter's channel count
-        // NOTE: Maybe filters should return whether they change the channel count?
-        self.base.channel_count()
+        self.filters
+            .iter()
+            .fold(self.base.channel_count(), |count, f| {
+                f.output_channel_count(count)
+            })
     }
     fn sample_rate(&self) -> u32 {
         // TODO: Precalculate final filter's sample rate
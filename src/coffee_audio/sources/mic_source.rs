@@ -0,0 +1,227 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use tokio::sync::mpsc;
+
+use crate::coffee_audio::capture::CaptureDevice;
+use crate::coffee_audio::types::{AudioChunk, AudioSource};
+
+// How many frames may queue up before new ones are dropped; at 20ms per
+// frame this is about a second of audio
+const CHANNEL_CAPACITY: usize = 50;
+
+/// Number of frames in `ms` milliseconds of audio at `sample_rate`.
+pub fn frames_for_duration(sample_rate: u32, ms: u32) -> usize {
+    (sample_rate as usize * ms as usize) / 1000
+}
+
+/// Captures audio from a CaptureDevice on a background thread and hands it
+/// out as fixed-size AudioChunks over a channel, ready to be encoded and
+/// sent to peers. If the receiving end falls behind, the newest frames are
+/// dropped rather than letting latency build up.
+///
+/// A MicSource can also be played directly (e.g. to monitor yourself) as
+/// long as nobody has taken its receiver.
+pub struct MicSource {
+    channel_count: u32,
+    sample_rate: u32,
+    frame_size: usize,
+    receiver: Option<mpsc::Receiver<AudioChunk>>,
+    running: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    chunk: AudioChunk,
+}
+
+impl MicSource {
+    /// Starts capturing from `device`, producing chunks of `frame_size`
+    /// frames each.
+    pub fn start<D: CaptureDevice>(mut device: D, frame_size: usize) -> Self {
+        let channel_count = device.channel_count();
+        let sample_rate = device.sample_rate();
+        let frame_size = frame_size.max(1);
+        let (tx, rx) = mpsc::channel::<AudioChunk>(CHANNEL_CAPACITY);
+        let running = Arc::new(AtomicBool::new(true));
+
+        let thread = {
+            let running = running.clone();
+            let mut tx = tx;
+            std::thread::spawn(move || {
+                let samples_per_frame = frame_size * channel_count.max(1) as usize;
+                let mut pending: Vec<i16> = Vec::with_capacity(samples_per_frame * 2);
+                let mut sink = |samples: &[i16]| {
+                    if !running.load(Ordering::SeqCst) {
+                        return false;
+                    }
+                    pending.extend_from_slice(samples);
+                    while pending.len() >= samples_per_frame {
+                        let frame: Vec<i16> = pending.drain(..samples_per_frame).collect();
+                        let chunk = AudioChunk::new_from_data(channel_count, sample_rate, frame);
+                        match tx.try_send(chunk) {
+                            Ok(()) => {}
+                            Err(mpsc::error::TrySendError::Full(_)) => {}
+                            Err(mpsc::error::TrySendError::Closed(_)) => return false,
+                        }
+                    }
+                    true
+                };
+                device.run(&mut sink);
+                running.store(false, Ordering::SeqCst);
+            })
+        };
+
+        MicSource {
+            channel_count,
+            sample_rate,
+            frame_size,
+            receiver: Some(rx),
+            running,
+            thread: Some(thread),
+            chunk: AudioChunk::new_from_data(channel_count, sample_rate, vec![]),
+        }
+    }
+
+    /// Takes the receiving end of the capture channel, e.g. for the network
+    /// layer. Can only be taken once.
+    pub fn take_receiver(&mut self) -> Option<mpsc::Receiver<AudioChunk>> {
+        self.receiver.take()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Stops capturing and waits for the capture thread to finish.
+    pub fn stop(&mut self) {
+        self.running.store(false, Ordering::SeqCst);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for MicSource {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl AudioSource for MicSource {
+    fn pull_samples(&mut self) -> (&mut [i16], bool) {
+        let next = self.receiver.as_mut().and_then(|rx| rx.try_recv().ok());
+        match next {
+            Some(chunk) => self.chunk = chunk,
+            None => {
+                // Nothing captured yet, so play a frame of silence
+                let silence = self.frame_size * self.channel_count.max(1) as usize;
+                let buffer = self.chunk.buffer_mut();
+                buffer.clear();
+                buffer.resize(silence, 0);
+            }
+        }
+        let has_more = self.receiver.is_some() && self.is_running();
+        (&mut self.chunk.buffer_mut()[..], has_more)
+    }
+
+    fn channel_count(&self) -> u32 {
+        self.channel_count
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coffee_audio::capture::SourceCaptureDevice;
+    use crate::coffee_audio::sources::ToneSource;
+
+    const RATE: u32 = 48_000;
+    const FRAME: usize = 480;
+
+    // A tone "microphone" that gives `frames` frames as fast as it's asked
+    fn tone_device(frames: u64) -> SourceCaptureDevice<ToneSource> {
+        SourceCaptureDevice::new("tone", ToneSource::new(440.0, RATE, Some(frames)))
+    }
+
+    // The first `frames` samples the same tone produces
+    fn tone_samples(frames: usize) -> Vec<i16> {
+        let mut tone = ToneSource::new(440.0, RATE, Some(frames as u64));
+        let mut samples = vec![];
+        loop {
+            let (data, has_more) = tone.pull_samples();
+            samples.extend_from_slice(data);
+            if !has_more {
+                return samples;
+            }
+        }
+    }
+
+    // Waits for a device that runs dry to finish handing everything over
+    fn wait_for_finish(mic: &MicSource) {
+        let started = std::time::Instant::now();
+        while mic.is_running() {
+            assert!(started.elapsed().as_secs() < 5, "capture never finished");
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+    }
+
+    fn drain(receiver: &mut mpsc::Receiver<AudioChunk>) -> Vec<AudioChunk> {
+        let mut chunks = vec![];
+        while let Ok(chunk) = receiver.try_recv() {
+            chunks.push(chunk);
+        }
+        chunks
+    }
+
+    #[test]
+    fn cuts_what_the_device_gives_into_whole_frames() {
+        // The tone comes in 960-frame pieces, which don't line up with ours
+        let mut mic = MicSource::start(tone_device(5 * FRAME as u64 + 100), FRAME);
+        let mut receiver = mic.take_receiver().unwrap();
+        wait_for_finish(&mic);
+        let chunks = drain(&mut receiver);
+        assert_eq!(chunks.len(), 5);
+        for chunk in &chunks {
+            assert_eq!(chunk.channel_count(), 1);
+            assert_eq!(chunk.sample_rate(), RATE);
+            assert_eq!(chunk.buffer().len(), FRAME);
+        }
+        let captured: Vec<i16> = chunks.iter().flat_map(|c| c.buffer().clone()).collect();
+        assert_eq!(captured, tone_samples(5 * FRAME));
+    }
+
+    #[test]
+    fn drops_the_newest_frames_once_the_channel_is_full() {
+        let frames = (CHANNEL_CAPACITY + 20) * FRAME;
+        let mut mic = MicSource::start(tone_device(frames as u64), FRAME);
+        let mut receiver = mic.take_receiver().unwrap();
+        wait_for_finish(&mic);
+        let chunks = drain(&mut receiver);
+        assert_eq!(chunks.len(), CHANNEL_CAPACITY);
+        assert_eq!(chunks[0].buffer(), &tone_samples(FRAME));
+    }
+
+    #[test]
+    fn stop_ends_capture_from_a_live_device() {
+        let mut device = SourceCaptureDevice::new("tone", ToneSource::new(440.0, RATE, None));
+        device.set_realtime(true);
+        let mut mic = MicSource::start(device, FRAME);
+        mic.stop();
+        assert!(!mic.is_running());
+        let (_, has_more) = mic.pull_samples();
+        assert!(!has_more);
+    }
+
+    #[test]
+    fn plays_silence_once_the_receiver_is_taken() {
+        let mut mic = MicSource::start(tone_device(10 * FRAME as u64), FRAME);
+        let _receiver = mic.take_receiver();
+        let (data, has_more) = mic.pull_samples();
+        assert_eq!(data.len(), FRAME);
+        assert!(data.iter().all(|&s| s == 0));
+        assert!(!has_more);
+    }
+}
@@ -0,0 +1,61 @@
+use crate::coffee_audio::types::{AudioChunk, AudioSource};
+
+const FRAMES_PER_CHUNK: usize = 960;
+// Peak level as a fraction of full scale
+const AMPLITUDE: f32 = 0.25;
+
+/// Generates a mono sine tone, either forever or for a set number of frames.
+pub struct ToneSource {
+    frequency: f32,
+    phase: f32,
+    remaining: Option<u64>,
+    chunk: AudioChunk,
+}
+
+impl ToneSource {
+    pub fn new(frequency: f32, sample_rate: u32, frames: Option<u64>) -> Self {
+        ToneSource {
+            frequency,
+            phase: 0.0,
+            remaining: frames,
+            chunk: AudioChunk::new_from_data(1, sample_rate, vec![]),
+        }
+    }
+}
+
+impl AudioSource for ToneSource {
+    fn pull_samples(&mut self) -> (&mut [i16], bool) {
+        let frames = match self.remaining {
+            Some(remaining) => (remaining as usize).min(FRAMES_PER_CHUNK),
+            None => FRAMES_PER_CHUNK,
+        };
+        let step = 2.0 * std::f32::consts::PI * self.frequency / self.chunk.sample_rate() as f32;
+        let amplitude = AMPLITUDE * 32767.0;
+
+        let mut phase = self.phase;
+        let buffer = self.chunk.buffer_mut();
+        buffer.clear();
+        for _ in 0..frames {
+            buffer.push((phase.sin() * amplitude) as i16);
+            phase = (phase + step) % (2.0 * std::f32::consts::PI);
+        }
+        self.phase = phase;
+
+        let has_more = match self.remaining.as_mut() {
+            Some(remaining) => {
+                *remaining -= frames as u64;
+                *remaining > 0
+            }
+            None => true,
+        };
+        (&mut self.chunk.buffer_mut()[..], has_more)
+    }
+
+    fn channel_count(&self) -> u32 {
+        1
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.chunk.sample_rate()
+    }
+}
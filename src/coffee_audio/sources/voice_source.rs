@@ -0,0 +1,101 @@
+use std::sync::{Arc, Mutex};
+
+use log::debug;
+
+use crate::coffee_audio::types::{AudioChunk, AudioSource};
+use crate::coffee_audio::voice_codec::VoiceDecoder;
+use crate::coffee_network::{JitterBuffer, JitterOutput};
+
+// Lost frames in a row worth papering over (100ms); past that they've most
+// likely stopped sending, and there's only silence left to play
+const MAX_CONCEALED_FRAMES: usize = 5;
+
+/// Plays one remote peer's voice. Every pull takes the next frame out of
+/// the peer's jitter buffer and decodes it, so frames are consumed at
+/// exactly the rate the output device plays them.
+///
+/// Lost frames are papered over rather than left as hard gaps: rebuilt
+/// from the next packet's FEC data if it's already here, otherwise
+/// concealed by the codec. A longer gap goes quiet until they're back.
+pub struct VoiceSource {
+    buffer: Arc<Mutex<JitterBuffer>>,
+    decoder: VoiceDecoder,
+    frame_size: usize,
+    chunk: AudioChunk,
+    // Nothing to conceal until something has actually been played
+    started: bool,
+    // Frames papered over since the last real one
+    concealed: usize,
+}
+
+impl VoiceSource {
+    /// `frame_size` is the number of frames in one voice packet, used for
+    /// the silence played while the buffer fills.
+    pub fn new(buffer: Arc<Mutex<JitterBuffer>>, decoder: VoiceDecoder, frame_size: usize) -> Self {
+        let chunk =
+            AudioChunk::new_from_data(decoder.channel_count(), decoder.sample_rate(), vec![]);
+        VoiceSource {
+            buffer,
+            decoder,
+            frame_size,
+            chunk,
+            started: false,
+            concealed: 0,
+        }
+    }
+
+    fn play_silence(&mut self) {
+        let samples = self.frame_size * self.chunk.channel_count() as usize;
+        let buffer = self.chunk.buffer_mut();
+        buffer.clear();
+        buffer.resize(samples, 0);
+    }
+}
+
+impl AudioSource for VoiceSource {
+    fn pull_samples(&mut self) -> (&mut [i16], bool) {
+        let (next, following, finished) = match self.buffer.lock() {
+            Ok(mut buffer) => {
+                let next = buffer.pop();
+                (next, buffer.peek_next().cloned(), buffer.is_finished())
+            }
+            Err(_) => (JitterOutput::Waiting, None, true),
+        };
+        let decoded = match next {
+            JitterOutput::Frame(data) => {
+                self.started = true;
+                self.concealed = 0;
+                self.decoder.decode(&data)
+            }
+            JitterOutput::Missing | JitterOutput::Waiting
+                if self.started && self.concealed < MAX_CONCEALED_FRAMES =>
+            {
+                self.concealed += 1;
+                match following {
+                    Some(packet) => self.decoder.recover(&packet, self.frame_size),
+                    None => self.decoder.conceal(self.frame_size),
+                }
+            }
+            JitterOutput::Missing | JitterOutput::Waiting => {
+                self.play_silence();
+                return (&mut self.chunk.buffer_mut()[..], !finished);
+            }
+        };
+        match decoded {
+            Ok(chunk) => self.chunk = chunk,
+            Err(e) => {
+                debug!("Error decoding voice frame: {}", e);
+                self.play_silence();
+            }
+        }
+        (&mut self.chunk.buffer_mut()[..], !finished)
+    }
+
+    fn channel_count(&self) -> u32 {
+        self.chunk.channel_count()
+    }
+
+    fn sample_rate(&self) -> u32 {
+        self.chunk.sample_rate()
+    }
+}
@@ -48,8 +48,25 @@ impl AudioChunk {
     }
 }
 
+/// An AudioSource is anything that audio can be pulled from, one chunk of
+/// interleaved samples at a time. Backends (SFML, WAV files, etc.) drive a
+/// source by pulling from it until it says it has nothing more to give.
+pub trait AudioSource {
+    /// Returns the next block of samples, and whether there will be more
+    /// after this one.
+    fn pull_samples(&mut self) -> (&mut [i16], bool);
+    fn channel_count(&self) -> u32;
+    fn sample_rate(&self) -> u32;
+}
+
 /// An AudioLayer takes an AudioChunk as input and modifies the chunk.
 /// Your original data is lost/changed by this process
 pub trait AudioLayer {
     fn modulate_chunk(&mut self, chunk: &mut AudioChunk);
+
+    /// The channel count of chunks coming out of this layer, given chunks
+    /// with `input` channels going in. Most layers don't change it.
+    fn output_channel_count(&self, input: u32) -> u32 {
+        input
+    }
 }
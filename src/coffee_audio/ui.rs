@@ -0,0 +1,6 @@
+pub mod audio_dialog;
+
+pub use audio_dialog::{
+    launch_effects_dialog, launch_input_dialog, launch_peer_volume_dialog,
+    launch_voice_quality_dialog, launch_volume_dialog, set_peer_muted,
+};
@@ -0,0 +1,302 @@
+use cursive::traits::*;
+use cursive::views::{Checkbox, Dialog, LinearLayout, ListView, SelectView, SliderView, TextView};
+use cursive::Cursive;
+use uuid::Uuid;
+
+use crate::coffee_app::ConfigStore;
+use crate::coffee_audio::voice_codec::VoiceCodecSettings;
+use crate::coffee_audio::{capture, AudioController};
+use crate::coffee_network::NetworkController;
+
+// Volume sliders go from silent to twice as loud, in tenths
+const VOLUME_STEPS: usize = 20;
+const VOLUME_PER_STEP: f32 = 0.1;
+// Bitrates to pick our voice's from, in kbps
+const BITRATES_KBPS: [i32; 6] = [12, 16, 24, 32, 48, 64];
+const MAX_COMPLEXITY: u8 = 10;
+// Packet loss to prepare for, in percent
+const PACKET_LOSSES: [u8; 4] = [0, 5, 10, 20];
+
+fn checkbox(checked: bool) -> Checkbox {
+    if checked {
+        Checkbox::new().checked()
+    } else {
+        Checkbox::new()
+    }
+}
+
+// Writes whatever the audio settings are now back to the config
+fn save_settings(audio: &AudioController, config: &ConfigStore) {
+    let audio = audio.clone();
+    let config = config.clone();
+    tokio::spawn(async move {
+        let settings = audio.get_settings().await;
+        config.update(|c| c.audio = settings);
+    });
+}
+
+// A slider that sets one peer's volume as it moves
+fn volume_slider(audio: AudioController, id: Uuid, volume: f32) -> SliderView {
+    let steps = (volume / VOLUME_PER_STEP).round() as usize;
+    SliderView::horizontal(VOLUME_STEPS + 1)
+        .value(steps.min(VOLUME_STEPS))
+        .on_change(move |_, step| {
+            let audio = audio.clone();
+            let volume = step as f32 * VOLUME_PER_STEP;
+            tokio::spawn(async move { audio.set_peer_volume(id, volume).await });
+        })
+}
+
+/// Mutes or unmutes one peer, and remembers it.
+pub fn set_peer_muted(audio: &AudioController, config: &ConfigStore, id: Uuid, muted: bool) {
+    let audio = audio.clone();
+    let config = config.clone();
+    tokio::spawn(async move {
+        audio.set_peer_muted(id, muted).await;
+        save_settings(&audio, &config);
+    });
+}
+
+// Changes how our voice is encoded, and remembers it
+fn update_codec<F>(audio: &AudioController, config: &ConfigStore, change: F)
+where
+    F: FnOnce(&mut VoiceCodecSettings) + Send + 'static,
+{
+    let audio = audio.clone();
+    let config = config.clone();
+    tokio::spawn(async move {
+        let mut codec = audio.get_codec_settings().await;
+        change(&mut codec);
+        audio.set_codec_settings(codec).await;
+        save_settings(&audio, &config);
+    });
+}
+
+pub fn launch_input_dialog(siv: &mut Cursive, audio: AudioController, config: ConfigStore) {
+    let current = config.get().audio.capture_device;
+    let mut devices = SelectView::<Option<String>>::new();
+    devices.add_item(
+        format!("System default ({})", capture::default_device()),
+        None,
+    );
+    for name in capture::available_devices() {
+        devices.add_item(name.clone(), Some(name));
+    }
+    let selection = devices
+        .iter()
+        .position(|(_, device)| *device == current)
+        .unwrap_or(0);
+    let devices = devices
+        .selected(selection)
+        .on_submit(move |s, device: &Option<String>| {
+            let device = device.clone();
+            {
+                let audio = audio.clone();
+                let config = config.clone();
+                tokio::spawn(async move {
+                    audio.set_capture_device(device).await;
+                    save_settings(&audio, &config);
+                });
+            }
+            s.pop_layer();
+            s.add_layer(Dialog::info(
+                "The new microphone will be used next time coffeeshop starts.",
+            ));
+        });
+
+    siv.add_layer(
+        Dialog::around(devices.scrollable())
+            .title("Microphone")
+            .dismiss_button("Cancel"),
+    );
+}
+
+pub fn launch_effects_dialog(siv: &mut Cursive, audio: AudioController, config: ConfigStore) {
+    let layers = config.get().audio.layers;
+
+    // Applied as soon as they're ticked, so they can be heard right away
+    let set_layers = {
+        let audio = audio.clone();
+        move |s: &mut Cursive| {
+            let mut layers = layers;
+            if let Some(checked) = s.call_on_name("crossfeed", |v: &mut Checkbox| v.is_checked()) {
+                layers.crossfeed.enabled = checked;
+            }
+            if let Some(checked) = s.call_on_name("swap_lr", |v: &mut Checkbox| v.is_checked()) {
+                layers.swap_left_right = checked;
+            }
+            if let Some(checked) = s.call_on_name("spatial", |v: &mut Checkbox| v.is_checked()) {
+                layers.spatial = checked;
+            }
+            let audio = audio.clone();
+            tokio::spawn(async move { audio.set_layer_settings(layers).await });
+        }
+    };
+
+    let effects = ListView::new()
+        .child(
+            "Seat voices around me",
+            checkbox(layers.spatial)
+                .on_change({
+                    let set_layers = set_layers.clone();
+                    move |s, _| set_layers(s)
+                })
+                .with_name("spatial"),
+        )
+        .child(
+            "Crossfeed (for headphones)",
+            checkbox(layers.crossfeed.enabled)
+                .on_change({
+                    let set_layers = set_layers.clone();
+                    move |s, _| set_layers(s)
+                })
+                .with_name("crossfeed"),
+        )
+        .child(
+            "Swap left and right",
+            checkbox(layers.swap_left_right)
+                .on_change(move |s, _| set_layers(s))
+                .with_name("swap_lr"),
+        );
+
+    siv.add_layer(
+        Dialog::around(effects)
+            .title("Effects")
+            .button("Done", move |s| {
+                save_settings(&audio, &config);
+                s.pop_layer();
+            }),
+    );
+}
+
+pub fn launch_volume_dialog(
+    siv: &mut Cursive,
+    audio: AudioController,
+    net: NetworkController,
+    config: ConfigStore,
+) {
+    // Everyone who's in the room right now has to be looked up first
+    let cb_sink = siv.cb_sink().clone();
+    tokio::spawn(async move {
+        let peers = net.get_peers().await;
+        let settings = audio.get_settings().await;
+        let _ = cb_sink.send(Box::new(move |s: &mut Cursive| {
+            if peers.is_empty() {
+                s.add_layer(Dialog::info("There's nobody else here yet."));
+                return;
+            }
+            let mut volumes = ListView::new();
+            for peer in peers {
+                let volume = settings.peer_volume(peer.id);
+                volumes.add_child(
+                    &peer.nickname,
+                    volume_slider(audio.clone(), peer.id, volume),
+                );
+            }
+            s.add_layer(
+                Dialog::around(
+                    LinearLayout::vertical()
+                        .child(TextView::new("Turn people up or down, just for you."))
+                        .child(volumes),
+                )
+                .title("Volume")
+                .button("Done", move |s| {
+                    save_settings(&audio, &config);
+                    s.pop_layer();
+                }),
+            );
+        }));
+    });
+}
+
+/// The volume dialog for just one peer.
+pub fn launch_peer_volume_dialog(
+    siv: &mut Cursive,
+    audio: AudioController,
+    config: ConfigStore,
+    id: Uuid,
+    nickname: &str,
+) {
+    let volume = config.get().audio.peer_volume(id);
+    siv.add_layer(
+        Dialog::around(volume_slider(audio.clone(), id, volume))
+            .title(format!("Volume: {}", nickname))
+            .button("Done", move |s| {
+                save_settings(&audio, &config);
+                s.pop_layer();
+            }),
+    );
+}
+
+/// Trades how good our voice sounds against the bandwidth and CPU it takes.
+/// Changes are heard from the next frame on.
+pub fn launch_voice_quality_dialog(siv: &mut Cursive, audio: AudioController, config: ConfigStore) {
+    let codec = config.get().audio.codec;
+
+    let mut bitrates = SelectView::<i32>::new().popup();
+    for kbps in BITRATES_KBPS.iter() {
+        bitrates.add_item(format!("{} kbps", kbps), kbps * 1000);
+    }
+    // Keep whatever's in the config, even if it isn't one of ours
+    if !BITRATES_KBPS.contains(&(codec.bitrate / 1000)) || codec.bitrate % 1000 != 0 {
+        bitrates.add_item(format!("{} bps", codec.bitrate), codec.bitrate);
+    }
+    let selection = bitrates
+        .iter()
+        .position(|(_, bitrate)| *bitrate == codec.bitrate)
+        .unwrap_or(0);
+    let bitrates = {
+        let audio = audio.clone();
+        let config = config.clone();
+        bitrates
+            .selected(selection)
+            .on_submit(move |_, bitrate: &i32| {
+                let bitrate = *bitrate;
+                update_codec(&audio, &config, move |c| c.bitrate = bitrate);
+            })
+    };
+
+    let complexity = {
+        let audio = audio.clone();
+        let config = config.clone();
+        SliderView::horizontal(MAX_COMPLEXITY as usize + 1)
+            .value(codec.complexity.min(MAX_COMPLEXITY) as usize)
+            .on_change(move |_, step| {
+                update_codec(&audio, &config, move |c| c.complexity = step as u8);
+            })
+    };
+
+    let mut losses = SelectView::<u8>::new().popup();
+    for loss in PACKET_LOSSES.iter() {
+        match loss {
+            0 => losses.add_item("None", 0),
+            _ => losses.add_item(format!("{}%", loss), *loss),
+        }
+    }
+    let selection = PACKET_LOSSES
+        .iter()
+        .rposition(|loss| *loss <= codec.expected_packet_loss)
+        .unwrap_or(0);
+    let losses = losses.selected(selection).on_submit(move |_, loss: &u8| {
+        let loss = *loss;
+        update_codec(&audio, &config, move |c| c.expected_packet_loss = loss);
+    });
+
+    siv.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(
+                    ListView::new()
+                        .child("Bitrate", bitrates)
+                        .child("Quality (uses more CPU)", complexity)
+                        .child("Expected packet loss", losses),
+                )
+                .child(TextView::new(
+                    "Preparing for loss sends extra data, so that dropped packets can be \
+                     rebuilt by whoever's listening.",
+                )),
+        )
+        .title("Voice Quality")
+        .dismiss_button("Close"),
+    );
+}
@@ -0,0 +1,237 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use audiopus::coder::{Decoder, Encoder};
+use audiopus::packet::Packet;
+use audiopus::{Application, Bitrate, Channels, MutSignals, SampleRate};
+use serde::{Deserialize, Serialize};
+
+use crate::coffee_audio::types::AudioChunk;
+use crate::coffee_network::MAX_VOICE_FRAME_SIZE;
+
+/// Voice is sent in frames of this many milliseconds
+pub const VOICE_FRAME_MS: u32 = 20;
+
+// Longest frame Opus can decode (120ms at 48kHz)
+const MAX_FRAME_SIZE: usize = 5760;
+
+const DEFAULT_BITRATE: i32 = 24_000;
+const DEFAULT_COMPLEXITY: u8 = 8;
+// Expected loss (in percent) at which whole redundant frames start being sent
+const REDUNDANCY_LOSS_THRESHOLD: u8 = 10;
+
+#[derive(Debug)]
+pub enum VoiceCodecError {
+    /// Only mono and stereo at 8, 12, 16, 24 or 48kHz can be encoded
+    UnsupportedFormat {
+        channel_count: u32,
+        sample_rate: u32,
+    },
+    /// The chunk's format doesn't match what the codec was created for
+    FormatMismatch,
+    Opus(audiopus::Error),
+}
+
+impl fmt::Display for VoiceCodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VoiceCodecError::UnsupportedFormat {
+                channel_count,
+                sample_rate,
+            } => write!(
+                f,
+                "unsupported voice format: {} channel(s) at {}Hz",
+                channel_count, sample_rate
+            ),
+            VoiceCodecError::FormatMismatch => {
+                write!(f, "audio chunk doesn't match the codec's format")
+            }
+            VoiceCodecError::Opus(e) => write!(f, "opus error: {}", e),
+        }
+    }
+}
+
+impl Error for VoiceCodecError {}
+
+impl From<audiopus::Error> for VoiceCodecError {
+    fn from(e: audiopus::Error) -> Self {
+        VoiceCodecError::Opus(e)
+    }
+}
+
+/// Knobs for trading voice quality against bandwidth and CPU.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(default)]
+pub struct VoiceCodecSettings {
+    /// Target bitrate in bits per second
+    pub bitrate: i32,
+    /// Encoder complexity, from 0 (cheapest) to 10 (best quality)
+    pub complexity: u8,
+    /// Packet loss (in percent) to prepare for. At 0 nothing extra is sent.
+    /// Any loss turns on Opus' in-band FEC, which costs a little bitrate;
+    /// from 10% on, every packet also carries a copy of the previous frame,
+    /// roughly doubling bandwidth so any single lost packet can be rebuilt.
+    pub expected_packet_loss: u8,
+}
+
+impl VoiceCodecSettings {
+    /// Whether packets should carry a copy of the previous frame.
+    pub fn wants_redundancy(&self) -> bool {
+        self.expected_packet_loss >= REDUNDANCY_LOSS_THRESHOLD
+    }
+}
+
+impl Default for VoiceCodecSettings {
+    fn default() -> Self {
+        VoiceCodecSettings {
+            bitrate: DEFAULT_BITRATE,
+            complexity: DEFAULT_COMPLEXITY,
+            expected_packet_loss: 0,
+        }
+    }
+}
+
+fn opus_format(
+    channel_count: u32,
+    sample_rate: u32,
+) -> Result<(Channels, SampleRate), VoiceCodecError> {
+    let unsupported = VoiceCodecError::UnsupportedFormat {
+        channel_count,
+        sample_rate,
+    };
+    let channels = match channel_count {
+        1 => Channels::Mono,
+        2 => Channels::Stereo,
+        _ => return Err(unsupported),
+    };
+    let rate = SampleRate::try_from(sample_rate as i32).map_err(|_| unsupported)?;
+    Ok((channels, rate))
+}
+
+/// Compresses fixed-size voice frames into small packets for the network.
+pub struct VoiceEncoder {
+    encoder: Encoder,
+    channel_count: u32,
+    sample_rate: u32,
+    packet: Vec<u8>,
+}
+
+impl VoiceEncoder {
+    pub fn new(
+        channel_count: u32,
+        sample_rate: u32,
+        settings: VoiceCodecSettings,
+    ) -> Result<Self, VoiceCodecError> {
+        let (channels, rate) = opus_format(channel_count, sample_rate)?;
+        let mut encoder = VoiceEncoder {
+            encoder: Encoder::new(rate, channels, Application::Voip)?,
+            channel_count,
+            sample_rate,
+            packet: vec![0u8; MAX_VOICE_FRAME_SIZE],
+        };
+        encoder.apply_settings(settings)?;
+        Ok(encoder)
+    }
+
+    pub fn apply_settings(&mut self, settings: VoiceCodecSettings) -> Result<(), VoiceCodecError> {
+        self.encoder
+            .set_bitrate(Bitrate::BitsPerSecond(settings.bitrate))?;
+        self.encoder.set_complexity(settings.complexity.min(10))?;
+        let loss = settings.expected_packet_loss.min(100);
+        self.encoder.set_inband_fec(loss > 0)?;
+        self.encoder.set_packet_loss_perc(loss)?;
+        Ok(())
+    }
+
+    /// Number of frames per channel expected in each chunk passed to `encode`.
+    pub fn frame_size(&self) -> usize {
+        (self.sample_rate * VOICE_FRAME_MS / 1000) as usize
+    }
+
+    /// Encodes exactly one voice frame into a packet.
+    pub fn encode(&mut self, chunk: &AudioChunk) -> Result<Vec<u8>, VoiceCodecError> {
+        if chunk.channel_count() != self.channel_count
+            || chunk.sample_rate() != self.sample_rate
+            || chunk.buffer().len() != self.frame_size() * self.channel_count as usize
+        {
+            return Err(VoiceCodecError::FormatMismatch);
+        }
+        let len = self.encoder.encode(chunk.buffer(), &mut self.packet)?;
+        Ok(self.packet[..len].to_vec())
+    }
+}
+
+/// Turns packets from a `VoiceEncoder` back into audio. Each remote speaker
+/// needs their own decoder, since Opus decoding is stateful.
+pub struct VoiceDecoder {
+    decoder: Decoder,
+    channel_count: u32,
+    sample_rate: u32,
+    samples: Vec<i16>,
+}
+
+impl VoiceDecoder {
+    pub fn new(channel_count: u32, sample_rate: u32) -> Result<Self, VoiceCodecError> {
+        let (channels, rate) = opus_format(channel_count, sample_rate)?;
+        Ok(VoiceDecoder {
+            decoder: Decoder::new(rate, channels)?,
+            channel_count,
+            sample_rate,
+            samples: vec![0i16; MAX_FRAME_SIZE * channel_count as usize],
+        })
+    }
+
+    pub fn channel_count(&self) -> u32 {
+        self.channel_count
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn decode(&mut self, packet: &[u8]) -> Result<AudioChunk, VoiceCodecError> {
+        let samples = self.samples.len();
+        self.run_decoder(Some(packet), samples, false)
+    }
+
+    /// Fills in for a lost frame of `frames` frames using Opus' own loss
+    /// concealment, which extrapolates from what came before and fades out
+    /// over repeated losses.
+    pub fn conceal(&mut self, frames: usize) -> Result<AudioChunk, VoiceCodecError> {
+        let samples = frames.min(MAX_FRAME_SIZE) * self.channel_count as usize;
+        self.run_decoder(None, samples, false)
+    }
+
+    /// Rebuilds a lost frame of `frames` frames from the forward error
+    /// correction data in the packet that followed it. If that packet has
+    /// no FEC data, this falls back to plain concealment.
+    pub fn recover(
+        &mut self,
+        next_packet: &[u8],
+        frames: usize,
+    ) -> Result<AudioChunk, VoiceCodecError> {
+        let samples = frames.min(MAX_FRAME_SIZE) * self.channel_count as usize;
+        self.run_decoder(Some(next_packet), samples, true)
+    }
+
+    fn run_decoder(
+        &mut self,
+        packet: Option<&[u8]>,
+        samples: usize,
+        fec: bool,
+    ) -> Result<AudioChunk, VoiceCodecError> {
+        let packet = match packet {
+            Some(p) => Some(Packet::try_from(p)?),
+            None => None,
+        };
+        let output = MutSignals::try_from(&mut self.samples[..samples])?;
+        let frames = self.decoder.decode(packet, output, fec)?;
+        let len = frames * self.channel_count as usize;
+        Ok(AudioChunk::new_from_data(
+            self.channel_count,
+            self.sample_rate,
+            self.samples[..len].to_vec(),
+        ))
+    }
+}
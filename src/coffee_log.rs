@@ -0,0 +1,106 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+
+// Other crates only get to log problems; their chatter isn't ours
+const OTHER_CRATES_LEVEL: Level = Level::Warn;
+
+/// Where log lines show up besides the log file.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Console {
+    /// The UI's log panel. Anything written to the terminal would end up
+    /// all over the UI.
+    Panel,
+    /// Standard output, for a relay, where the log is all there is to show
+    Stdout,
+    /// Standard error, for one-off commands whose output is elsewhere
+    Stderr,
+}
+
+struct CoffeeLogger {
+    file: Option<Mutex<File>>,
+    console: Console,
+}
+
+impl CoffeeLogger {
+    fn write_file(&self, line: &str) {
+        if let Some(file) = &self.file {
+            // Losing a line is better than losing the app over it
+            let mut file = match file.lock() {
+                Ok(file) => file,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            let _ = writeln!(file, "{}", line);
+        }
+    }
+}
+
+impl Log for CoffeeLogger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+            || metadata.level() <= OTHER_CRATES_LEVEL
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} {}: {}",
+            chrono::Local::now().format("%Y-%m-%d %H:%M:%S%.3f"),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        self.write_file(&line);
+        match self.console {
+            Console::Panel => cursive::logger::log(record),
+            Console::Stdout => {
+                // A relay whose output has gone away keeps relaying
+                let _ = writeln!(io::stdout().lock(), "{}", line);
+            }
+            Console::Stderr => eprintln!("{}", line),
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            if let Ok(mut file) = file.lock() {
+                let _ = file.flush();
+            }
+        }
+    }
+}
+
+/// Sends everything logged at `level` or above to `console`, and appends
+/// it to `log_file` if there is one. Only one logger can ever be set up.
+pub fn init(
+    level: LevelFilter,
+    log_file: Option<&Path>,
+    console: Console,
+) -> Result<(), SetLoggerError> {
+    let file = log_file.and_then(|path| match open_log_file(path) {
+        Ok(file) => Some(Mutex::new(file)),
+        Err(e) => {
+            eprintln!("Unable to open log file {}: {}", path.display(), e);
+            None
+        }
+    });
+    if console == Console::Panel {
+        // Sets up the buffer the log panel reads from
+        cursive::logger::get_logger();
+    }
+    log::set_boxed_logger(Box::new(CoffeeLogger { file, console }))?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+fn open_log_file(path: &Path) -> io::Result<File> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    OpenOptions::new().create(true).append(true).open(path)
+}
@@ -1,23 +1,151 @@
 pub mod ui;
 
+mod admission;
+mod backoff;
+mod chat_history;
+mod crypto;
+mod framing;
+mod handshake;
+mod identity;
+mod jitter_buffer;
 mod peer;
+mod peer_registry;
+mod text_chat;
+mod transport;
+mod trust_store;
 
-use std::net::SocketAddr;
+use std::collections::hash_map::Entry;
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::Duration;
 
+use log::{debug, info, trace, warn};
+use serde::{Deserialize, Serialize};
 use tokio::net::{TcpListener, TcpStream};
 use tokio::sync::{broadcast, mpsc, RwLock};
 
 use uuid::Uuid;
 
+/// How long a peer can go without being heard from before we give up on it,
+/// unless told otherwise
+pub const DEFAULT_PEER_TIMEOUT: Duration = Duration::from_secs(10);
+// Heartbeats go out several times per timeout, so keep it sane
+const MIN_PEER_TIMEOUT: Duration = Duration::from_secs(1);
+// Retry schedule for peers that dropped out without saying goodbye. Adds up
+// to a few minutes before we decide they aren't coming back.
+const RECONNECT_INITIAL_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_MAX_DELAY: Duration = Duration::from_secs(30);
+const RECONNECT_ATTEMPTS: u32 = 10;
+// How long to wait for goodbyes to go out when leaving
+const LEAVE_TIMEOUT: Duration = Duration::from_millis(500);
+// Longest a handshake may take, start to finish, before we hang up
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Pause after a failed accept, so running out of file handles doesn't spin
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+use self::admission::AdmissionLimiter;
+pub use self::admission::{Invite, RoomKey};
+use self::backoff::Backoff;
+pub use self::identity::{parse_identity_key, Identity, IDENTITY_KEY_SIZE};
+pub use self::jitter_buffer::{JitterBuffer, JitterOutput};
 use self::peer::Peer;
+use self::peer_registry::{ConnectedPeers, PeerRegistry};
+pub use self::text_chat::{
+    ChatMessage, ChatMessageKind, ChatQuery, ChatText, ChatUpdate, TextChatController,
+};
+pub use self::transport::TransportError;
+use self::trust_store::Trust;
+pub use self::trust_store::TrustStore;
+
+/// Largest encoded voice frame that can be sent: the most a single Opus
+/// frame ever takes.
+pub const MAX_VOICE_FRAME_SIZE: usize = 1275;
+/// Longest nickname anyone can go by, in characters
+pub const MAX_NICKNAME_LENGTH: usize = 32;
+
+/// Whether `nickname` will do, and if not, why. Nicknames end up in the
+/// chat, the logs and the trust store's file, so nothing that could break
+/// a line gets through.
+pub fn check_nickname(nickname: &str) -> Result<(), String> {
+    if nickname.trim().is_empty() {
+        Err("a nickname can't be blank".to_string())
+    } else if nickname.chars().count() > MAX_NICKNAME_LENGTH {
+        Err(format!(
+            "a nickname can't be longer than {} characters",
+            MAX_NICKNAME_LENGTH
+        ))
+    } else if nickname.chars().any(char::is_control) {
+        Err("a nickname can't have line breaks or other control characters".to_string())
+    } else {
+        Ok(())
+    }
+}
+
+/// One encoded frame of someone's voice. Sequence numbers count up by one
+/// per frame so receivers can put packets back in order and spot gaps.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct VoicePacket {
+    pub sequence: u32,
+    pub data: Vec<u8>,
+    /// A copy of the previous frame (`sequence - 1`), sent when the
+    /// speaker expects enough loss to make it worth the bandwidth
+    pub redundant: Option<Vec<u8>>,
+}
+
+/// A peer as seen by someone already connected to it, with the address its
+/// TCP listener can be reached at.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct KnownPeer {
+    pub id: Uuid,
+    pub nickname: String,
+    pub address: SocketAddr,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ConnectionState {
+    Connected,
+    /// Dropped out without saying goodbye; we're trying to get them back
+    Reconnecting,
+}
+
+/// Someone in the room and how we're getting on with them, for showing to
+/// the user.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PeerStatus {
+    pub peer: KnownPeer,
+    pub state: ConnectionState,
+    /// Whether they offer to pass on chat and voice for peers we can't reach
+    pub relay: bool,
+    /// Whether we believe them about who the chat and voice they pass on is
+    /// from; see `NetworkController::trust_relay`
+    pub trusted_relay: bool,
+    pub identity_key: [u8; IDENTITY_KEY_SIZE],
+    /// Short form of their identity key
+    pub fingerprint: String,
+}
 
 #[derive(Clone, Debug)]
 pub enum Message {
-    _Connect(Uuid),
-    Disconnect(Uuid),
-    TextChat(Uuid, String),
-    _VoiceChat(Uuid, Vec<u8>),
+    /// Someone joined the room, with their nickname
+    Connect(Uuid, String),
+    /// Someone left the room, with their nickname
+    Disconnect(Uuid, String),
+    /// Someone joined under a nickname we've seen before, but with a
+    /// different identity key than last time
+    IdentityChanged(Uuid, String),
+    /// Someone said something
+    TextChat(Uuid, ChatText),
+    /// Someone now goes by a new nickname
+    NicknameChanged(Uuid, String),
+    VoiceChat(Uuid, VoicePacket),
+    /// Peers that someone in the room is connected to. Broadcast when our
+    /// own peer list changes, and sent to the server when a remote tells us
+    /// about its peers, so that everyone ends up connected to everyone.
+    KnownPeers(Vec<KnownPeer>),
+    /// We've moved to another room by changing the room key (None being
+    /// the open room). Never leaves this machine.
+    RoomChanged(Option<RoomKey>),
 }
 
 #[derive(Debug)]
@@ -25,11 +153,34 @@ struct NetworkControllerPrivate {
     address: SocketAddr,
     local_id: Uuid,
     local_nick: String,
+    identity: Identity,
+    // Identity keys we've seen before, to spot impostors
+    trust: TrustStore,
+    // Who gets into the room; None lets anyone in
+    room_key: Option<RoomKey>,
+    admission: AdmissionLimiter,
     // Broadcase for sending messages OUT from the network state
     broadcast_tx: broadcast::Sender<Message>,
     // MPSC for sending messages INTO the network state
     mpsc_tx: mpsc::Sender<Message>,
-    peers: Vec<Peer>,
+    peers: PeerRegistry,
+    // Peers we are connecting to but haven't finished the handshake with
+    dialing: HashSet<Uuid>,
+    // Peers that dropped out unexpectedly, which we're trying to get back.
+    // As far as the rest of the app knows they never left.
+    reconnecting: HashMap<Uuid, PeerStatus>,
+    // The last nickname we heard for everyone in the room, including those
+    // we only hear from through a relay
+    nicknames: HashMap<Uuid, String>,
+    // Whether we pass other peers' chat and voice on to those who can't
+    // reach them directly
+    relay: bool,
+    // Identity keys of the relays we believe about who the messages they
+    // pass on are from
+    trusted_relays: HashSet<[u8; IDENTITY_KEY_SIZE]>,
+    // Sequence number for the next frame of our own voice
+    voice_sequence: u32,
+    peer_timeout: Duration,
 }
 
 #[derive(Clone, Debug)]
@@ -38,17 +189,51 @@ pub struct NetworkController {
 }
 
 impl NetworkController {
-    pub fn new_with_port_and_username(port_num: u16, username: String) -> Self {
-        let (btx, _brx) = broadcast::channel::<Message>(16);
+    /// Starts listening for peers. Our peer id comes from `identity`, the
+    /// keys peers present are checked against `trust`, only the relays in
+    /// `trusted_relays` may speak for anyone else, and peers quiet for
+    /// longer than `peer_timeout` are dropped. Fails if nothing can listen
+    /// on `address`.
+    pub fn new(
+        address: SocketAddr,
+        username: String,
+        identity: Identity,
+        trust: TrustStore,
+        trusted_relays: HashSet<[u8; IDENTITY_KEY_SIZE]>,
+        peer_timeout: Duration,
+    ) -> Result<Self, TransportError> {
+        // Bound up front, so the caller finds out if the port is taken and
+        // our real address is known from the start
+        let listener = std::net::TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        let listener = TcpListener::from_std(listener)?;
+        info!("Listening for peers on {}", address);
+
+        // Voice packets arrive ~50 times a second per speaker, so leave
+        // plenty of room before slow receivers start lagging
+        let (btx, _brx) = broadcast::channel::<Message>(256);
         let (mtx, mrx) = mpsc::channel::<Message>(100);
+        let local_id = identity.peer_id();
         let state = NetworkController {
             inner: Arc::new(RwLock::new(NetworkControllerPrivate {
-                address: SocketAddr::from(([0, 0, 0, 0], port_num)),
-                local_id: Uuid::new_v4(),
+                address,
+                local_id,
                 local_nick: username,
+                identity,
+                trust,
+                room_key: None,
+                admission: AdmissionLimiter::new(),
                 broadcast_tx: btx,
                 mpsc_tx: mtx,
-                peers: vec![],
+                peers: PeerRegistry::new(local_id),
+                dialing: HashSet::new(),
+                reconnecting: HashMap::new(),
+                nicknames: HashMap::new(),
+                relay: false,
+                trusted_relays,
+                voice_sequence: 0,
+                peer_timeout: peer_timeout.max(MIN_PEER_TIMEOUT),
             })),
         };
 
@@ -56,46 +241,252 @@ impl NetworkController {
         state.start_mpsc(mrx);
 
         // Initiate async loop to accept remote connections
-        state.start_tcp_server();
+        state.start_tcp_server(listener);
 
-        state
+        Ok(state)
     }
 
     async fn add_peer(&mut self, peer: Peer) {
-        self.inner.write().await.peers.push(peer)
+        let inner = &mut *self.inner.write().await;
+        let joined = peer.known_peer();
+        let identity_key = peer.identity_key();
+        if !inner.peers.add(peer) {
+            return;
+        }
+
+        inner.nicknames.insert(joined.id, joined.nickname.clone());
+        let mut events = vec![];
+        if inner.reconnecting.remove(&joined.id).is_some() {
+            info!("{} ({}) is back", joined.nickname, joined.id);
+        } else {
+            info!("{} ({}) joined", joined.nickname, joined.id);
+            events.push(Message::Connect(joined.id, joined.nickname.clone()));
+            match inner.trust.check(&joined.nickname, &identity_key) {
+                Trust::Known => {}
+                Trust::FirstSeen => info!(
+                    "First time seeing {}, remembering key {}",
+                    joined.nickname,
+                    identity::fingerprint(&identity_key)
+                ),
+                Trust::Changed => {
+                    warn!(
+                        "{} has a different key than before ({}); they may not be who they say",
+                        joined.nickname,
+                        identity::fingerprint(&identity_key)
+                    );
+                    events.push(Message::IdentityChanged(joined.id, joined.nickname));
+                }
+            }
+        }
+        // Let everyone know who we're connected to now
+        events.push(Message::KnownPeers(inner.peers.snapshot()));
+        for msg in events {
+            if inner.broadcast_tx.send(msg).is_err() {
+                debug!("Nobody to tell about the new peer");
+            }
+        }
     }
 
-    async fn handle_message(&mut self, msg: Message) {
-        println!("Handling message: {:?}", msg);
-        println!(
-            "Number of receivers: {}",
-            self.inner.read().await.broadcast_tx.receiver_count()
+    // Drops connections that have closed and deals with anyone who is now
+    // gone: peers that said goodbye are announced as having left, the rest
+    // we try to get back. A duplicate connection closing goes unnoticed.
+    async fn remove_closed_peers(&mut self) {
+        let inner = &mut *self.inner.write().await;
+        let departed = inner.peers.remove_closed();
+        if !departed.is_empty() {
+            // Keeps any relays up to date on who we can reach ourselves
+            let known = Message::KnownPeers(inner.peers.snapshot());
+            if inner.broadcast_tx.send(known).is_err() {
+                debug!("Nobody to tell about known peers");
+            }
+        }
+        for peer in departed {
+            let left = peer.known_peer();
+            if peer.ended_cleanly() {
+                info!("{} ({}) left", left.nickname, left.id);
+                if inner
+                    .broadcast_tx
+                    .send(Message::Disconnect(left.id, left.nickname))
+                    .is_err()
+                {
+                    debug!("Nobody to tell about the departed peer");
+                }
+            } else if let Entry::Vacant(slot) = inner.reconnecting.entry(left.id) {
+                info!("Lost {} ({}), reconnecting", left.nickname, left.id);
+                let mut status = peer.status();
+                status.state = ConnectionState::Reconnecting;
+                slot.insert(status);
+                self.start_reconnecting(left);
+            }
+        }
+    }
+
+    fn start_reconnecting(&self, peer: KnownPeer) {
+        let net = self.clone();
+        tokio::spawn(async move {
+            let mut backoff = Backoff::new(
+                RECONNECT_INITIAL_DELAY,
+                RECONNECT_MAX_DELAY,
+                RECONNECT_ATTEMPTS,
+            );
+            while let Some(delay) = backoff.next_delay() {
+                tokio::time::delay_for(delay).await;
+                // They may well have reconnected to us in the meantime
+                if !net.inner.read().await.reconnecting.contains_key(&peer.id) {
+                    return;
+                }
+                net.dial(peer.clone()).await;
+            }
+
+            let inner = &mut *net.inner.write().await;
+            if inner.reconnecting.remove(&peer.id).is_some() {
+                info!("Giving up on {} ({})", peer.nickname, peer.id);
+                if inner
+                    .broadcast_tx
+                    .send(Message::Disconnect(peer.id, peer.nickname))
+                    .is_err()
+                {
+                    debug!("Nobody to tell about the departed peer");
+                }
+            }
+        });
+    }
+
+    // Connects to any peers we've been told about that we aren't connected
+    // to (or connecting to) yet.
+    fn dial_known_peers(&self, known: Vec<KnownPeer>) {
+        for known_peer in known {
+            let net = self.clone();
+            tokio::spawn(async move { net.dial(known_peer).await });
+        }
+    }
+
+    // Connects to a peer we've heard about and waits for the handshake,
+    // unless we're already connected or connecting to it.
+    async fn dial(&self, known_peer: KnownPeer) {
+        {
+            let mut inner = self.inner.write().await;
+            if inner.peers.is_connected(known_peer.id)
+                || known_peer.id == inner.local_id
+                || !inner.dialing.insert(known_peer.id)
+            {
+                return;
+            }
+        }
+
+        debug!(
+            "Dialing {} ({}) at {}",
+            known_peer.nickname, known_peer.id, known_peer.address
         );
-        // TODO: do we need to do any processing of the message?
-        // match msg {
-        //     Message::_Connect(_) => {}
-        //     Message::Disconnect(_) => {}
-        //     Message::TextChat(sender, _) => {
-        //         if sender == self.get_local_id().await {
-        //             return;
-        //         }
-        //     }
-        //     Message::_VoiceChat(_, _) => {}
-        // }
+        let dialed = match TcpStream::connect(known_peer.address).await {
+            Ok(stream) => process_new_peer(self.clone(), stream, true).await,
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = dialed {
+            info!(
+                "Unable to connect to {} at {}: {}",
+                known_peer.nickname, known_peer.address, e
+            );
+        }
+        self.inner.write().await.dialing.remove(&known_peer.id);
+    }
+
+    /// Says goodbye to every peer, so that they know not to wait for us to
+    /// come back, and waits (briefly) for that to go out.
+    pub async fn leave(&self) {
+        let peers = self.inner.read().await.peers.connections();
+        for peer in peers.iter() {
+            peer.close();
+        }
+        let wait = async {
+            while peers.iter().any(|p| !p.is_closed()) {
+                tokio::time::delay_for(Duration::from_millis(10)).await;
+            }
+        };
+        let _ = tokio::time::timeout(LEAVE_TIMEOUT, wait).await;
+    }
+
+    async fn handle_message(&mut self, msg: Message) {
+        trace!("Handling message: {:?}", msg);
+        match &msg {
+            Message::KnownPeers(known) => {
+                // Only of interest to the network itself
+                {
+                    let nicknames = &mut self.inner.write().await.nicknames;
+                    let usable = known.iter().filter(|k| check_nickname(&k.nickname).is_ok());
+                    for k in usable {
+                        // Whoever told us may not have heard of a rename yet
+                        nicknames.entry(k.id).or_insert_with(|| k.nickname.clone());
+                    }
+                }
+                self.dial_known_peers(known.clone());
+                return;
+            }
+            Message::NicknameChanged(id, nickname) => {
+                // Nothing to tell anyone if we'd already heard, and a warning
+                // instead if it's a nickname someone else has gone by
+                if let Some(event) = self.rename(*id, nickname).await {
+                    self.broadcast(event).await;
+                }
+                return;
+            }
+            Message::Disconnect(_, _) => {
+                // Sent by a peer whose connection just closed; the registry
+                // decides whether that means they actually left
+                self.remove_closed_peers().await;
+                return;
+            }
+            _ => {}
+        }
 
         // Rebroadcast all messages (for now) to all listeners
-        if self
-            .inner
-            .read()
-            .await
-            .broadcast_tx
-            .send(msg.clone())
-            .is_err()
-        {
-            // TODO: report error to a proper logger
-            println!("Error broadcasting message from server");
+        self.broadcast(msg).await;
+    }
+
+    async fn broadcast(&self, msg: Message) {
+        if self.inner.read().await.broadcast_tx.send(msg).is_err() {
+            debug!("Nobody to pass the message on to");
+        }
+    }
+
+    // Records someone's new nickname, returning what to tell everyone: the
+    // rename itself, a warning if the nickname is known to belong to
+    // another key, or nothing if it isn't new.
+    async fn rename(&self, id: Uuid, nickname: &str) -> Option<Message> {
+        let inner = &mut *self.inner.write().await;
+        if id == inner.local_id {
+            // Already changed by set_local_nick
+            return Some(Message::NicknameChanged(id, nickname.to_string()));
+        }
+        if inner.nicknames.get(&id).map(String::as_str) == Some(nickname) {
+            return None;
+        }
+        // Held to the same standard as a nickname given on joining. Someone
+        // we only hear from through a relay has no key we could check, so
+        // they can only take nicknames nobody has been seen with.
+        let trusted = match inner.peers.identity_key(id) {
+            Some(key) => inner.trust.check(nickname, &key) != Trust::Changed,
+            None => !inner.trust.knows(nickname),
+        };
+        if !trusted {
+            warn!(
+                "{} tried to take the nickname {}, which has been seen with a different key",
+                id, nickname
+            );
+            return Some(Message::IdentityChanged(id, nickname.to_string()));
+        }
+        let old = inner.nicknames.insert(id, nickname.to_string());
+        inner.peers.rename(id, nickname);
+        if let Some(status) = inner.reconnecting.get_mut(&id) {
+            status.peer.nickname = nickname.to_string();
         }
-        println!("Message rebroadcast: {:?}", msg);
+        info!(
+            "{} ({}) is now known as {}",
+            old.unwrap_or_default(),
+            id,
+            nickname
+        );
+        Some(Message::NicknameChanged(id, nickname.to_string()))
     }
 
     fn start_mpsc(&self, mut mrx: mpsc::Receiver<Message>) {
@@ -109,65 +500,194 @@ impl NetworkController {
         });
     }
 
-    fn start_tcp_server(&self) {
+    fn start_tcp_server(&self, mut listener: TcpListener) {
         let state = self.clone();
         tokio::spawn(async move {
-            let address = state.get_address().await;
-            let mut listener = TcpListener::bind(address).await.unwrap();
-            match listener.local_addr() {
-                Ok(address) => {
-                    state.set_address(address).await;
-                    println!("TCP Listener bound: {:?}", address);
-                }
-                Err(e) => {
-                    println!("Error binding TCP listener: {}", e);
-                    return;
-                }
-            }
             loop {
-                let (stream, address) = listener.accept().await.unwrap();
+                let (stream, address) = match listener.accept().await {
+                    Ok(accepted) => accepted,
+                    Err(e) => {
+                        warn!("Error accepting a peer: {}", e);
+                        tokio::time::delay_for(ACCEPT_RETRY_DELAY).await;
+                        continue;
+                    }
+                };
                 let state = state.clone();
 
-                println!("Accepting incoming peer: {}", address);
-                process_new_peer(state, stream);
+                // Charged before the handshake starts, so that connections
+                // made in parallel can't all guess at once
+                if !state
+                    .inner
+                    .write()
+                    .await
+                    .admission
+                    .try_attempt(address.ip())
+                {
+                    info!("Turning away {}: too many attempts", address);
+                    continue;
+                }
+                debug!("Accepting incoming peer: {}", address);
+                tokio::spawn(async move {
+                    if let Err(e) = process_new_peer(state, stream, false).await {
+                        info!("Unable to accept peer from {}: {}", address, e);
+                    }
+                });
             }
         });
     }
 
-    pub fn connect_to(&self, address_string: String) {
-        println!("Connecting to: {}", address_string);
-        let state = self.clone();
-        tokio::spawn(async move {
-            if let Ok(address) = address_string.parse::<SocketAddr>() {
-                match TcpStream::connect(address).await {
-                    Ok(stream) => {
-                        process_new_peer(state.clone(), stream);
-                    }
-                    Err(e) => {
-                        // TODO: report an error to a proper logger
-                        println!("Server connection failed: {}, {}", address, e);
-                    }
-                }
-            } else {
-                println!("Unable to parse address");
-            }
-        });
+    /// Connects to the peer at `address` and waits for the handshake.
+    pub async fn connect_to(&self, address: String) -> Result<(), TransportError> {
+        let address = address
+            .trim()
+            .parse::<SocketAddr>()
+            .map_err(|_| TransportError::BadAddress(address))?;
+        debug!("Connecting to {}", address);
+        let stream = TcpStream::connect(address).await?;
+        process_new_peer(self.clone(), stream, true).await
     }
 
     pub async fn get_local_id(&self) -> Uuid {
         self.inner.read().await.local_id
     }
 
+    pub async fn get_identity(&self) -> Identity {
+        self.inner.read().await.identity.clone()
+    }
+
+    pub async fn is_relay(&self) -> bool {
+        self.inner.read().await.relay
+    }
+
+    /// Makes us a relay for peers that connect from now on: chat and voice
+    /// from each of them is passed on to any of the others that can't reach
+    /// them directly, behind a firewall say.
+    pub async fn set_relay(&self, relay: bool) {
+        self.inner.write().await.relay = relay;
+    }
+
+    /// Whether we believe the peer with `identity_key` about who the
+    /// messages it passes on are from, if it's a relay.
+    pub async fn trusts_relay(&self, identity_key: &[u8; IDENTITY_KEY_SIZE]) -> bool {
+        self.inner
+            .read()
+            .await
+            .trusted_relays
+            .contains(identity_key)
+    }
+
+    /// Decides whether to believe the relay with `identity_key` about who
+    /// the messages it passes on are from, starting with anything it sends
+    /// next. A relay only ever speaks for peers we aren't connected to.
+    pub async fn trust_relay(&self, identity_key: [u8; IDENTITY_KEY_SIZE], trusted: bool) {
+        let inner = &mut *self.inner.write().await;
+        if trusted {
+            inner.trusted_relays.insert(identity_key);
+        } else {
+            inner.trusted_relays.remove(&identity_key);
+        }
+        inner.peers.set_trusted_relay(&identity_key, trusted);
+    }
+
+    async fn get_connected_peers(&self) -> ConnectedPeers {
+        self.inner.read().await.peers.connected()
+    }
+
+    pub async fn get_room_key(&self) -> Option<RoomKey> {
+        self.inner.read().await.room_key
+    }
+
+    /// Sets who gets into the room from now on; None lets anyone in. Peers
+    /// already here stay connected either way.
+    pub async fn set_room_key(&self, room_key: Option<RoomKey>) {
+        let inner = &mut *self.inner.write().await;
+        if inner.room_key == room_key {
+            return;
+        }
+        inner.room_key = room_key;
+        if inner
+            .broadcast_tx
+            .send(Message::RoomChanged(room_key))
+            .is_err()
+        {
+            debug!("Nobody to tell about the new room");
+        }
+    }
+
+    /// An invite to the room, pointing at us.
+    pub async fn create_invite(&self) -> Invite {
+        let inner = self.inner.read().await;
+        let mut address = inner.address;
+        if address.ip().is_unspecified() {
+            address.set_ip(outward_ip());
+        }
+        Invite {
+            address,
+            room_key: inner.room_key,
+        }
+    }
+
+    /// Takes on the invite's room key and connects to the peer it points at.
+    pub async fn join(&self, invite: Invite) -> Result<(), TransportError> {
+        self.set_room_key(invite.room_key).await;
+        self.connect_to(invite.address.to_string()).await
+    }
+
     pub async fn get_local_nick(&self) -> String {
         self.inner.read().await.local_nick.clone()
     }
 
+    /// Changes our nickname and lets everyone in the room know.
+    pub async fn set_local_nick(&self, nickname: String) {
+        let (mut sender, msg) = {
+            let mut inner = self.inner.write().await;
+            if inner.local_nick == nickname {
+                return;
+            }
+            info!("Now known as {}", nickname);
+            inner.local_nick = nickname.clone();
+            (
+                inner.mpsc_tx.clone(),
+                Message::NicknameChanged(inner.local_id, nickname),
+            )
+        };
+        if let Err(e) = sender.send(msg).await {
+            warn!("Error announcing nickname change: {}", e);
+        }
+    }
+
+    /// What someone in the room (or we ourselves) goes by, if we know.
+    pub async fn get_nickname(&self, id: Uuid) -> Option<String> {
+        let inner = self.inner.read().await;
+        if id == inner.local_id {
+            Some(inner.local_nick.clone())
+        } else {
+            inner.nicknames.get(&id).cloned()
+        }
+    }
+
+    /// The peers we're connected to right now.
+    pub async fn get_peers(&self) -> Vec<KnownPeer> {
+        self.inner.read().await.peers.snapshot()
+    }
+
+    /// Everyone in the room as far as the user is concerned: the peers
+    /// we're connected to, and those we're trying to get back.
+    pub async fn get_peer_statuses(&self) -> Vec<PeerStatus> {
+        let inner = self.inner.read().await;
+        let mut statuses: Vec<PeerStatus> =
+            inner.peers.connections().iter().map(Peer::status).collect();
+        statuses.extend(inner.reconnecting.values().cloned());
+        statuses
+    }
+
+    pub async fn get_peer_timeout(&self) -> Duration {
+        self.inner.read().await.peer_timeout
+    }
+
     pub async fn get_address(&self) -> SocketAddr {
         self.inner.read().await.address
     }
-    async fn set_address(&self, address: SocketAddr) {
-        self.inner.write().await.address = address;
-    }
 
     pub async fn get_server_sender(&self) -> mpsc::Sender<Message> {
         self.inner.read().await.mpsc_tx.clone()
@@ -182,25 +702,90 @@ impl NetworkController {
         tokio::spawn(async move {
             let mut sender = net.get_server_sender().await;
             if let Err(e) = sender
-                .send(Message::TextChat(net.get_local_id().await, text.clone()))
+                .send(Message::TextChat(
+                    net.get_local_id().await,
+                    ChatText::new(text),
+                ))
                 .await
             {
-                println!("Error sending text message: {}", e);
+                warn!("Error sending text message: {}", e);
             }
-            println!("Sent message: {}", text);
         });
     }
-}
 
-fn process_new_peer(mut net: NetworkController, stream: TcpStream) {
-    tokio::spawn(async move {
-        let peer = match Peer::new(stream, net.clone()).await {
-            Ok(peer) => peer,
-            Err(_e) => {
-                // TODO: Log e somewhere
-                return;
-            }
+    /// Sends an already-encoded frame of our own voice out to all peers,
+    /// optionally along with a copy of the frame before it.
+    pub async fn send_voice_data(&self, data: Vec<u8>, redundant: Option<Vec<u8>>) {
+        let (mut sender, msg) = {
+            let mut inner = self.inner.write().await;
+            let sequence = inner.voice_sequence;
+            inner.voice_sequence = sequence.wrapping_add(1);
+            let packet = VoicePacket {
+                sequence,
+                data,
+                redundant,
+            };
+            (
+                inner.mpsc_tx.clone(),
+                Message::VoiceChat(inner.local_id, packet),
+            )
         };
-        net.add_peer(peer.clone()).await;
-    });
+        if let Err(e) = sender.send(msg).await {
+            warn!("Error sending voice data: {}", e);
+        }
+    }
+}
+
+async fn process_new_peer(
+    mut net: NetworkController,
+    stream: TcpStream,
+    outbound: bool,
+) -> Result<(), TransportError> {
+    let remote_ip = stream.peer_addr().ok().map(|address| address.ip());
+    // A peer that stalls mid-handshake would otherwise hold its attempt
+    // (and the connection) open forever
+    let peer = tokio::time::timeout(HANDSHAKE_TIMEOUT, Peer::new(stream, net.clone(), outbound))
+        .await
+        .map_err(|_| TransportError::TimedOut("handshake"))??;
+    // Anyone who dialed us was charged an attempt when we accepted
+    if let (Some(ip), false) = (remote_ip, outbound) {
+        net.inner.write().await.admission.record_success(ip);
+    }
+    net.add_peer(peer).await;
+    Ok(())
+}
+
+// The address other machines most likely reach us at, found by asking which
+// interface we'd route out of. Nothing is actually sent.
+fn outward_ip() -> IpAddr {
+    std::net::UdpSocket::bind("0.0.0.0:0")
+        .and_then(|socket| {
+            socket.connect("192.0.2.1:9")?;
+            socket.local_addr()
+        })
+        .map(|address| address.ip())
+        .unwrap_or_else(|_| IpAddr::from([127, 0, 0, 1]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn takes_ordinary_nicknames() {
+        assert!(check_nickname("alice").is_ok());
+        assert!(check_nickname("Zoë the Barista ☕").is_ok());
+        assert!(check_nickname(&"x".repeat(MAX_NICKNAME_LENGTH)).is_ok());
+    }
+
+    #[test]
+    fn turns_away_nicknames_that_could_break_a_line() {
+        assert!(check_nickname("").is_err());
+        assert!(check_nickname("   ").is_err());
+        assert!(check_nickname("alice\nbob").is_err());
+        assert!(check_nickname("alice\r").is_err());
+        assert!(check_nickname("tab\there").is_err());
+        assert!(check_nickname("\u{1b}[31mred").is_err());
+        assert!(check_nickname(&"x".repeat(MAX_NICKNAME_LENGTH + 1)).is_err());
+    }
 }
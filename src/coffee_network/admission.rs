@@ -0,0 +1,252 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+/// Size of a room key, and of the proofs made with it
+pub const ROOM_KEY_SIZE: usize = 32;
+
+const INVITE_PREFIX: &str = "coffee://";
+const PASSPHRASE_LABEL: &[u8] = b"coffeeshop room passphrase v1";
+const ROOM_ID_LABEL: &[u8] = b"coffeeshop room id v1";
+// Bytes of the room id, before it's written out in hex
+const ROOM_ID_SIZE: usize = 8;
+// Attempts an address gets, failed or still going, before it's locked out
+// for a while
+const MAX_ATTEMPTS: u32 = 5;
+const LOCKOUT: Duration = Duration::from_secs(60);
+
+/// The secret shared by everyone allowed in a room, made from a passphrase
+/// they've all agreed on. It never goes over the wire, except inside an
+/// invite; peers only ever send proofs made with it.
+#[derive(Clone, Copy, Eq, PartialEq)]
+pub struct RoomKey([u8; ROOM_KEY_SIZE]);
+
+impl fmt::Debug for RoomKey {
+    // Anyone who can read the logs shouldn't be able to join the room
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "RoomKey(..)")
+    }
+}
+
+impl RoomKey {
+    /// The key for a passphrase everyone has agreed on. Only as strong as
+    /// the passphrase: anyone can try guesses against a proof they've seen.
+    pub fn from_passphrase(passphrase: &str) -> Self {
+        let hkdf = Hkdf::<Sha256>::new(Some(PASSPHRASE_LABEL), passphrase.as_bytes());
+        let mut key = [0u8; ROOM_KEY_SIZE];
+        // 32 bytes is always a valid length for SHA-256 HKDF
+        let _ = hkdf.expand(b"room key", &mut key);
+        RoomKey(key)
+    }
+
+    fn mac(&self, challenge: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.0).expect("HMAC accepts any key length");
+        mac.update(challenge);
+        mac
+    }
+
+    /// Answers a challenge in a way only someone with the key could.
+    pub fn prove(&self, challenge: &[u8]) -> [u8; ROOM_KEY_SIZE] {
+        let mut proof = [0u8; ROOM_KEY_SIZE];
+        proof.copy_from_slice(&self.mac(challenge).finalize().into_bytes());
+        proof
+    }
+
+    /// A name for the room that's the same for everyone with the key, but
+    /// says nothing about the key itself, so it's fine to put in a file
+    /// name.
+    pub fn room_id(&self) -> String {
+        hex::encode(&self.mac(ROOM_ID_LABEL).finalize().into_bytes()[..ROOM_ID_SIZE])
+    }
+
+    /// Checks someone's answer to a challenge, in constant time.
+    pub fn verify(&self, challenge: &[u8], proof: &[u8; ROOM_KEY_SIZE]) -> bool {
+        self.mac(challenge).verify(proof).is_ok()
+    }
+}
+
+#[derive(Debug)]
+pub enum InviteError {
+    /// Doesn't start with `coffee://`
+    NotAnInvite,
+    BadAddress,
+    BadKey,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::NotAnInvite => write!(f, "not a coffeeshop invite"),
+            InviteError::BadAddress => write!(f, "invite has a bad address"),
+            InviteError::BadKey => write!(f, "invite has a bad room key"),
+        }
+    }
+}
+
+impl Error for InviteError {}
+
+/// Everything needed to join a room: where one of its peers is, and the
+/// room's key if it has one. Written as `coffee://<address>/<key in hex>`
+/// (or just `coffee://<address>` for an open room), so it can be pasted
+/// into a chat or an email.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Invite {
+    pub address: SocketAddr,
+    pub room_key: Option<RoomKey>,
+}
+
+impl fmt::Display for Invite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}{}", INVITE_PREFIX, self.address)?;
+        if let Some(room_key) = &self.room_key {
+            write!(f, "/{}", hex::encode(room_key.0))?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for Invite {
+    type Err = InviteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let rest = s
+            .trim()
+            .strip_prefix(INVITE_PREFIX)
+            .ok_or(InviteError::NotAnInvite)?;
+        let mut parts = rest.splitn(2, '/');
+        let address = parts
+            .next()
+            .and_then(|address| address.parse::<SocketAddr>().ok())
+            .ok_or(InviteError::BadAddress)?;
+        let room_key = match parts.next() {
+            Some(hex_key) => {
+                let mut key = [0u8; ROOM_KEY_SIZE];
+                hex::decode_to_slice(hex_key, &mut key).map_err(|_| InviteError::BadKey)?;
+                Some(RoomKey(key))
+            }
+            None => None,
+        };
+        Ok(Invite { address, room_key })
+    }
+}
+
+// Recent attempts from one address that haven't got in
+#[derive(Debug)]
+struct Attempts {
+    count: u32,
+    last: Instant,
+}
+
+/// Keeps anyone from guessing at the room key: an address that tries to
+/// get in too many times without succeeding is turned away without a
+/// handshake for a while.
+///
+/// Every attempt is charged up front, when the connection is accepted, and
+/// only paid back once it gets in. Waiting to count failures until their
+/// handshakes finish would let someone make as many guesses at once as
+/// they can open connections.
+#[derive(Debug, Default)]
+pub struct AdmissionLimiter {
+    attempts: HashMap<IpAddr, Attempts>,
+}
+
+impl AdmissionLimiter {
+    pub fn new() -> Self {
+        AdmissionLimiter {
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Charges `address` for an attempt at getting in, or returns false if
+    /// it's used them all up and should be turned away.
+    pub fn try_attempt(&mut self, address: IpAddr) -> bool {
+        // Everything older than a lockout is forgiven
+        self.attempts.retain(|_, a| a.last.elapsed() < LOCKOUT);
+        let attempts = self.attempts.entry(address).or_insert(Attempts {
+            count: 0,
+            last: Instant::now(),
+        });
+        if attempts.count >= MAX_ATTEMPTS {
+            return false;
+        }
+        attempts.count += 1;
+        attempts.last = Instant::now();
+        true
+    }
+
+    /// Someone from `address` got in, so they evidently have the key and
+    /// everything they've been charged is forgiven.
+    pub fn record_success(&mut self, address: IpAddr) {
+        self.attempts.remove(&address);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(last: u8) -> IpAddr {
+        IpAddr::from([192, 168, 1, last])
+    }
+
+    #[test]
+    fn attempts_still_going_count_against_an_address() {
+        let mut limiter = AdmissionLimiter::new();
+        // None of these has finished, let alone failed
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(limiter.try_attempt(ip(20)));
+        }
+        assert!(!limiter.try_attempt(ip(20)));
+        assert!(!limiter.try_attempt(ip(20)));
+        // Someone else on the network isn't held to it
+        assert!(limiter.try_attempt(ip(21)));
+    }
+
+    #[test]
+    fn getting_in_pays_back_every_attempt() {
+        let mut limiter = AdmissionLimiter::new();
+        for _ in 0..MAX_ATTEMPTS - 1 {
+            assert!(limiter.try_attempt(ip(20)));
+        }
+        limiter.record_success(ip(20));
+        for _ in 0..MAX_ATTEMPTS {
+            assert!(limiter.try_attempt(ip(20)));
+        }
+        assert!(!limiter.try_attempt(ip(20)));
+    }
+
+    #[test]
+    fn invites_read_back_as_written() {
+        let address: SocketAddr = "192.168.1.20:47001".parse().unwrap();
+        for room_key in [None, Some(RoomKey::from_passphrase("espresso"))].iter() {
+            let invite = Invite {
+                address,
+                room_key: *room_key,
+            };
+            assert_eq!(invite.to_string().parse::<Invite>().unwrap(), invite);
+        }
+    }
+
+    #[test]
+    fn says_whats_wrong_with_a_bad_invite() {
+        assert!(matches!(
+            "http://192.168.1.20:47001".parse::<Invite>(),
+            Err(InviteError::NotAnInvite)
+        ));
+        assert!(matches!(
+            "coffee://somewhere".parse::<Invite>(),
+            Err(InviteError::BadAddress)
+        ));
+        assert!(matches!(
+            "coffee://192.168.1.20:47001/not-hex".parse::<Invite>(),
+            Err(InviteError::BadKey)
+        ));
+    }
+}
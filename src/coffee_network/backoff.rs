@@ -0,0 +1,98 @@
+use std::time::Duration;
+
+use rand::Rng;
+
+// Each delay lands somewhere within this fraction either side of nominal, so
+// that everyone who lost the same link doesn't retry in lockstep
+const JITTER: f64 = 0.25;
+
+/// Exponential backoff with jitter: each delay is double the last, up to a
+/// limit, for a fixed number of attempts.
+#[derive(Debug)]
+pub struct Backoff {
+    next: Duration,
+    max: Duration,
+    attempts_left: u32,
+}
+
+impl Backoff {
+    pub fn new(initial: Duration, max: Duration, attempts: u32) -> Self {
+        Backoff {
+            next: initial,
+            max,
+            attempts_left: attempts,
+        }
+    }
+
+    /// How long to wait before the next attempt, or `None` once we're out
+    /// of attempts.
+    pub fn next_delay(&mut self) -> Option<Duration> {
+        if self.attempts_left == 0 {
+            return None;
+        }
+        self.attempts_left -= 1;
+
+        let nominal = self.next;
+        self.next = (self.next * 2).min(self.max);
+        let factor = rand::thread_rng().gen_range(1.0 - JITTER, 1.0 + JITTER);
+        Some(nominal.mul_f64(factor))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const INITIAL: Duration = Duration::from_secs(1);
+    const MAX: Duration = Duration::from_secs(30);
+
+    fn within_jitter(delay: Duration, nominal: Duration) -> bool {
+        delay >= nominal.mul_f64(1.0 - JITTER) && delay <= nominal.mul_f64(1.0 + JITTER)
+    }
+
+    #[test]
+    fn doubles_from_the_initial_delay_up_to_the_cap() {
+        let mut backoff = Backoff::new(INITIAL, MAX, 8);
+        let nominal = [1, 2, 4, 8, 16, 30, 30, 30];
+        for secs in nominal.iter() {
+            let delay = backoff.next_delay().unwrap();
+            let expected = Duration::from_secs(*secs);
+            assert!(
+                within_jitter(delay, expected),
+                "{:?} for {:?}",
+                delay,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn runs_out_after_the_last_attempt() {
+        let mut backoff = Backoff::new(INITIAL, MAX, 3);
+        for _ in 0..3 {
+            assert!(backoff.next_delay().is_some());
+        }
+        assert_eq!(backoff.next_delay(), None);
+        assert_eq!(backoff.next_delay(), None);
+    }
+
+    #[test]
+    fn jitter_stays_in_bounds_and_spreads_retries_out() {
+        let delays: Vec<Duration> = (0..200)
+            .map(|_| Backoff::new(INITIAL, MAX, 1).next_delay().unwrap())
+            .collect();
+        assert!(delays.iter().all(|d| within_jitter(*d, INITIAL)));
+        // Everyone waiting exactly as long would defeat the point
+        assert!(delays.iter().any(|d| *d != delays[0]));
+    }
+
+    #[test]
+    fn a_new_dropout_starts_from_the_initial_delay() {
+        // Reconnecting gets a fresh Backoff every time a peer drops out, so
+        // however far the last one got, the next starts short again
+        let mut first = Backoff::new(INITIAL, MAX, 10);
+        while first.next_delay().is_some() {}
+        let mut second = Backoff::new(INITIAL, MAX, 10);
+        assert!(within_jitter(second.next_delay().unwrap(), INITIAL));
+    }
+}
@@ -0,0 +1,289 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use log::{debug, warn};
+
+use crate::coffee_network::admission::RoomKey;
+use crate::coffee_network::framing::{encode_frame, FrameDecoder, FrameError};
+use crate::coffee_network::text_chat::ChatMessage;
+
+const HISTORY_EXTENSION: &str = "chat";
+// The file name for the room anyone can get into
+const OPEN_ROOM_ID: &str = "open";
+
+/// A room's chat as it happened, kept on disk so it's still there after a
+/// restart. Each room gets its own file, named after its `RoomKey::room_id`,
+/// and messages are added to the end of it: one length-prefixed bincode
+/// frame each, just as they'd go over the wire. Only the latest messages
+/// are worth keeping, so once the file holds twice as many as that it's
+/// rewritten with just those, which keeps it quick to load.
+#[derive(Debug)]
+pub struct ChatHistory {
+    // Where the room's chat is kept; None keeps nothing
+    path: Option<PathBuf>,
+    // How many of the latest messages to keep
+    keep: usize,
+    // How many are in the file, as far as we know
+    records: usize,
+}
+
+impl ChatHistory {
+    pub fn in_memory() -> Self {
+        ChatHistory {
+            path: None,
+            keep: 0,
+            records: 0,
+        }
+    }
+
+    /// The history of the room with `room_key` (or the open room), kept in
+    /// `dir`, holding on to at least the latest `keep` messages. Nothing is
+    /// written until there's something to record.
+    pub fn open(dir: &Path, room_key: Option<&RoomKey>, keep: usize) -> Self {
+        let room_id = room_key.map_or_else(|| OPEN_ROOM_ID.to_string(), RoomKey::room_id);
+        ChatHistory {
+            path: Some(dir.join(room_id).with_extension(HISTORY_EXTENSION)),
+            keep: keep.max(1),
+            records: 0,
+        }
+    }
+
+    /// Everything on file, oldest first, after cutting it back to the
+    /// latest `keep` messages if it's grown too long.
+    pub fn load(&mut self) -> io::Result<Vec<ChatMessage>> {
+        let mut messages = self.read()?;
+        self.records = messages.len();
+        if self.is_overgrown() {
+            messages.drain(..messages.len() - self.keep);
+            self.rewrite(&messages)?;
+        }
+        Ok(messages)
+    }
+
+    /// Adds a message to the end of the history.
+    pub fn append(&mut self, message: &ChatMessage) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let frame =
+            encode_frame(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        // All in one write, so a crash can only ever cut off the last one
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)?
+            .write_all(&frame)?;
+        self.records += 1;
+        if self.is_overgrown() {
+            self.load()?;
+        }
+        Ok(())
+    }
+
+    fn is_overgrown(&self) -> bool {
+        self.records > self.keep * 2
+    }
+
+    // Replaces the file with just `messages`. Written out in full before it
+    // takes the old one's place, so a crash can't lose the lot.
+    fn rewrite(&mut self, messages: &[ChatMessage]) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut contents = vec![];
+        for message in messages {
+            let frame =
+                encode_frame(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            contents.extend_from_slice(&frame);
+        }
+        let temp_path = path.with_extension(format!("{}.new", HISTORY_EXTENSION));
+        fs::write(&temp_path, contents)?;
+        fs::rename(&temp_path, path)?;
+        debug!(
+            "Cut {} back to the latest {} messages",
+            path.display(),
+            messages.len()
+        );
+        self.records = messages.len();
+        Ok(())
+    }
+
+    // Everything recorded so far, oldest first. A record that's been cut
+    // short, by a crash part way through writing it say, is cut off the
+    // file so what's added next isn't lost behind it; one that's merely
+    // unreadable is skipped.
+    fn read(&self) -> io::Result<Vec<ChatMessage>> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(vec![]),
+        };
+        let bytes = match fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        let mut messages = vec![];
+        loop {
+            match decoder.next_frame::<ChatMessage>() {
+                Ok(Some(message)) => messages.push(message),
+                Ok(None) => break,
+                Err(FrameError::Bincode(e)) => {
+                    warn!("Skipping bad message in {}: {}", path.display(), e)
+                }
+                Err(e) => {
+                    warn!("Unreadable record in {}: {}", path.display(), e);
+                    break;
+                }
+            }
+        }
+        if decoder.buffered_len() > 0 {
+            let good_len = bytes.len() - decoder.buffered_len();
+            warn!(
+                "Cutting {} unreadable bytes off the end of {}",
+                decoder.buffered_len(),
+                path.display()
+            );
+            OpenOptions::new()
+                .write(true)
+                .open(path)?
+                .set_len(good_len as u64)?;
+        }
+        Ok(messages)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coffee_network::ChatMessageKind;
+    use std::time::SystemTime;
+    use uuid::Uuid;
+
+    // A history in a directory of its own, gone once the test is done with it
+    struct TempHistory {
+        dir: PathBuf,
+    }
+
+    impl TempHistory {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "coffeeshop-history-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TempHistory { dir }
+        }
+
+        fn open(&self, keep: usize) -> ChatHistory {
+            ChatHistory::open(&self.dir, None, keep)
+        }
+
+        fn file_len(&self) -> u64 {
+            let path = self
+                .dir
+                .join(OPEN_ROOM_ID)
+                .with_extension(HISTORY_EXTENSION);
+            fs::metadata(path).unwrap().len()
+        }
+    }
+
+    impl Drop for TempHistory {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    fn message(body: &str) -> ChatMessage {
+        ChatMessage {
+            id: Uuid::new_v4(),
+            sender: Uuid::new_v4(),
+            nickname: Some("alice".to_string()),
+            timestamp: SystemTime::now(),
+            received_at: SystemTime::now(),
+            body: body.to_string(),
+            kind: ChatMessageKind::Text,
+        }
+    }
+
+    fn bodies(messages: &[ChatMessage]) -> Vec<String> {
+        messages.iter().map(|m| m.body.clone()).collect()
+    }
+
+    #[test]
+    fn reads_back_what_was_recorded() {
+        let temp = TempHistory::new("round-trip");
+        let mut history = temp.open(10);
+        assert!(history.load().unwrap().is_empty());
+        let recorded = vec![message("one"), message("two")];
+        for m in recorded.iter() {
+            history.append(m).unwrap();
+        }
+        assert_eq!(temp.open(10).load().unwrap(), recorded);
+    }
+
+    #[test]
+    fn cuts_off_a_torn_record_and_carries_on() {
+        let temp = TempHistory::new("torn");
+        let mut history = temp.open(10);
+        history.append(&message("whole")).unwrap();
+        let whole_len = temp.file_len();
+        history.append(&message("torn")).unwrap();
+        let path = temp
+            .dir
+            .join(OPEN_ROOM_ID)
+            .with_extension(HISTORY_EXTENSION);
+        OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(temp.file_len() - 3)
+            .unwrap();
+
+        let mut history = temp.open(10);
+        assert_eq!(bodies(&history.load().unwrap()), vec!["whole"]);
+        assert_eq!(temp.file_len(), whole_len);
+        history.append(&message("after")).unwrap();
+        assert_eq!(
+            bodies(&temp.open(10).load().unwrap()),
+            vec!["whole", "after"]
+        );
+    }
+
+    #[test]
+    fn never_holds_more_than_twice_what_it_keeps() {
+        let temp = TempHistory::new("capped");
+        let mut history = temp.open(3);
+        for i in 0..7 {
+            history.append(&message(&i.to_string())).unwrap();
+        }
+        // The seventh pushed it over, leaving the latest three
+        assert_eq!(bodies(&temp.open(3).load().unwrap()), vec!["4", "5", "6"]);
+    }
+
+    #[test]
+    fn cuts_back_a_long_file_when_loading() {
+        let temp = TempHistory::new("long");
+        let mut history = temp.open(100);
+        for i in 0..10 {
+            history.append(&message(&i.to_string())).unwrap();
+        }
+        assert_eq!(bodies(&temp.open(2).load().unwrap()), vec!["8", "9"]);
+        assert_eq!(bodies(&temp.open(100).load().unwrap()), vec!["8", "9"]);
+    }
+
+    #[test]
+    fn keeps_nothing_in_memory() {
+        let mut history = ChatHistory::in_memory();
+        history.append(&message("gone")).unwrap();
+        assert!(history.load().unwrap().is_empty());
+    }
+}
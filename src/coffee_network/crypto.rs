@@ -0,0 +1,451 @@
+use std::error::Error;
+use std::fmt;
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{ChaCha20Poly1305, Key, Nonce};
+use hkdf::Hkdf;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use x25519_dalek::{EphemeralSecret, PublicKey};
+
+/// Size of an X25519 public key on the wire
+pub const PUBLIC_KEY_SIZE: usize = 32;
+/// Bytes the AEAD adds to every message
+pub const TAG_SIZE: usize = 16;
+/// Bytes in front of every encrypted datagram, carrying its sequence number
+pub const DATAGRAM_HEADER_SIZE: usize = 8;
+
+// Mixed into the transcript and key derivation so keys made for this
+// protocol can never be confused with keys made for anything else
+const PROTOCOL_LABEL: &[u8] = b"coffeeshop transport v1";
+// How far behind the newest datagram an older one may still arrive
+const REPLAY_WINDOW_SIZE: u64 = 64;
+
+#[derive(Debug)]
+pub enum CryptoError {
+    /// The remote's key share would give a predictable shared secret
+    WeakKey,
+    /// A message failed authentication: tampered with, replayed out of
+    /// order, or encrypted under some other key
+    Decrypt,
+    /// A datagram we've already accepted (or one too old to tell)
+    Replay,
+    /// Too short to even hold a header and tag
+    Truncated,
+    /// We've sent so many messages the nonces would wrap around
+    Exhausted,
+}
+
+impl fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoError::WeakKey => write!(f, "remote sent a weak key share"),
+            CryptoError::Decrypt => write!(f, "message failed authentication"),
+            CryptoError::Replay => write!(f, "replayed or stale datagram"),
+            CryptoError::Truncated => write!(f, "message is too short"),
+            CryptoError::Exhausted => write!(f, "session has run out of nonces"),
+        }
+    }
+}
+
+impl Error for CryptoError {}
+
+/// Our half of an ephemeral X25519 key exchange. A fresh one is made for
+/// every connection, so recorded traffic stays safe even if a machine is
+/// compromised later.
+pub struct KeyExchange {
+    secret: EphemeralSecret,
+    public: PublicKey,
+}
+
+impl KeyExchange {
+    pub fn new() -> Self {
+        let secret = EphemeralSecret::new(OsRng);
+        let public = PublicKey::from(&secret);
+        KeyExchange { secret, public }
+    }
+
+    pub fn public_key(&self) -> [u8; PUBLIC_KEY_SIZE] {
+        self.public.to_bytes()
+    }
+
+    /// Combines our secret with the remote's public key into session keys.
+    /// `prologue` is everything the two sides said before the key shares,
+    /// which ends up in the handshake hash along with them, so if anyone
+    /// changed it along the way the two sides end up with different keys.
+    /// `initiator` is true on the side that dialed; both sides need to
+    /// agree on which key is for which direction.
+    pub fn finish(
+        self,
+        remote_public: [u8; PUBLIC_KEY_SIZE],
+        prologue: &[u8],
+        initiator: bool,
+    ) -> Result<Session, CryptoError> {
+        let local_public = self.public_key();
+        let shared = self.secret.diffie_hellman(&PublicKey::from(remote_public));
+        if !shared.was_contributory() {
+            return Err(CryptoError::WeakKey);
+        }
+
+        let (initiator_public, responder_public) = if initiator {
+            (local_public, remote_public)
+        } else {
+            (remote_public, local_public)
+        };
+        let mut transcript = Sha256::new();
+        transcript.update(PROTOCOL_LABEL);
+        transcript.update((prologue.len() as u64).to_be_bytes());
+        transcript.update(prologue);
+        transcript.update(initiator_public);
+        transcript.update(responder_public);
+        let mut handshake_hash = [0u8; 32];
+        handshake_hash.copy_from_slice(&transcript.finalize());
+
+        let hkdf = Hkdf::<Sha256>::new(Some(&handshake_hash), shared.as_bytes());
+        let key = |label: &[u8]| {
+            let mut okm = [0u8; 32];
+            // 32 bytes is always a valid length for SHA-256 HKDF
+            let _ = hkdf.expand(label, &mut okm);
+            ChaCha20Poly1305::new(&Key::from(okm))
+        };
+        let (tcp_send, tcp_recv, udp_send, udp_recv) = if initiator {
+            (
+                key(b"tcp i2r"),
+                key(b"tcp r2i"),
+                key(b"udp i2r"),
+                key(b"udp r2i"),
+            )
+        } else {
+            (
+                key(b"tcp r2i"),
+                key(b"tcp i2r"),
+                key(b"udp r2i"),
+                key(b"udp i2r"),
+            )
+        };
+
+        Ok(Session {
+            handshake_hash,
+            tcp_send: SealingKey::new(tcp_send),
+            tcp_recv: SealingKey::new(tcp_recv),
+            udp_send: SealingKey::new(udp_send),
+            udp_recv,
+            udp_replay: ReplayWindow::new(),
+        })
+    }
+}
+
+impl Default for KeyExchange {
+    fn default() -> Self {
+        KeyExchange::new()
+    }
+}
+
+// A key along with the counter its nonces are made from. Each direction of
+// each channel has its own key, so counters never collide.
+struct SealingKey {
+    cipher: ChaCha20Poly1305,
+    counter: u64,
+}
+
+impl SealingKey {
+    fn new(cipher: ChaCha20Poly1305) -> Self {
+        SealingKey { cipher, counter: 0 }
+    }
+
+    fn next_counter(&mut self) -> Result<u64, CryptoError> {
+        let counter = self.counter;
+        self.counter = counter.checked_add(1).ok_or(CryptoError::Exhausted)?;
+        Ok(counter)
+    }
+}
+
+fn nonce_for(counter: u64) -> Nonce {
+    let mut nonce = [0u8; 12];
+    nonce[4..].copy_from_slice(&counter.to_be_bytes());
+    Nonce::from(nonce)
+}
+
+/// Tracks which recent datagram sequence numbers we've seen, so a captured
+/// datagram can't be played back at us, while still allowing the usual
+/// amount of UDP reordering.
+#[derive(Debug, Default)]
+struct ReplayWindow {
+    // Highest sequence number accepted so far, plus one (0 = none yet)
+    next: u64,
+    // Bit n set means `next - 1 - n` has been seen
+    seen: u64,
+}
+
+impl ReplayWindow {
+    fn new() -> Self {
+        ReplayWindow { next: 0, seen: 0 }
+    }
+
+    fn check(&self, sequence: u64) -> Result<(), CryptoError> {
+        if sequence >= self.next {
+            return Ok(());
+        }
+        let age = self.next - 1 - sequence;
+        if age >= REPLAY_WINDOW_SIZE || self.seen & (1 << age) != 0 {
+            return Err(CryptoError::Replay);
+        }
+        Ok(())
+    }
+
+    // Only call once the datagram has been authenticated, so forgeries
+    // can't push the window along
+    fn accept(&mut self, sequence: u64) {
+        if sequence >= self.next {
+            let shift = sequence + 1 - self.next;
+            self.seen = if shift >= REPLAY_WINDOW_SIZE {
+                0
+            } else {
+                self.seen << shift
+            };
+            self.seen |= 1;
+            self.next = sequence + 1;
+        } else {
+            self.seen |= 1 << (self.next - 1 - sequence);
+        }
+    }
+}
+
+/// Keys for one connection, as agreed by `KeyExchange`.
+///
+/// TCP frames are sealed with a counter that both sides keep in step, since
+/// the stream already delivers them in order, exactly once; anything
+/// replayed, dropped or reordered fails to decrypt. Datagrams carry their
+/// counter in the clear and are checked against a replay window instead.
+pub struct Session {
+    handshake_hash: [u8; 32],
+    tcp_send: SealingKey,
+    tcp_recv: SealingKey,
+    udp_send: SealingKey,
+    udp_recv: ChaCha20Poly1305,
+    udp_replay: ReplayWindow,
+}
+
+impl fmt::Debug for Session {
+    // Keys stay out of the logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Session")
+            .field("tcp_sent", &self.tcp_send.counter)
+            .field("tcp_received", &self.tcp_recv.counter)
+            .field("udp_sent", &self.udp_send.counter)
+            .finish()
+    }
+}
+
+impl Session {
+    /// Identifies this particular exchange; anything that signs it is bound
+    /// to this connection and no other.
+    pub fn handshake_hash(&self) -> [u8; 32] {
+        self.handshake_hash
+    }
+
+    /// Encrypts the body of the next TCP frame.
+    pub fn seal_frame(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let nonce = nonce_for(self.tcp_send.next_counter()?);
+        self.tcp_send
+            .cipher
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| CryptoError::Exhausted)
+    }
+
+    /// Decrypts the body of the next TCP frame. Any failure here means the
+    /// stream can't be trusted any more.
+    pub fn open_frame(&mut self, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if ciphertext.len() < TAG_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+        let nonce = nonce_for(self.tcp_recv.next_counter()?);
+        self.tcp_recv
+            .cipher
+            .decrypt(&nonce, ciphertext)
+            .map_err(|_| CryptoError::Decrypt)
+    }
+
+    /// Encrypts a datagram, prefixed with its sequence number.
+    pub fn seal_datagram(&mut self, plaintext: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        let sequence = self.udp_send.next_counter()?;
+        let sealed = self
+            .udp_send
+            .cipher
+            .encrypt(&nonce_for(sequence), plaintext)
+            .map_err(|_| CryptoError::Exhausted)?;
+        let mut datagram = Vec::with_capacity(DATAGRAM_HEADER_SIZE + sealed.len());
+        datagram.extend_from_slice(&sequence.to_be_bytes());
+        datagram.extend_from_slice(&sealed);
+        Ok(datagram)
+    }
+
+    /// Decrypts a datagram, rejecting any we've seen before.
+    pub fn open_datagram(&mut self, datagram: &[u8]) -> Result<Vec<u8>, CryptoError> {
+        if datagram.len() < DATAGRAM_HEADER_SIZE + TAG_SIZE {
+            return Err(CryptoError::Truncated);
+        }
+        let mut header = [0u8; DATAGRAM_HEADER_SIZE];
+        header.copy_from_slice(&datagram[..DATAGRAM_HEADER_SIZE]);
+        let sequence = u64::from_be_bytes(header);
+        self.udp_replay.check(sequence)?;
+
+        let plaintext = self
+            .udp_recv
+            .decrypt(&nonce_for(sequence), &datagram[DATAGRAM_HEADER_SIZE..])
+            .map_err(|_| CryptoError::Decrypt)?;
+        self.udp_replay.accept(sequence);
+        Ok(plaintext)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HELLOS: &[u8] = b"both hellos";
+
+    // Both ends of one connection: the dialer's session, then the listener's
+    fn session_pair(initiator_prologue: &[u8], responder_prologue: &[u8]) -> (Session, Session) {
+        let initiator = KeyExchange::new();
+        let responder = KeyExchange::new();
+        let initiator_public = initiator.public_key();
+        let responder_public = responder.public_key();
+        (
+            initiator
+                .finish(responder_public, initiator_prologue, true)
+                .unwrap(),
+            responder
+                .finish(initiator_public, responder_prologue, false)
+                .unwrap(),
+        )
+    }
+
+    #[test]
+    fn frames_round_trip_both_ways() {
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        assert_eq!(a.handshake_hash(), b.handshake_hash());
+        for i in 0..3u8 {
+            let sealed = a.seal_frame(&[i; 10]).unwrap();
+            assert_eq!(sealed.len(), 10 + TAG_SIZE);
+            assert_eq!(b.open_frame(&sealed).unwrap(), vec![i; 10]);
+            let sealed = b.seal_frame(b"reply").unwrap();
+            assert_eq!(a.open_frame(&sealed).unwrap(), b"reply");
+        }
+    }
+
+    #[test]
+    fn tampered_frame_is_rejected() {
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        let mut sealed = a.seal_frame(b"hello").unwrap();
+        sealed[0] ^= 1;
+        assert!(matches!(b.open_frame(&sealed), Err(CryptoError::Decrypt)));
+    }
+
+    #[test]
+    fn dropped_or_replayed_frame_is_rejected() {
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        let first = a.seal_frame(b"first").unwrap();
+        let second = a.seal_frame(b"second").unwrap();
+        assert!(matches!(b.open_frame(&second), Err(CryptoError::Decrypt)));
+
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        let first_again = a.seal_frame(b"first").unwrap();
+        b.open_frame(&first_again).unwrap();
+        assert!(matches!(
+            b.open_frame(&first_again),
+            Err(CryptoError::Decrypt)
+        ));
+        // And frames from another connection are no good either
+        assert!(matches!(b.open_frame(&first), Err(CryptoError::Decrypt)));
+    }
+
+    #[test]
+    fn short_messages_are_truncated() {
+        let (_, mut b) = session_pair(HELLOS, HELLOS);
+        assert!(matches!(
+            b.open_frame(&[0; TAG_SIZE - 1]),
+            Err(CryptoError::Truncated)
+        ));
+        assert!(matches!(
+            b.open_datagram(&[0; DATAGRAM_HEADER_SIZE + TAG_SIZE - 1]),
+            Err(CryptoError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn datagrams_round_trip_out_of_order() {
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        let datagrams: Vec<Vec<u8>> = (0..4u8).map(|i| a.seal_datagram(&[i]).unwrap()).collect();
+        for &i in &[1, 0, 3, 2] {
+            assert_eq!(b.open_datagram(&datagrams[i]).unwrap(), vec![i as u8]);
+        }
+    }
+
+    #[test]
+    fn replayed_datagram_is_rejected() {
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        let first = a.seal_datagram(b"first").unwrap();
+        let second = a.seal_datagram(b"second").unwrap();
+        b.open_datagram(&second).unwrap();
+        b.open_datagram(&first).unwrap();
+        assert!(matches!(b.open_datagram(&first), Err(CryptoError::Replay)));
+        assert!(matches!(b.open_datagram(&second), Err(CryptoError::Replay)));
+    }
+
+    #[test]
+    fn datagram_older_than_window_is_rejected() {
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        let datagrams: Vec<Vec<u8>> = (0..=REPLAY_WINDOW_SIZE)
+            .map(|_| a.seal_datagram(b"voice").unwrap())
+            .collect();
+        b.open_datagram(datagrams.last().unwrap()).unwrap();
+        // Just inside the window is still fine, just outside isn't
+        b.open_datagram(&datagrams[1]).unwrap();
+        assert!(matches!(
+            b.open_datagram(&datagrams[0]),
+            Err(CryptoError::Replay)
+        ));
+    }
+
+    #[test]
+    fn tampered_datagram_is_rejected_without_moving_window() {
+        let (mut a, mut b) = session_pair(HELLOS, HELLOS);
+        let first = a.seal_datagram(b"first").unwrap();
+
+        // A sequence number from far ahead, as if to push `first` out of
+        // the window
+        let mut forged = first.clone();
+        forged[..DATAGRAM_HEADER_SIZE].copy_from_slice(&1000u64.to_be_bytes());
+        assert!(matches!(
+            b.open_datagram(&forged),
+            Err(CryptoError::Decrypt)
+        ));
+
+        let mut tampered = first.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            b.open_datagram(&tampered),
+            Err(CryptoError::Decrypt)
+        ));
+
+        assert_eq!(b.open_datagram(&first).unwrap(), b"first");
+    }
+
+    #[test]
+    fn different_prologues_give_different_keys() {
+        let (mut a, mut b) = session_pair(HELLOS, b"other hellos");
+        assert_ne!(a.handshake_hash(), b.handshake_hash());
+        let sealed = a.seal_frame(b"hello").unwrap();
+        assert!(matches!(b.open_frame(&sealed), Err(CryptoError::Decrypt)));
+    }
+
+    #[test]
+    fn weak_key_share_is_rejected() {
+        let local = KeyExchange::new();
+        assert!(matches!(
+            local.finish([0; PUBLIC_KEY_SIZE], HELLOS, true),
+            Err(CryptoError::WeakKey)
+        ));
+    }
+}
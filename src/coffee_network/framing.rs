@@ -0,0 +1,199 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Size of the big-endian length prefix in front of every frame
+const LENGTH_PREFIX_SIZE: usize = 4;
+/// Largest frame body we'll send or accept. Anything bigger is either a bug
+/// or someone feeding us garbage.
+pub const MAX_FRAME_SIZE: usize = 64 * 1024;
+
+#[derive(Debug)]
+pub enum FrameError {
+    /// The frame body is bigger than `MAX_FRAME_SIZE`
+    Oversized(usize),
+    /// The frame body couldn't be (de)serialized
+    Bincode(bincode::Error),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Oversized(size) => write!(
+                f,
+                "frame of {} bytes exceeds the {} byte limit",
+                size, MAX_FRAME_SIZE
+            ),
+            FrameError::Bincode(e) => write!(f, "bad frame body: {}", e),
+        }
+    }
+}
+
+impl Error for FrameError {}
+
+impl From<bincode::Error> for FrameError {
+    fn from(e: bincode::Error) -> Self {
+        FrameError::Bincode(e)
+    }
+}
+
+/// Serializes `message` into a single length-prefixed frame, ready to be
+/// written to a stream in one go.
+pub fn encode_frame<T: Serialize>(message: &T) -> Result<Vec<u8>, FrameError> {
+    encode_frame_body(&bincode::serialize(message)?)
+}
+
+/// Wraps an already-serialized (or encrypted) body in a frame.
+pub fn encode_frame_body(body: &[u8]) -> Result<Vec<u8>, FrameError> {
+    if body.len() > MAX_FRAME_SIZE {
+        return Err(FrameError::Oversized(body.len()));
+    }
+    let mut frame = Vec::with_capacity(LENGTH_PREFIX_SIZE + body.len());
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(body);
+    Ok(frame)
+}
+
+/// Reassembles length-prefixed frames from a byte stream. Bytes can be fed
+/// in however the stream happens to deliver them: a frame split across many
+/// reads, or many frames in one read.
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    pub fn new() -> Self {
+        FrameDecoder { buffer: vec![] }
+    }
+
+    /// Adds bytes read from the stream.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Takes the next complete frame, if one has fully arrived. An oversized
+    /// frame is an error as soon as its length prefix is seen; the stream
+    /// can't be trusted after that and should be closed.
+    pub fn next_frame<T: DeserializeOwned>(&mut self) -> Result<Option<T>, FrameError> {
+        match self.next_frame_body()? {
+            Some(body) => Ok(Some(bincode::deserialize::<T>(&body)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Like `next_frame`, but hands back the raw body for the caller to
+    /// decrypt or deserialize itself.
+    pub fn next_frame_body(&mut self) -> Result<Option<Vec<u8>>, FrameError> {
+        if self.buffer.len() < LENGTH_PREFIX_SIZE {
+            return Ok(None);
+        }
+        let mut prefix = [0u8; LENGTH_PREFIX_SIZE];
+        prefix.copy_from_slice(&self.buffer[..LENGTH_PREFIX_SIZE]);
+        let len = usize::try_from(u32::from_be_bytes(prefix)).unwrap_or(usize::MAX);
+        if len > MAX_FRAME_SIZE {
+            return Err(FrameError::Oversized(len));
+        }
+        let end = LENGTH_PREFIX_SIZE + len;
+        if self.buffer.len() < end {
+            return Ok(None);
+        }
+
+        // Drop the frame either way, so one bad message doesn't wedge the stream
+        let body = self.buffer[LENGTH_PREFIX_SIZE..end].to_vec();
+        self.buffer.drain(..end);
+        Ok(Some(body))
+    }
+
+    /// Bytes received but not yet part of a complete frame.
+    pub fn buffered_len(&self) -> usize {
+        self.buffer.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(text: &str) -> Vec<u8> {
+        encode_frame(&text.to_string()).unwrap()
+    }
+
+    fn drain(decoder: &mut FrameDecoder) -> Vec<String> {
+        let mut frames = vec![];
+        while let Some(text) = decoder.next_frame::<String>().unwrap() {
+            frames.push(text);
+        }
+        frames
+    }
+
+    #[test]
+    fn waits_for_a_frame_split_across_reads() {
+        let bytes = frame("hello there");
+        let mut decoder = FrameDecoder::new();
+        // Byte by byte, including through the length prefix
+        for byte in &bytes[..bytes.len() - 1] {
+            decoder.push(&[*byte]);
+            assert!(decoder.next_frame::<String>().unwrap().is_none());
+        }
+        decoder.push(&bytes[bytes.len() - 1..]);
+        assert_eq!(drain(&mut decoder), vec!["hello there"]);
+        assert_eq!(decoder.buffered_len(), 0);
+    }
+
+    #[test]
+    fn splits_frames_that_arrive_together() {
+        let mut bytes = frame("one");
+        bytes.extend(frame(""));
+        bytes.extend(frame("three"));
+        // The start of a fourth, still on its way
+        let fourth = frame("four");
+        bytes.extend(&fourth[..6]);
+
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        assert_eq!(drain(&mut decoder), vec!["one", "", "three"]);
+        assert_eq!(decoder.buffered_len(), 6);
+        decoder.push(&fourth[6..]);
+        assert_eq!(drain(&mut decoder), vec!["four"]);
+    }
+
+    #[test]
+    fn rejects_an_oversized_length_before_the_body_arrives() {
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&(MAX_FRAME_SIZE as u32 + 1).to_be_bytes());
+        match decoder.next_frame::<String>() {
+            Err(FrameError::Oversized(size)) => assert_eq!(size, MAX_FRAME_SIZE + 1),
+            other => panic!("expected an oversized frame, got {:?}", other),
+        }
+        let body = vec![0u8; MAX_FRAME_SIZE + 1];
+        assert!(matches!(
+            encode_frame_body(&body),
+            Err(FrameError::Oversized(_))
+        ));
+    }
+
+    #[test]
+    fn accepts_a_frame_right_at_the_limit() {
+        let body = vec![7u8; MAX_FRAME_SIZE];
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&encode_frame_body(&body).unwrap());
+        assert_eq!(decoder.next_frame_body().unwrap(), Some(body));
+    }
+
+    #[test]
+    fn skips_past_a_frame_that_wont_deserialize() {
+        let mut bytes = encode_frame_body(&[0xff]).unwrap();
+        bytes.extend(frame("after"));
+        let mut decoder = FrameDecoder::new();
+        decoder.push(&bytes);
+        assert!(matches!(
+            decoder.next_frame::<String>(),
+            Err(FrameError::Bincode(_))
+        ));
+        assert_eq!(drain(&mut decoder), vec!["after"]);
+    }
+}
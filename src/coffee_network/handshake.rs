@@ -0,0 +1,549 @@
+use std::error::Error;
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpStream;
+use tokio::prelude::*;
+
+use crate::coffee_network::admission::{RoomKey, ROOM_KEY_SIZE};
+use crate::coffee_network::crypto::{CryptoError, KeyExchange, Session, PUBLIC_KEY_SIZE};
+use crate::coffee_network::framing::{encode_frame, encode_frame_body, FrameDecoder, FrameError};
+use crate::coffee_network::identity::{self, Identity, IdentityError, IDENTITY_KEY_SIZE};
+
+/// Every coffeeshop handshake starts with this, so we can tell right away
+/// when something else entirely has connected to us.
+pub const PROTOCOL_MAGIC: u32 = 0xC0FF_EE00;
+/// The protocol this build speaks
+pub const PROTOCOL_VERSION: u16 = 8;
+/// The oldest protocol this build can still talk to. Older versions sent
+/// everything in the clear (1), had no way to tell who was on the other
+/// end (2), let anyone into the room (3), knew nothing of relays (4), sent
+/// chat without saying when and couldn't change nicknames (5), gave no
+/// way to spot the same chat message arriving twice (6), or left the Hellos
+/// out of the handshake hash, so they could be changed unnoticed (7).
+pub const MIN_PROTOCOL_VERSION: u16 = 8;
+
+const READ_BUFFER_SIZE: usize = 1024;
+// Signed along with the handshake hash, so an identity signature can't be
+// lifted from (or into) anything else
+const IDENTITY_PROOF_LABEL: &[u8] = b"coffeeshop identity proof v1";
+const ADMISSION_PROOF_LABEL: &[u8] = b"coffeeshop admission proof v1";
+
+/// Optional features a peer may support. Both sides advertise theirs during
+/// the handshake, and only the ones they have in common are used.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    /// Voice frames encoded with Opus
+    pub const VOICE_OPUS: Capabilities = Capabilities(1);
+    /// Encrypted TCP frames and UDP datagrams
+    pub const ENCRYPTION: Capabilities = Capabilities(1 << 1);
+    /// Compressed TCP frames
+    pub const COMPRESSION: Capabilities = Capabilities(1 << 2);
+
+    /// Everything this build knows how to do.
+    pub fn supported() -> Self {
+        Capabilities::VOICE_OPUS.union(Capabilities::ENCRYPTION)
+    }
+
+    /// What we refuse to do without.
+    pub fn required() -> Self {
+        Capabilities::ENCRYPTION
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn union(self, other: Capabilities) -> Self {
+        Capabilities(self.0 | other.0)
+    }
+
+    pub fn intersection(self, other: Capabilities) -> Self {
+        Capabilities(self.0 & other.0)
+    }
+}
+
+impl fmt::Display for Capabilities {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = [
+            (Capabilities::VOICE_OPUS, "voice-opus"),
+            (Capabilities::ENCRYPTION, "encryption"),
+            (Capabilities::COMPRESSION, "compression"),
+        ];
+        let list: Vec<&str> = names
+            .iter()
+            .filter(|(c, _)| self.contains(*c))
+            .map(|(_, name)| *name)
+            .collect();
+        if list.is_empty() {
+            write!(f, "none")
+        } else {
+            write!(f, "{}", list.join(", "))
+        }
+    }
+}
+
+/// The first frame each side sends. The magic number and versions come
+/// first so they can be checked before anything else is trusted.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct Hello {
+    magic: u32,
+    version: u16,
+    min_version: u16,
+    capabilities: Capabilities,
+}
+
+impl Hello {
+    fn local(capabilities: Capabilities) -> Self {
+        Hello {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            min_version: MIN_PROTOCOL_VERSION,
+            capabilities,
+        }
+    }
+}
+
+/// Each side's verdict on the other's Hello, so that a rejected peer finds
+/// out why instead of just seeing the connection drop.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum HelloReply {
+    Accept,
+    Reject(String),
+}
+
+/// What both sides agreed on.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Agreement {
+    pub version: u16,
+    pub capabilities: Capabilities,
+}
+
+#[derive(Debug)]
+pub enum HandshakeError {
+    /// The other end isn't speaking our protocol at all
+    NotCoffeeshop,
+    /// We refused the remote
+    Incompatible(String),
+    /// The remote refused us
+    Rejected(String),
+    /// The connection closed partway through
+    Closed,
+    Frame(FrameError),
+    Crypto(CryptoError),
+    /// The remote couldn't prove who it is
+    Identity(IdentityError),
+    /// We refused to let the remote into the room
+    NotAdmitted(String),
+    Io(io::Error),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::NotCoffeeshop => write!(f, "remote is not a coffeeshop peer"),
+            HandshakeError::Incompatible(reason) => write!(f, "incompatible peer: {}", reason),
+            HandshakeError::Rejected(reason) => write!(f, "rejected by peer: {}", reason),
+            HandshakeError::Closed => write!(f, "connection closed during handshake"),
+            HandshakeError::Frame(e) => write!(f, "handshake frame error: {}", e),
+            HandshakeError::Crypto(e) => write!(f, "key exchange failed: {}", e),
+            HandshakeError::Identity(e) => write!(f, "identity check failed: {}", e),
+            HandshakeError::NotAdmitted(reason) => write!(f, "refused entry: {}", reason),
+            HandshakeError::Io(e) => write!(f, "handshake I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+impl From<FrameError> for HandshakeError {
+    fn from(e: FrameError) -> Self {
+        HandshakeError::Frame(e)
+    }
+}
+
+impl From<CryptoError> for HandshakeError {
+    fn from(e: CryptoError) -> Self {
+        HandshakeError::Crypto(e)
+    }
+}
+
+impl From<IdentityError> for HandshakeError {
+    fn from(e: IdentityError) -> Self {
+        HandshakeError::Identity(e)
+    }
+}
+
+impl From<io::Error> for HandshakeError {
+    fn from(e: io::Error) -> Self {
+        HandshakeError::Io(e)
+    }
+}
+
+// Checks the remote's Hello against ours. Both sides run this on the same
+// pair of Hellos, so they reach the same agreement without another round trip.
+fn negotiate(local: &Hello, remote: &Hello) -> Result<Agreement, String> {
+    if remote.version < local.min_version {
+        return Err(format!(
+            "protocol version {} is too old (need at least {})",
+            remote.version, local.min_version
+        ));
+    }
+    if local.version < remote.min_version {
+        return Err(format!(
+            "protocol version {} is too old for the remote (needs at least {})",
+            local.version, remote.min_version
+        ));
+    }
+    let capabilities = local.capabilities.intersection(remote.capabilities);
+    if !capabilities.contains(Capabilities::required()) {
+        return Err(format!(
+            "peer lacks required features (has: {}, need: {})",
+            remote.capabilities,
+            Capabilities::required()
+        ));
+    }
+    Ok(Agreement {
+        version: local.version.min(remote.version),
+        capabilities,
+    })
+}
+
+pub async fn write_frame<T: Serialize>(
+    stream: &mut TcpStream,
+    message: &T,
+) -> Result<(), HandshakeError> {
+    stream.write_all(&encode_frame(message)?).await?;
+    Ok(())
+}
+
+/// Reads the next frame from a stream that isn't being polled elsewhere yet.
+/// Bytes past the end of the frame stay in `decoder`.
+pub async fn read_frame<T: DeserializeOwned>(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+) -> Result<T, HandshakeError> {
+    let mut buf = [0u8; READ_BUFFER_SIZE];
+    loop {
+        if let Some(message) = decoder.next_frame::<T>()? {
+            return Ok(message);
+        }
+        let read_count = stream.read(&mut buf).await?;
+        if read_count == 0 {
+            return Err(HandshakeError::Closed);
+        }
+        decoder.push(&buf[..read_count]);
+    }
+}
+
+// Both Hellos, the dialer's first, so both sides put together the same
+// bytes. Each is serialized afresh rather than kept as it came in, which
+// comes to the same thing for anything we managed to parse.
+fn hello_transcript(
+    local: &Hello,
+    remote: &Hello,
+    initiator: bool,
+) -> Result<Vec<u8>, HandshakeError> {
+    let (first, second) = if initiator {
+        (local, remote)
+    } else {
+        (remote, local)
+    };
+    let mut transcript = bincode::serialize(first).map_err(FrameError::from)?;
+    transcript.extend(bincode::serialize(second).map_err(FrameError::from)?);
+    Ok(transcript)
+}
+
+/// Exchanges protocol versions and capabilities with the remote, rejecting
+/// it (and telling it why) if the two can't work together. Along with what
+/// was agreed, returns the Hellos themselves, for `exchange_keys` to fold
+/// into the handshake hash. `initiator` is true on the side that dialed.
+pub async fn negotiate_protocol(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    capabilities: Capabilities,
+    initiator: bool,
+) -> Result<(Agreement, Vec<u8>), HandshakeError> {
+    let local = Hello::local(capabilities);
+    write_frame(stream, &local).await?;
+
+    let remote = match read_frame::<Hello>(stream, decoder).await {
+        Ok(remote) if remote.magic == PROTOCOL_MAGIC => remote,
+        // Wrong magic, an unparseable Hello, or a length prefix that makes
+        // no sense all mean the same thing: not one of us
+        Ok(_) | Err(HandshakeError::Frame(_)) => return Err(HandshakeError::NotCoffeeshop),
+        Err(e) => return Err(e),
+    };
+
+    let verdict = negotiate(&local, &remote);
+    let reply = match &verdict {
+        Ok(_) => HelloReply::Accept,
+        Err(reason) => HelloReply::Reject(reason.clone()),
+    };
+    write_frame(stream, &reply).await?;
+    let agreement = verdict.map_err(HandshakeError::Incompatible)?;
+
+    match read_frame::<HelloReply>(stream, decoder).await? {
+        HelloReply::Accept => Ok((agreement, hello_transcript(&local, &remote, initiator)?)),
+        HelloReply::Reject(reason) => Err(HandshakeError::Rejected(reason)),
+    }
+}
+
+/// Each side's ephemeral public key, the only thing sent in the clear after
+/// the Hellos.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct KeyShare {
+    public_key: [u8; PUBLIC_KEY_SIZE],
+}
+
+/// Agrees on session keys with the remote. `hellos` is what
+/// `negotiate_protocol` returned, so the handshake hash (and every signature
+/// over it) covers the versions and capabilities each side claimed too.
+/// `initiator` is true on the side that dialed. Every frame after this one
+/// should be sealed with the returned session.
+pub async fn exchange_keys(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    hellos: &[u8],
+    initiator: bool,
+) -> Result<Session, HandshakeError> {
+    let local = KeyExchange::new();
+    write_frame(
+        stream,
+        &KeyShare {
+            public_key: local.public_key(),
+        },
+    )
+    .await?;
+    let remote = read_frame::<KeyShare>(stream, decoder).await?;
+    Ok(local.finish(remote.public_key, hellos, initiator)?)
+}
+
+/// Like `write_frame`, but encrypted.
+pub async fn write_sealed_frame<T: Serialize>(
+    stream: &mut TcpStream,
+    session: &mut Session,
+    message: &T,
+) -> Result<(), HandshakeError> {
+    let body = bincode::serialize(message).map_err(FrameError::from)?;
+    let sealed = session.seal_frame(&body)?;
+    stream.write_all(&encode_frame_body(&sealed)?).await?;
+    Ok(())
+}
+
+/// Like `read_frame`, but encrypted. The first sealed frame also confirms
+/// that both sides ended up with the same keys.
+pub async fn read_sealed_frame<T: DeserializeOwned>(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    session: &mut Session,
+) -> Result<T, HandshakeError> {
+    let mut buf = [0u8; READ_BUFFER_SIZE];
+    loop {
+        if let Some(body) = decoder.next_frame_body()? {
+            let plaintext = session.open_frame(&body)?;
+            return Ok(bincode::deserialize::<T>(&plaintext).map_err(FrameError::from)?);
+        }
+        let read_count = stream.read(&mut buf).await?;
+        if read_count == 0 {
+            return Err(HandshakeError::Closed);
+        }
+        decoder.push(&buf[..read_count]);
+    }
+}
+
+/// An identity key, along with its signature over this connection's
+/// handshake.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct IdentityProof {
+    public_key: [u8; IDENTITY_KEY_SIZE],
+    // Serde only does arrays up to 32 long
+    signature: Vec<u8>,
+}
+
+// What each side signs. Naming the side keeps a peer from just echoing our
+// own proof back at us.
+fn identity_proof_message(session: &Session, initiator: bool) -> Vec<u8> {
+    let role: &[u8] = if initiator {
+        b"initiator"
+    } else {
+        b"responder"
+    };
+    let mut message = IDENTITY_PROOF_LABEL.to_vec();
+    message.extend_from_slice(role);
+    message.extend_from_slice(&session.handshake_hash());
+    message
+}
+
+/// Proves to the remote that we hold our identity key, and checks that it
+/// holds its own. Both sides sign the handshake hash, which is unique to
+/// this connection, so a proof can't be replayed on another. The hash
+/// covers both Hellos and both key shares, so this is also what stops
+/// anyone in the middle from swapping in their own key share or quietly
+/// taking away a capability. Returns the remote's identity key.
+pub async fn exchange_identities(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    session: &mut Session,
+    identity: &Identity,
+    initiator: bool,
+) -> Result<[u8; IDENTITY_KEY_SIZE], HandshakeError> {
+    let local = IdentityProof {
+        public_key: identity.public_key(),
+        signature: identity.sign(&identity_proof_message(session, initiator)),
+    };
+    write_sealed_frame(stream, session, &local).await?;
+
+    let remote = read_sealed_frame::<IdentityProof>(stream, decoder, session).await?;
+    identity::verify(
+        &remote.public_key,
+        &identity_proof_message(session, !initiator),
+        &remote.signature,
+    )?;
+    Ok(remote.public_key)
+}
+
+/// Sent by the side that dialed: proof that it knows the room key, if it
+/// has one.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct AdmissionRequest {
+    proof: Option<[u8; ROOM_KEY_SIZE]>,
+}
+
+/// The answer, with the listener's own proof so the joiner knows it's in
+/// the room it meant to join.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+enum AdmissionReply {
+    Accept(Option<[u8; ROOM_KEY_SIZE]>),
+    Reject(String),
+}
+
+// The challenge each side answers. The handshake hash is fresh for every
+// connection, so old answers are useless, and naming the side keeps the
+// listener from just echoing the joiner's answer back.
+fn admission_challenge(session: &Session, initiator: bool) -> Vec<u8> {
+    let role: &[u8] = if initiator {
+        b"initiator"
+    } else {
+        b"responder"
+    };
+    let mut challenge = ADMISSION_PROOF_LABEL.to_vec();
+    challenge.extend_from_slice(role);
+    challenge.extend_from_slice(&session.handshake_hash());
+    challenge
+}
+
+// Whether an answer to the challenge shows the other side is in the same
+// room as us, which is only the case if we both have the same key or both
+// have none at all
+fn check_admission(
+    room_key: Option<&RoomKey>,
+    challenge: &[u8],
+    proof: Option<&[u8; ROOM_KEY_SIZE]>,
+) -> Result<(), String> {
+    match (room_key, proof) {
+        (None, None) => Ok(()),
+        (Some(key), Some(proof)) if key.verify(challenge, proof) => Ok(()),
+        (Some(_), Some(_)) => Err("wrong room key".to_string()),
+        (Some(_), None) => Err("this room needs a passphrase or an invite".to_string()),
+        (None, Some(_)) => Err("this room is open, but a room key was given".to_string()),
+    }
+}
+
+/// Makes sure both sides belong to the same room, without the room key
+/// ever being sent. The joiner answers first, so a listener never hands
+/// out proofs to someone who isn't already in the room.
+pub async fn admit(
+    stream: &mut TcpStream,
+    decoder: &mut FrameDecoder,
+    session: &mut Session,
+    room_key: Option<&RoomKey>,
+    initiator: bool,
+) -> Result<(), HandshakeError> {
+    let local_proof = room_key.map(|key| key.prove(&admission_challenge(session, initiator)));
+    let remote_challenge = admission_challenge(session, !initiator);
+
+    if initiator {
+        let request = AdmissionRequest { proof: local_proof };
+        write_sealed_frame(stream, session, &request).await?;
+        match read_sealed_frame::<AdmissionReply>(stream, decoder, session).await? {
+            AdmissionReply::Accept(proof) => {
+                check_admission(room_key, &remote_challenge, proof.as_ref())
+                    .map_err(HandshakeError::NotAdmitted)
+            }
+            AdmissionReply::Reject(reason) => Err(HandshakeError::Rejected(reason)),
+        }
+    } else {
+        let request = read_sealed_frame::<AdmissionRequest>(stream, decoder, session).await?;
+        let verdict = check_admission(room_key, &remote_challenge, request.proof.as_ref());
+        let reply = match &verdict {
+            Ok(()) => AdmissionReply::Accept(local_proof),
+            Err(reason) => AdmissionReply::Reject(reason.clone()),
+        };
+        write_sealed_frame(stream, session, &reply).await?;
+        verdict.map_err(HandshakeError::NotAdmitted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hello(version: u16, capabilities: Capabilities) -> Hello {
+        Hello {
+            version,
+            ..Hello::local(capabilities)
+        }
+    }
+
+    #[test]
+    fn both_sides_agree_on_the_transcript() {
+        let dialer = hello(PROTOCOL_VERSION, Capabilities::supported());
+        let listener = hello(PROTOCOL_VERSION, Capabilities::required());
+        assert_eq!(
+            hello_transcript(&dialer, &listener, true).unwrap(),
+            hello_transcript(&listener, &dialer, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn changed_capabilities_change_the_transcript() {
+        let dialer = hello(PROTOCOL_VERSION, Capabilities::supported());
+        let listener = hello(PROTOCOL_VERSION, Capabilities::supported());
+        // What the listener would see if someone stripped voice out of the
+        // dialer's Hello on the way
+        let stripped = hello(PROTOCOL_VERSION, Capabilities::required());
+        assert_ne!(
+            hello_transcript(&dialer, &listener, true).unwrap(),
+            hello_transcript(&listener, &stripped, false).unwrap()
+        );
+    }
+
+    #[test]
+    fn negotiate_keeps_common_capabilities() {
+        let local = hello(PROTOCOL_VERSION, Capabilities::supported());
+        let remote = hello(PROTOCOL_VERSION, Capabilities::required());
+        assert_eq!(
+            negotiate(&local, &remote),
+            Ok(Agreement {
+                version: PROTOCOL_VERSION,
+                capabilities: Capabilities::required(),
+            })
+        );
+    }
+
+    #[test]
+    fn negotiate_refuses_old_or_unencrypted_peers() {
+        let local = hello(PROTOCOL_VERSION, Capabilities::supported());
+        let old = Hello {
+            min_version: MIN_PROTOCOL_VERSION - 1,
+            ..hello(MIN_PROTOCOL_VERSION - 1, Capabilities::supported())
+        };
+        assert!(negotiate(&local, &old).is_err());
+        let plain = hello(PROTOCOL_VERSION, Capabilities::VOICE_OPUS);
+        assert!(negotiate(&local, &plain).is_err());
+    }
+}
@@ -0,0 +1,171 @@
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer};
+use log::info;
+use rand::rngs::OsRng;
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+/// Size of an identity public key on the wire
+pub const IDENTITY_KEY_SIZE: usize = 32;
+
+const IDENTITY_FILE: &str = "identity.key";
+// Mixed into the id so that it can't be mistaken for any other hash of the
+// same key
+const PEER_ID_LABEL: &[u8] = b"coffeeshop peer id v1";
+
+#[derive(Debug)]
+pub enum IdentityError {
+    /// The key file exists but doesn't hold a key
+    Corrupt,
+    /// A key or signature that doesn't check out
+    BadSignature,
+    Io(io::Error),
+}
+
+impl fmt::Display for IdentityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IdentityError::Corrupt => write!(f, "identity key file is corrupt"),
+            IdentityError::BadSignature => write!(f, "identity signature is invalid"),
+            IdentityError::Io(e) => write!(f, "identity key I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for IdentityError {}
+
+impl From<io::Error> for IdentityError {
+    fn from(e: io::Error) -> Self {
+        IdentityError::Io(e)
+    }
+}
+
+/// Who we are, across launches. The public half is what other peers know
+/// us by; our peer id is derived from it, so nobody can claim our id
+/// without also holding the secret half.
+#[derive(Clone)]
+pub struct Identity {
+    keypair: Arc<Keypair>,
+}
+
+impl fmt::Debug for Identity {
+    // The secret half stays out of the logs
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Identity")
+            .field("peer_id", &self.peer_id())
+            .finish()
+    }
+}
+
+impl Identity {
+    /// A brand new identity that only lasts as long as it's kept around.
+    pub fn generate() -> Self {
+        Identity {
+            keypair: Arc::new(Keypair::generate(&mut OsRng)),
+        }
+    }
+
+    /// Loads the identity kept in `dir`, making one (and the directory) if
+    /// there isn't one yet.
+    pub fn load_or_create(dir: &Path) -> Result<Self, IdentityError> {
+        let path = dir.join(IDENTITY_FILE);
+        match fs::read(&path) {
+            Ok(bytes) => {
+                let secret = SecretKey::from_bytes(&bytes).map_err(|_| IdentityError::Corrupt)?;
+                let public = PublicKey::from(&secret);
+                Ok(Identity {
+                    keypair: Arc::new(Keypair { secret, public }),
+                })
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                let identity = Identity::generate();
+                fs::create_dir_all(dir)?;
+                write_secret(&path, identity.keypair.secret.as_bytes())?;
+                info!("Created a new identity in {}", path.display());
+                Ok(identity)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn public_key(&self) -> [u8; IDENTITY_KEY_SIZE] {
+        self.keypair.public.to_bytes()
+    }
+
+    pub fn peer_id(&self) -> Uuid {
+        peer_id_for(&self.public_key())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        self.keypair.sign(message).to_bytes().to_vec()
+    }
+}
+
+/// The peer id that goes with an identity key.
+pub fn peer_id_for(public_key: &[u8; IDENTITY_KEY_SIZE]) -> Uuid {
+    let mut hash = Sha256::new();
+    hash.update(PEER_ID_LABEL);
+    hash.update(public_key);
+    let mut bytes = [0u8; 16];
+    bytes.copy_from_slice(&hash.finalize()[..16]);
+    Uuid::from_bytes(bytes)
+}
+
+/// Checks that `signature` is `public_key`'s signature over `message`.
+pub fn verify(
+    public_key: &[u8; IDENTITY_KEY_SIZE],
+    message: &[u8],
+    signature: &[u8],
+) -> Result<(), IdentityError> {
+    let public_key = PublicKey::from_bytes(public_key).map_err(|_| IdentityError::BadSignature)?;
+    let signature = Signature::try_from(signature).map_err(|_| IdentityError::BadSignature)?;
+    public_key
+        .verify_strict(message, &signature)
+        .map_err(|_| IdentityError::BadSignature)
+}
+
+/// Reads a whole identity key written out in hex, as the relay logs its
+/// own when it starts.
+pub fn parse_identity_key(text: &str) -> Result<[u8; IDENTITY_KEY_SIZE], String> {
+    let mut key = [0u8; IDENTITY_KEY_SIZE];
+    hex::decode_to_slice(text.trim(), &mut key)
+        .map_err(|_| format!("not a {}-byte key in hex", IDENTITY_KEY_SIZE))?;
+    Ok(key)
+}
+
+/// Prints a key the way people can compare it by eye.
+pub fn fingerprint(public_key: &[u8; IDENTITY_KEY_SIZE]) -> String {
+    public_key[..8]
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<Vec<String>>()
+        .join(":")
+}
+
+#[cfg(unix)]
+fn write_secret(path: &Path, secret: &[u8]) -> io::Result<()> {
+    use std::os::unix::fs::OpenOptionsExt;
+    // Nobody else on the machine gets to be us
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?;
+    file.write_all(secret)
+}
+
+#[cfg(not(unix))]
+fn write_secret(path: &Path, secret: &[u8]) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    file.write_all(secret)
+}
@@ -0,0 +1,461 @@
+use std::collections::BTreeMap;
+use std::time::Instant;
+
+// How quickly the jitter estimate follows new measurements (RFC 3550 uses 16)
+const JITTER_SMOOTHING: f64 = 16.0;
+// How many standard "jitters" of delay to hold back before playing
+const JITTER_MULTIPLIER: f64 = 3.0;
+const DEFAULT_MIN_DELAY_FRAMES: usize = 1;
+const DEFAULT_MAX_DELAY_FRAMES: usize = 10;
+// Buffered frames beyond the target before old ones get skipped
+const TRIM_SLACK_FRAMES: usize = 2;
+// A packet this many frames behind playback means the sender restarted, and
+// one this far ahead that they skipped a long stretch
+const RESET_WINDOW_FRAMES: u32 = 50;
+
+/// What the jitter buffer hands out on each tick of playback.
+#[derive(Debug, PartialEq)]
+pub enum JitterOutput {
+    /// The next frame, in order
+    Frame(Vec<u8>),
+    /// The next frame should have been here, but was lost or is late
+    Missing,
+    /// Still filling up; nothing should be played yet
+    Waiting,
+}
+
+/// Counters for how a stream has been behaving.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct JitterStats {
+    pub received: u64,
+    pub duplicates: u64,
+    /// Arrived after their slot was already played
+    pub late: u64,
+    /// Never showed up in time for their slot
+    pub missing: u64,
+    /// Skipped to bring latency back down
+    pub dropped: u64,
+    /// Lost, but rebuilt from a redundant copy in a later packet
+    pub recovered: u64,
+}
+
+// A frame waiting to be played
+struct Buffered {
+    data: Vec<u8>,
+    // Only a redundant copy, from a later packet
+    redundant: bool,
+}
+
+/// Smooths out the uneven, out-of-order arrival of voice packets from one
+/// remote peer. Packets are pushed in as they arrive (with their sequence
+/// number and arrival time) and popped once per frame by playback.
+///
+/// The buffer holds back enough frames to ride out the jitter it has
+/// measured so far, and trims itself back down when the network calms.
+/// Nothing here reads the clock, so a simulated arrival schedule behaves
+/// exactly the same every time.
+pub struct JitterBuffer {
+    frame_ms: f64,
+    min_delay_frames: usize,
+    max_delay_frames: usize,
+
+    packets: BTreeMap<u32, Buffered>,
+    next_sequence: Option<u32>,
+    playing: bool,
+    closed: bool,
+
+    // Sequence number and arrival time of the last packet measured
+    last_arrival: Option<(u32, Instant)>,
+    jitter_ms: f64,
+    stats: JitterStats,
+}
+
+impl JitterBuffer {
+    pub fn new(frame_ms: u32) -> Self {
+        JitterBuffer {
+            frame_ms: f64::from(frame_ms.max(1)),
+            min_delay_frames: DEFAULT_MIN_DELAY_FRAMES,
+            max_delay_frames: DEFAULT_MAX_DELAY_FRAMES,
+            packets: BTreeMap::new(),
+            next_sequence: None,
+            playing: false,
+            closed: false,
+            last_arrival: None,
+            jitter_ms: 0.0,
+            stats: JitterStats::default(),
+        }
+    }
+
+    /// Adds a packet that arrived at `arrival`. Returns false if it was
+    /// dropped as a duplicate or for arriving too late to be played.
+    pub fn push(&mut self, sequence: u32, data: Vec<u8>, arrival: Instant) -> bool {
+        if let Some(next) = self.next_sequence {
+            if sequence < next {
+                if sequence.saturating_add(RESET_WINDOW_FRAMES) < next {
+                    // Way behind what we're playing; the sender must have
+                    // started over, so we do too
+                    self.reset();
+                } else {
+                    self.stats.late += 1;
+                    return false;
+                }
+            }
+        }
+        // The original is as good as it gets, but a redundant copy that
+        // beat it here makes way
+        if let Some(buffered) = self.packets.get(&sequence) {
+            if !buffered.redundant {
+                self.stats.duplicates += 1;
+                return false;
+            }
+        }
+
+        self.measure_jitter(sequence, arrival);
+        self.packets.insert(
+            sequence,
+            Buffered {
+                data,
+                redundant: false,
+            },
+        );
+        self.stats.received += 1;
+        true
+    }
+
+    /// Offers a redundant copy of an earlier frame (carried inside a later
+    /// packet). It's only used if the original never arrived and its slot
+    /// hasn't been played yet.
+    pub fn recover(&mut self, sequence: u32, data: Vec<u8>) -> bool {
+        if let Some(next) = self.next_sequence {
+            if sequence < next {
+                return false;
+            }
+        }
+        if self.packets.contains_key(&sequence) {
+            return false;
+        }
+        let redundant = true;
+        self.packets.insert(sequence, Buffered { data, redundant });
+        true
+    }
+
+    /// The packet for the slot that will be played next, if it's here
+    /// already. After a `Missing` frame this is the packet whose forward
+    /// error correction data can rebuild the missing one.
+    pub fn peek_next(&self) -> Option<&Vec<u8>> {
+        self.packets
+            .get(&self.next_sequence?)
+            .map(|buffered| &buffered.data)
+    }
+
+    /// Takes the next frame for playback; call once per frame duration.
+    pub fn pop(&mut self) -> JitterOutput {
+        let target = self.target_delay_frames();
+        if !self.playing {
+            if self.packets.len() < target {
+                return JitterOutput::Waiting;
+            }
+            self.playing = true;
+            let first = self.packets.keys().next().copied();
+            self.next_sequence = match (self.next_sequence, first) {
+                (Some(next), Some(first)) => Some(next.max(first)),
+                (_, first) => first,
+            };
+        }
+
+        let mut next = match self.next_sequence {
+            Some(next) => next,
+            None => return JitterOutput::Waiting,
+        };
+
+        // Everything buffered is from far ahead: the sender skipped a long
+        // stretch, so catch up rather than play it all as missing
+        if let Some(&first) = self.packets.keys().next() {
+            if first.saturating_sub(next) > RESET_WINDOW_FRAMES {
+                next = first;
+            }
+        }
+
+        // Too much buffered: skip ahead to cut the latency back down. Any
+        // gap before the oldest frame goes with it.
+        while self.packets.len() > target + TRIM_SLACK_FRAMES {
+            if let Some(&oldest) = self.packets.keys().next() {
+                self.packets.remove(&oldest);
+                self.stats.dropped += 1;
+                next = oldest.wrapping_add(1);
+            }
+        }
+
+        let output = match self.packets.remove(&next) {
+            Some(buffered) => {
+                if buffered.redundant {
+                    self.stats.recovered += 1;
+                }
+                JitterOutput::Frame(buffered.data)
+            }
+            None => {
+                self.stats.missing += 1;
+                JitterOutput::Missing
+            }
+        };
+        self.next_sequence = Some(next.wrapping_add(1));
+
+        // Ran dry: wait to build the buffer back up before playing again
+        if self.packets.is_empty() {
+            self.playing = false;
+        }
+        output
+    }
+
+    /// Marks the stream as finished; no more packets are expected.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+
+    /// True once the stream is closed and everything has been played.
+    pub fn is_finished(&self) -> bool {
+        self.closed && self.packets.is_empty()
+    }
+
+    /// Current jitter estimate, in milliseconds.
+    pub fn jitter_ms(&self) -> f64 {
+        self.jitter_ms
+    }
+
+    /// How many frames the buffer currently aims to hold before playing.
+    pub fn target_delay_frames(&self) -> usize {
+        let frames = (self.jitter_ms * JITTER_MULTIPLIER / self.frame_ms).round() as usize + 1;
+        frames.clamp(self.min_delay_frames, self.max_delay_frames)
+    }
+
+    pub fn stats(&self) -> JitterStats {
+        self.stats
+    }
+
+    fn reset(&mut self) {
+        self.packets.clear();
+        self.next_sequence = None;
+        self.playing = false;
+        self.last_arrival = None;
+    }
+
+    // Interarrival jitter as in RFC 3550: how much the gap between packets
+    // varies compared to the gap between when they were sent. Only ever
+    // the gap since the last packet is measured, so precision doesn't
+    // wear away however long the stream runs. Across a long jump in
+    // sequence numbers there's nothing to compare, so it starts afresh.
+    fn measure_jitter(&mut self, sequence: u32, arrival: Instant) {
+        if let Some((last_sequence, last_arrival)) = self.last_arrival {
+            if last_sequence.max(sequence) - last_sequence.min(sequence) > RESET_WINDOW_FRAMES {
+                self.last_arrival = Some((sequence, arrival));
+                return;
+            }
+            let arrived_ms = arrival
+                .saturating_duration_since(last_arrival)
+                .as_secs_f64()
+                * 1000.0;
+            let sent_ms = (i64::from(sequence) - i64::from(last_sequence)) as f64 * self.frame_ms;
+            let d = (arrived_ms - sent_ms).abs();
+            self.jitter_ms += (d - self.jitter_ms) / JITTER_SMOOTHING;
+        }
+        self.last_arrival = Some((sequence, arrival));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const FRAME_MS: u32 = 20;
+
+    // Feeds in packets as (sequence, arrival in ms after `start`)
+    fn arrive(buffer: &mut JitterBuffer, start: Instant, schedule: &[(u32, u64)]) {
+        for &(sequence, at_ms) in schedule {
+            let arrival = start + Duration::from_millis(at_ms);
+            buffer.push(sequence, vec![sequence as u8], arrival);
+        }
+    }
+
+    // Plays `count` frames, giving the sequence number of each one played
+    // (None for anything else)
+    fn play(buffer: &mut JitterBuffer, count: usize) -> Vec<Option<u32>> {
+        (0..count)
+            .map(|_| match buffer.pop() {
+                JitterOutput::Frame(data) => Some(u32::from(data[0])),
+                _ => None,
+            })
+            .collect()
+    }
+
+    // Plays a frame every FRAME_MS for `ticks` frames, with each packet in
+    // `schedule` turning up as its time comes
+    fn simulate(
+        buffer: &mut JitterBuffer,
+        start: Instant,
+        schedule: &[(u32, u64)],
+        ticks: usize,
+    ) -> Vec<Option<u32>> {
+        let mut schedule = schedule.to_vec();
+        schedule.sort_by_key(|&(_, at_ms)| at_ms);
+        let mut pending = schedule.into_iter().peekable();
+        let mut played = vec![];
+        for tick in 0..ticks {
+            let now_ms = (tick as u32 * FRAME_MS) as u64;
+            while let Some(&(sequence, at_ms)) = pending.peek() {
+                if at_ms > now_ms {
+                    break;
+                }
+                arrive(buffer, start, &[(sequence, at_ms)]);
+                pending.next();
+            }
+            played.extend(play(buffer, 1));
+        }
+        played
+    }
+
+    // Packets `from..to` sent every frame, arriving `late_ms(sequence)` late
+    fn steady(from: u32, to: u32, late_ms: impl Fn(u32) -> u64) -> Vec<(u32, u64)> {
+        (from..to)
+            .map(|s| (s, u64::from(s * FRAME_MS) + late_ms(s)))
+            .collect()
+    }
+
+    #[test]
+    fn plays_a_steady_stream_in_order_with_minimal_delay() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        let played = simulate(&mut buffer, start, &steady(0, 50, |_| 5), 50);
+        // Each packet is played on the first tick after it arrives
+        assert_eq!(played[0], None);
+        assert_eq!(played[1..], (0..49).map(Some).collect::<Vec<_>>()[..]);
+        assert!(buffer.jitter_ms() < 0.01);
+        assert_eq!(buffer.target_delay_frames(), 1);
+    }
+
+    #[test]
+    fn puts_reordered_packets_back_in_order() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        let schedule = [(1, 25), (0, 30), (3, 62), (2, 64), (4, 85), (5, 105)];
+        let played = simulate(&mut buffer, start, &schedule, 7);
+        let played: Vec<u32> = played.into_iter().flatten().collect();
+        assert_eq!(played, vec![0, 1, 2, 3, 4]);
+        assert_eq!(buffer.stats().missing, 0);
+    }
+
+    #[test]
+    fn drops_late_and_duplicate_packets() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        arrive(&mut buffer, start, &[(0, 0), (1, 20), (2, 40)]);
+        assert_eq!(play(&mut buffer, 2), vec![Some(0), Some(1)]);
+        assert!(!buffer.push(1, vec![1], start + Duration::from_millis(50)));
+        assert!(!buffer.push(2, vec![2], start + Duration::from_millis(50)));
+        let stats = buffer.stats();
+        assert_eq!((stats.late, stats.duplicates), (1, 1));
+        assert_eq!(play(&mut buffer, 1), vec![Some(2)]);
+    }
+
+    #[test]
+    fn holds_back_more_when_arrivals_are_uneven_and_less_once_they_settle() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        // Every other packet held up by 40ms
+        arrive(
+            &mut buffer,
+            start,
+            &steady(0, 100, |s| u64::from(s % 2) * 40),
+        );
+        let jittery = buffer.target_delay_frames();
+        assert!(jittery >= 4, "target only {} frames", jittery);
+        assert!(jittery <= DEFAULT_MAX_DELAY_FRAMES);
+
+        arrive(&mut buffer, start, &steady(100, 300, |_| 0));
+        assert_eq!(buffer.target_delay_frames(), 1);
+    }
+
+    #[test]
+    fn skips_old_frames_when_too_much_builds_up() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        // A burst all at once, as after a stall
+        let burst: Vec<(u32, u64)> = (0..20).map(|s| (s, 0)).collect();
+        arrive(&mut buffer, start, &burst);
+        let target = buffer.target_delay_frames();
+        let played = play(&mut buffer, 1);
+        let skipped = 20 - (target + TRIM_SLACK_FRAMES) as u32;
+        assert_eq!(played, vec![Some(skipped)]);
+        assert_eq!(buffer.stats().dropped, u64::from(skipped));
+    }
+
+    #[test]
+    fn catches_up_at_once_after_a_long_jump_ahead() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        arrive(&mut buffer, start, &[(0, 0), (1, 20)]);
+        assert_eq!(play(&mut buffer, 2), vec![Some(0), Some(1)]);
+        // Far too many missing frames to step through one at a time
+        let far = 3_000_000_000;
+        for s in far..far + 3 {
+            buffer.push(s, vec![s as u8], start + Duration::from_millis(40));
+        }
+        let played = play(&mut buffer, 1);
+        assert_eq!(played, vec![Some(far as u8 as u32)]);
+    }
+
+    #[test]
+    fn stays_precise_hours_into_a_stream() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        // Ten hours of a perfectly steady stream shouldn't look jittery
+        let hours = 10 * 60 * 60 * 1000 / FRAME_MS;
+        for sequence in 0..hours {
+            arrive(
+                &mut buffer,
+                start,
+                &[(sequence, u64::from(sequence * FRAME_MS) + 3)],
+            );
+            buffer.pop();
+        }
+        assert!(buffer.jitter_ms() < 0.01, "jitter {}ms", buffer.jitter_ms());
+        assert_eq!(buffer.stats().missing, 0);
+    }
+
+    #[test]
+    fn starts_over_when_the_sender_restarts() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        arrive(&mut buffer, start, &steady(1000, 1010, |_| 0));
+        assert_eq!(play(&mut buffer, 9).len(), 9);
+        arrive(&mut buffer, start, &[(0, 20_400), (1, 20_420)]);
+        assert_eq!(play(&mut buffer, 2), vec![Some(0), Some(1)]);
+    }
+
+    #[test]
+    fn counts_a_redundant_copy_as_recovered_only_once_it_plays() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        arrive(&mut buffer, start, &[(0, 0)]);
+        assert_eq!(play(&mut buffer, 1), vec![Some(0)]);
+        // 1 is lost, but 2 carries a copy of it
+        assert!(buffer.recover(1, vec![1]));
+        arrive(&mut buffer, start, &[(2, 40)]);
+        assert_eq!(buffer.stats().recovered, 0);
+        assert_eq!(play(&mut buffer, 2), vec![Some(1), Some(2)]);
+        assert_eq!(buffer.stats().recovered, 1);
+    }
+
+    #[test]
+    fn prefers_the_original_to_a_redundant_copy_that_beat_it() {
+        let mut buffer = JitterBuffer::new(FRAME_MS);
+        let start = Instant::now();
+        arrive(&mut buffer, start, &[(0, 0)]);
+        assert_eq!(play(&mut buffer, 1), vec![Some(0)]);
+        assert!(buffer.recover(1, vec![101]));
+        arrive(&mut buffer, start, &[(2, 40), (1, 41)]);
+        assert_eq!(play(&mut buffer, 2), vec![Some(1), Some(2)]);
+        let stats = buffer.stats();
+        assert_eq!((stats.recovered, stats.duplicates), (0, 0));
+    }
+}
@@ -1,18 +1,54 @@
+use log::{debug, info, trace, warn};
 use serde::{Deserialize, Serialize};
-use std::error::Error;
-use std::sync::Arc;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use tokio::net::{TcpStream, UdpSocket};
 use tokio::prelude::*;
-use tokio::sync::{broadcast, mpsc, RwLock};
+use tokio::sync::{broadcast, mpsc, Notify, RwLock};
 use uuid::Uuid;
 
-use crate::coffee_network::{Message, NetworkController};
+use crate::coffee_network::crypto::{Session, DATAGRAM_HEADER_SIZE, TAG_SIZE};
+use crate::coffee_network::framing::{encode_frame_body, FrameDecoder, FrameError};
+use crate::coffee_network::handshake::{self, Agreement, Capabilities, HandshakeError};
+use crate::coffee_network::identity::{self, IDENTITY_KEY_SIZE};
+use crate::coffee_network::peer_registry::ConnectedPeers;
+use crate::coffee_network::transport::TransportError;
+use crate::coffee_network::{
+    check_nickname, ChatText, ConnectionState, KnownPeer, Message, NetworkController, PeerStatus,
+    VoicePacket, MAX_VOICE_FRAME_SIZE,
+};
+
+// Everything in a voice message besides the frames themselves: the message
+// kind, sender, sequence number and lengths
+const UDP_ENVELOPE_SIZE: usize = 64;
+// Room for the biggest datagram anyone sends: a voice frame and its
+// redundant copy, both as big as they get, in their envelope and sealed.
+// Anything longer would be cut short and fail to open.
+const UDP_BUFFER_SIZE: usize =
+    2 * MAX_VOICE_FRAME_SIZE + UDP_ENVELOPE_SIZE + DATAGRAM_HEADER_SIZE + TAG_SIZE;
+// How often we ping a peer on each channel. Shorter timeouts ping faster so
+// that a few lost pings never add up to one.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(2);
+const UDP_PING_RETRY: Duration = Duration::from_millis(500);
+
+// When we last heard anything at all from the peer on each channel
+#[derive(Debug)]
+struct LastSeen {
+    tcp: Instant,
+    udp: Instant,
+}
 
 #[derive(Serialize, Debug, Deserialize, Clone, Eq, PartialEq, Hash)]
 struct PeerInfo {
-    id: Uuid,
     nickname: String,
     udp_port: u16,
+    // Port our TCP listener accepts other peers on
+    listen_port: u16,
+    // Relays pass on messages from other peers, not just their own
+    relay: bool,
 }
 
 // #[derive(Debug)]
@@ -53,7 +89,36 @@ struct PeerInfo {
 
 #[derive(Clone, Debug)]
 pub struct Peer {
+    // Derived from the identity key the peer proved it holds
+    id: Uuid,
+    identity_key: [u8; IDENTITY_KEY_SIZE],
     info: PeerInfo,
+    agreement: Agreement,
+    local_id: Uuid,
+    // Whether we dialed this peer, rather than it dialing us
+    outbound: bool,
+    // Whether we're a relay, passing other peers' messages on to this one
+    relaying: bool,
+    // Who the peer says it's connected to, so a relay knows what it needs
+    // to pass on
+    direct_peers: Arc<Mutex<HashSet<Uuid>>>,
+    // Who we're connected to ourselves, so we know whose messages we
+    // shouldn't be hearing second hand
+    our_peers: ConnectedPeers,
+    // Whether we've chosen to believe the peer when it passes on messages
+    // from others. Saying it's a relay isn't enough on its own.
+    trusted_relay: Arc<AtomicBool>,
+    // Where the remote's TCP listener can be reached
+    listen_address: SocketAddr,
+    shutdown: Arc<Notify>,
+    closed: Arc<AtomicBool>,
+    // Set when either side closed the connection on purpose
+    said_goodbye: Arc<AtomicBool>,
+    // Silence on either channel for this long means the peer is gone
+    timeout: Duration,
+    last_seen: Arc<Mutex<LastSeen>>,
+    // Everything after the key exchange is encrypted with this
+    session: Arc<Mutex<Session>>,
     // inner: Arc<RwLock<PeerPrivate>>,
     tcp_stream: Arc<RwLock<TcpStream>>,
     udp_socket: Arc<RwLock<UdpSocket>>,
@@ -66,48 +131,103 @@ impl Peer {
     pub async fn new(
         mut tcp_stream: TcpStream,
         net: NetworkController,
-    ) -> Result<Self, Box<dyn Error>> {
-        let mut buf = [0u8; 1024];
+        outbound: bool,
+    ) -> Result<Self, TransportError> {
+        // Make sure we speak the same protocol before anything else
+        let mut tcp_decoder = FrameDecoder::new();
+        let (agreement, hellos) = handshake::negotiate_protocol(
+            &mut tcp_stream,
+            &mut tcp_decoder,
+            Capabilities::supported(),
+            outbound,
+        )
+        .await?;
+        debug!(
+            "Negotiated protocol v{} with features: {}",
+            agreement.version, agreement.capabilities
+        );
+        let mut session =
+            handshake::exchange_keys(&mut tcp_stream, &mut tcp_decoder, &hellos, outbound).await?;
+        // Nothing about us goes out until we know they belong in the room
+        handshake::admit(
+            &mut tcp_stream,
+            &mut tcp_decoder,
+            &mut session,
+            net.get_room_key().await.as_ref(),
+            outbound,
+        )
+        .await?;
+        let identity_key = handshake::exchange_identities(
+            &mut tcp_stream,
+            &mut tcp_decoder,
+            &mut session,
+            &net.get_identity().await,
+            outbound,
+        )
+        .await?;
+        let id = identity::peer_id_for(&identity_key);
 
         // Open UDP conneciton and get port number
         let mut local_address = net.get_address().await;
         local_address.set_port(0); // bind to dynamic port
-        let udp_socket = UdpSocket::bind(local_address).await.unwrap();
-        let udp_port = udp_socket.local_addr().unwrap().port();
+        let udp_socket = UdpSocket::bind(local_address).await?;
+        let udp_port = udp_socket.local_addr()?.port();
 
         // Construct local peer info to send to remote
+        let local_id = net.get_local_id().await;
         let local_peer_info = PeerInfo {
-            id: net.get_local_id().await,
             nickname: net.get_local_nick().await,
             udp_port,
+            listen_port: net.get_address().await.port(),
+            relay: net.is_relay().await,
         };
 
         // Write to remote
-        if let Ok(v) = bincode::serialize::<PeerInfo>(&local_peer_info) {
-            if tcp_stream.write(&v).await.is_ok() {
-                println!("Sending local peer info: {:?}", local_peer_info);
-            } else {
-                println!("Error writing handshake data on tcp stream");
-            }
-        } else {
-            println!("Error serializing handshake info");
-        }
-
-        // Receive initial PeerInfo from the remote connection
-        let read_count = tcp_stream.read(&mut buf).await?;
-        let info = bincode::deserialize::<PeerInfo>(&buf[..read_count])?;
-        println!("Received remote peer info: {:?}", info);
+        handshake::write_sealed_frame(&mut tcp_stream, &mut session, &local_peer_info).await?;
+        trace!("Sent local peer info: {:?}", local_peer_info);
+
+        // Receive initial PeerInfo from the remote connection. Anything the
+        // remote sends right after it stays in the decoder for the poll loop.
+        let info = handshake::read_sealed_frame::<PeerInfo>(
+            &mut tcp_stream,
+            &mut tcp_decoder,
+            &mut session,
+        )
+        .await?;
+        debug!("Received remote peer info from {}: {:?}", id, info);
+        check_nickname(&info.nickname).map_err(HandshakeError::Incompatible)?;
 
         // Connect the UDP socket to remote's address and  UDP port
         let mut remote_address = tcp_stream.peer_addr()?;
         remote_address.set_port(info.udp_port);
         udp_socket.connect(remote_address).await?;
+        let mut listen_address = remote_address;
+        listen_address.set_port(info.listen_port);
 
         // Create server channel bindings
         let server_tx = net.get_server_sender().await;
         let broadcast_rx = net.get_broadcast_receiver().await;
         let peer = Peer {
+            id,
+            identity_key,
             info,
+            agreement,
+            local_id,
+            outbound,
+            relaying: local_peer_info.relay,
+            direct_peers: Arc::new(Mutex::new(HashSet::new())),
+            our_peers: net.get_connected_peers().await,
+            trusted_relay: Arc::new(AtomicBool::new(net.trusts_relay(&identity_key).await)),
+            listen_address,
+            shutdown: Arc::new(Notify::new()),
+            closed: Arc::new(AtomicBool::new(false)),
+            said_goodbye: Arc::new(AtomicBool::new(false)),
+            timeout: net.get_peer_timeout().await,
+            last_seen: Arc::new(Mutex::new(LastSeen {
+                tcp: Instant::now(),
+                udp: Instant::now(),
+            })),
+            session: Arc::new(Mutex::new(session)),
             // inner: Arc::new(RwLock::new(PeerPrivate {
             tcp_stream: Arc::new(RwLock::new(tcp_stream)),
             udp_socket: Arc::new(RwLock::new(udp_socket)),
@@ -116,11 +236,176 @@ impl Peer {
             server_tx: Arc::new(RwLock::new(server_tx)),
             // })),
         };
-        peer.start_polling();
+        peer.start_polling(tcp_decoder);
 
         Ok(peer)
     }
 
+    pub fn id(&self) -> Uuid {
+        self.id
+    }
+
+    pub fn identity_key(&self) -> [u8; IDENTITY_KEY_SIZE] {
+        self.identity_key
+    }
+
+    pub fn is_outbound(&self) -> bool {
+        self.outbound
+    }
+
+    /// How other peers can find this one.
+    pub fn known_peer(&self) -> KnownPeer {
+        KnownPeer {
+            id: self.id,
+            nickname: self.info.nickname.clone(),
+            address: self.listen_address,
+        }
+    }
+
+    pub fn set_nickname(&mut self, nickname: &str) {
+        self.info.nickname = nickname.to_string();
+    }
+
+    /// How the connection looks to the user, as of now.
+    pub fn status(&self) -> PeerStatus {
+        PeerStatus {
+            peer: self.known_peer(),
+            state: ConnectionState::Connected,
+            relay: self.info.relay,
+            trusted_relay: self.speaks_for_others(),
+            identity_key: self.identity_key,
+            fingerprint: identity::fingerprint(&self.identity_key),
+        }
+    }
+
+    /// Says goodbye to the peer and stops the poll loop, which drops the
+    /// connection.
+    pub fn close(&self) {
+        self.said_goodbye.store(true, Ordering::SeqCst);
+        self.shutdown.notify();
+    }
+
+    /// Stops the poll loop without saying goodbye, for a second connection
+    /// to someone we're keeping another connection to. They aren't going
+    /// anywhere, so the remote mustn't be told they left; if this was its
+    /// only connection so far, it sees a dropout until ours arrives.
+    pub fn drop_duplicate(&self) {
+        self.shutdown.notify();
+    }
+
+    /// True once the poll loop has stopped, for whatever reason.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// True if the connection was closed on purpose by either side, rather
+    /// than dropping out from under us.
+    pub fn ended_cleanly(&self) -> bool {
+        self.said_goodbye.load(Ordering::SeqCst)
+    }
+
+    // Relay fns
+    fn lock_direct_peers(&self) -> MutexGuard<'_, HashSet<Uuid>> {
+        // Always replaced whole, so never seen half-updated
+        match self.direct_peers.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    fn reaches_directly(&self, id: Uuid) -> bool {
+        self.lock_direct_peers().contains(&id)
+    }
+
+    fn set_direct_peers(&self, known: &[KnownPeer]) {
+        *self.lock_direct_peers() = known.iter().map(|k| k.id).collect();
+    }
+
+    // Whether a message from `sender` should go out to this peer. Everyone
+    // in the room connects to everyone else, so normally only our own
+    // messages are sent on, since relaying would deliver them twice. A
+    // relay also sends them on to anyone it can't see a direct route for.
+    fn should_forward(&self, sender: Uuid) -> bool {
+        if sender == self.local_id {
+            return true;
+        }
+        self.relaying && sender != self.id && !self.reaches_directly(sender)
+    }
+
+    pub fn set_trusted_relay(&self, trusted: bool) {
+        self.trusted_relay.store(trusted, Ordering::SeqCst);
+    }
+
+    // Whether the peer is a relay we've chosen to trust
+    fn speaks_for_others(&self) -> bool {
+        self.info.relay && self.trusted_relay.load(Ordering::SeqCst)
+    }
+
+    // Who really sent a message the peer passed on, or None if we don't
+    // believe it. Everyone speaks for themselves. A trusted relay also
+    // speaks for others, but only those we aren't connected to; we'd hear
+    // from them directly otherwise, so that's a forgery or a duplicate.
+    fn sender_of(&self, claimed: Uuid) -> Option<Uuid> {
+        let believable = claimed == self.id
+            || (self.speaks_for_others()
+                && claimed != self.local_id
+                && !self.our_peers.contains(claimed));
+        if believable {
+            Some(claimed)
+        } else {
+            debug!(
+                "Not taking {}'s word for a message from {}",
+                self.id, claimed
+            );
+            None
+        }
+    }
+
+    // Heartbeat fns
+    fn saw_tcp(&self) {
+        if let Ok(mut last_seen) = self.last_seen.lock() {
+            last_seen.tcp = Instant::now();
+        }
+    }
+
+    fn saw_udp(&self) {
+        if let Ok(mut last_seen) = self.last_seen.lock() {
+            last_seen.udp = Instant::now();
+        }
+    }
+
+    // Names the channel the peer has gone quiet on, if any
+    fn silent_channel(&self) -> Option<&'static str> {
+        let last_seen = self.last_seen.lock().ok()?;
+        if last_seen.tcp.elapsed() > self.timeout {
+            Some("TCP")
+        } else if last_seen.udp.elapsed() > self.timeout {
+            Some("UDP")
+        } else {
+            None
+        }
+    }
+
+    fn heartbeat_interval(&self) -> Duration {
+        HEARTBEAT_INTERVAL.min(self.timeout / 3)
+    }
+
+    async fn send_heartbeat(&mut self) -> Result<(), TransportError> {
+        self.send_tcp(&PeerMessageTcp::Ping).await?;
+        self.send_udp(&PeerMessageUdp::Ping).await?;
+        Ok(())
+    }
+
+    // Crypto fns
+    fn lock_session(&self) -> MutexGuard<'_, Session> {
+        // The session is never left half-updated, so a panic elsewhere
+        // doesn't make it unusable
+        match self.session.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
     // UDP fns
     async fn is_udp_pong_ok(&self) -> bool {
         *self.udp_pong_ok.read().await
@@ -138,23 +423,43 @@ impl Peer {
         self.udp_socket.write().await.send(bytes).await
     }
 
-    async fn handle_udp_read(&mut self, read: io::Result<usize>, bytes: &[u8]) -> Result<(), ()> {
-        println!("Peer received udp signal");
-        if let Ok(peer_message) = PeerMessageUdp::new_from_read(read, &bytes) {
-            match peer_message {
-                PeerMessageUdp::Ping => {
-                    println!("Received UDP PING!!");
-                    if let Ok(ping_bytes) = bincode::serialize(&PeerMessageUdp::Pong {}) {
-                        if self.udp_write(&ping_bytes).await.is_err() {
-                            println!("Error sending  UDP pong");
-                        }
-                    }
+    async fn send_udp(&mut self, message: &PeerMessageUdp) -> Result<usize, TransportError> {
+        let plaintext = bincode::serialize(message)?;
+        let datagram = self.lock_session().seal_datagram(&plaintext)?;
+        Ok(self.udp_write(&datagram).await?)
+    }
+
+    // Only fails if the connection can't go on; datagrams that are lost or
+    // don't check out are just dropped, since UDP loses some anyway
+    async fn handle_udp_read(
+        &mut self,
+        read: io::Result<usize>,
+        bytes: &[u8],
+    ) -> Result<(), TransportError> {
+        let peer_message =
+            match PeerMessageUdp::new_from_read(read, bytes, &mut self.lock_session()) {
+                Ok(peer_message) => peer_message,
+                Err(e) => {
+                    debug!("Dropping datagram from {}: {}", self.id, e);
+                    return Ok(());
                 }
-                PeerMessageUdp::Pong => {
-                    println!("Received UDP PONG!!");
-                    self.set_udp_pong_ok().await;
+            };
+        self.saw_udp();
+        match peer_message {
+            PeerMessageUdp::Ping => {
+                trace!("UDP ping from {}", self.id);
+                if let Err(e) = self.send_udp(&PeerMessageUdp::Pong).await {
+                    debug!("Error sending UDP pong to {}: {}", self.id, e);
+                }
+            }
+            PeerMessageUdp::Pong => {
+                trace!("UDP pong from {}", self.id);
+                self.set_udp_pong_ok().await;
+            }
+            PeerMessageUdp::VoiceData(sender, packet) => {
+                if let Some(sender) = self.sender_of(sender) {
+                    self.server_send(Message::VoiceChat(sender, packet)).await?;
                 }
-                PeerMessageUdp::VoiceData(_, _) => {}
             }
         }
         Ok(())
@@ -165,41 +470,82 @@ impl Peer {
         self.tcp_stream.write().await.read(bytes).await
     }
 
-    async fn tcp_write(&mut self, bytes: &[u8]) -> io::Result<usize> {
-        println!("Sending TCP to peer: {:?}", self);
-        self.tcp_stream.write().await.write(bytes).await
+    async fn tcp_write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        trace!("Sending {} bytes over TCP to {}", bytes.len(), self.id);
+        self.tcp_stream.write().await.write_all(bytes).await
     }
 
-    async fn handle_tcp_read(&mut self, read: io::Result<usize>, bytes: &[u8]) -> Result<(), ()> {
-        println!("Peer received tcp signal");
-        let count = match read {
-            Ok(c) => c,
-            Err(e) => {
-                println!("Error reading TCP: {}", e);
-                return Err(());
-            }
-        };
+    async fn send_tcp(&mut self, message: &PeerMessageTcp) -> Result<(), TransportError> {
+        let plaintext = bincode::serialize(message)?;
+        let sealed = self.lock_session().seal_frame(&plaintext)?;
+        let frame = encode_frame_body(&sealed)?;
+        Ok(self.tcp_write(&frame).await?)
+    }
+
+    async fn handle_tcp_read(
+        &mut self,
+        read: io::Result<usize>,
+        bytes: &[u8],
+        decoder: &mut FrameDecoder,
+    ) -> Result<(), TransportError> {
+        let count = read?;
         if count < 1 {
-            println!("No data read (count < 1)");
-            return Err(());
+            return Err(TransportError::Closed);
         }
 
-        // TODO: maybe need to wait for a null terminator and
-        // break messages apart?
-        if let Ok(peer_message) = bincode::deserialize::<PeerMessageTcp>(&bytes[..count]) {
+        self.saw_tcp();
+
+        // A read can hold part of a message, or several of them
+        decoder.push(&bytes[..count]);
+        loop {
+            let body = match decoder.next_frame_body() {
+                Ok(Some(body)) => body,
+                Ok(None) => break,
+                Err(e @ FrameError::Oversized(_)) => return Err(e.into()),
+                Err(e) => {
+                    warn!("Skipping bad frame from {}: {}", self.id, e);
+                    continue;
+                }
+            };
+            // Frames are numbered implicitly, so one that doesn't decrypt
+            // leaves us out of step with the peer for good
+            let plaintext = self.lock_session().open_frame(&body)?;
+            let peer_message = match bincode::deserialize::<PeerMessageTcp>(&plaintext) {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("Skipping message from {} we can't read: {}", self.id, e);
+                    continue;
+                }
+            };
             match peer_message {
-                PeerMessageTcp::Ping => {} // TODO
-                PeerMessageTcp::Pong => {} // TODO
-                PeerMessageTcp::ChatEvent(_sender, text) => {
-                    println!("Message received: {}", text);
-                    let msg = Message::TextChat(self.info.id, text);
-                    if let Err(_err) = self.server_send(msg).await {
-                        return Err(());
+                PeerMessageTcp::Ping => self.send_tcp(&PeerMessageTcp::Pong).await?,
+                // Hearing anything at all is what counts
+                PeerMessageTcp::Pong => {}
+                PeerMessageTcp::Goodbye => {
+                    self.said_goodbye.store(true, Ordering::SeqCst);
+                    return Err(TransportError::Closed);
+                }
+                PeerMessageTcp::ChatEvent(sender, text) => {
+                    trace!("Chat from {} via {}: {}", sender, self.id, text.body);
+                    if let Some(sender) = self.sender_of(sender) {
+                        self.server_send(Message::TextChat(sender, text)).await?;
+                    }
+                }
+                PeerMessageTcp::NicknameChanged(sender, nickname) => {
+                    if let Err(reason) = check_nickname(&nickname) {
+                        warn!("Ignoring rename from {}: {}", self.id, reason);
+                        continue;
+                    }
+                    if let Some(sender) = self.sender_of(sender) {
+                        self.server_send(Message::NicknameChanged(sender, nickname))
+                            .await?;
                     }
                 }
+                PeerMessageTcp::KnownPeers(known) => {
+                    self.set_direct_peers(&known);
+                    self.server_send(Message::KnownPeers(known)).await?;
+                }
             }
-        } else {
-            println!("Error deserializing message");
         }
         Ok(())
     }
@@ -208,136 +554,265 @@ impl Peer {
         self.broadcast_rx.write().await.recv().await
     }
 
-    async fn server_send(&mut self, msg: Message) -> Result<(), mpsc::error::SendError<Message>> {
-        self.server_tx.write().await.send(msg).await
+    async fn server_send(&mut self, msg: Message) -> Result<(), TransportError> {
+        self.server_tx
+            .write()
+            .await
+            .send(msg)
+            .await
+            .map_err(|_| TransportError::Detached)
     }
 
-    // TODO: Make this return result so that loop can fail on failure
-    async fn wait_for_udp_ping(&self) {
-        let mut udp_buf = [0u8; 1024];
+    // Pings the peer over UDP until it answers. Gives up if it hasn't after
+    // the peer timeout, since a firewall in the way won't go away by itself.
+    async fn wait_for_udp_ping(&self) -> Result<(), TransportError> {
+        let mut udp_buf = [0u8; UDP_BUFFER_SIZE];
         let mut peer = self.clone();
+        let mut give_up = tokio::time::delay_for(self.timeout);
         loop {
-            let mut delay = tokio::time::delay_for(tokio::time::Duration::from_millis(500));
+            peer.send_udp(&PeerMessageUdp::Ping).await?;
+            let mut retry = tokio::time::delay_for(UDP_PING_RETRY);
             tokio::select! {
-                _ = &mut delay => {
-                    if let Ok(ping_bytes) = bincode::serialize(&PeerMessageUdp::Ping {}) {
-                        if peer.udp_write(&ping_bytes).await.is_err() {
-                            println!("Error sending  UDP ping");
-                            break;
-                        }
-                        println!("Sending UDP Ping");
-                    }
+                _ = self.shutdown.notified() => {
+                    return Err(TransportError::Closed);
                 },
+                _ = &mut give_up => {
+                    return Err(TransportError::TimedOut("UDP"));
+                },
+                _ = &mut retry => {},
                 udp_read = self.udp_read(&mut udp_buf) => {
-                    println!("Read UDP...");
                     if peer.handle_udp_read(udp_read, &udp_buf).await.is_ok() && self.is_udp_pong_ok().await {
-                        break;
+                        return Ok(());
                     }
                 }
             };
         }
     }
 
-    fn start_polling(&self) {
+    fn start_polling(&self, mut tcp_decoder: FrameDecoder) {
         let mut peer = self.clone();
-        let mut udp_buf = [0u8; 1024];
+        let mut udp_buf = [0u8; UDP_BUFFER_SIZE];
         let mut tcp_buf = [0u8; 1024];
         tokio::spawn(async move {
-            if let Ok(ping_bytes) = bincode::serialize(&PeerMessageUdp::Ping {}) {
-                if peer.udp_write(&ping_bytes).await.is_err() {
-                    println!("Error sending  UDP ping");
-                    return;
-                }
-            }
-            // TODO: Check result for failure here
-            peer.wait_for_udp_ping().await;
-            println!("Starting peer poll loop... {:?}", peer);
-            loop {
+            // Why the connection ended, unless we ended it ourselves
+            let mut ended = peer.wait_for_udp_ping().await.err();
+            // Only start the clock once both channels are up
+            peer.saw_tcp();
+            peer.saw_udp();
+            let mut heartbeat = tokio::time::interval(peer.heartbeat_interval());
+            debug!("Polling {} ({})", peer.info.nickname, peer.id);
+            while ended.is_none() {
                 tokio::select! {
+                    _ = peer.shutdown.notified() => {
+                        if peer.ended_cleanly() {
+                            if let Err(e) = peer.send_tcp(&PeerMessageTcp::Goodbye).await {
+                                debug!("Error saying goodbye to {}: {}", peer.id, e);
+                            }
+                        }
+                        break;
+                    },
+                    _ = heartbeat.tick() => {
+                        if let Some(channel) = peer.silent_channel() {
+                            ended = Some(TransportError::TimedOut(channel));
+                        } else if let Err(e) = peer.send_heartbeat().await {
+                            ended = Some(e);
+                        }
+                    },
                     udp_read = peer.udp_read(&mut udp_buf) => {
-                        println!("UDP came in to peer");
-                        if peer.handle_udp_read(udp_read, &udp_buf).await.is_err() {break;}
+                        ended = peer.handle_udp_read(udp_read, &udp_buf).await.err();
                     },
                     tcp_read = peer.tcp_read(&mut tcp_buf) => {
-                        println!("TCP came in to peer");
-                        if peer.handle_tcp_read(tcp_read, &tcp_buf).await.is_err() {break;}
+                        ended = peer.handle_tcp_read(tcp_read, &tcp_buf, &mut tcp_decoder).await.err();
                     },
                     recv_result = peer.server_recv() => {
-                        println!("Broadcast came in to peer");
-                        match recv_result {
-                            Ok(msg) => {
-                                println!("Peer received broadcast: {:?}", msg);
-                                match msg {
-                                    Message::_Connect(_) => {}
-                                    Message::Disconnect(_) => {}
-                                    Message::TextChat(sender, text) => {
-                                        if sender == peer.info.id {
-                                            println!("Refusing to send message back to myself");
-                                            continue;
-                                        }
-                                        let peer_message = PeerMessageTcp::ChatEvent(sender, text);
-                                        if let Ok(bytes) = bincode::serialize(&peer_message) {
-                                            if peer.tcp_write(&bytes).await.is_err() {
-                                                println!("Error sending text chat");
-                                                break;
-                                            }
-                                        } else {
-                                            println!("Error  converting message to bytes");
-                                        }
-                                    }
-                                    Message::_VoiceChat(sender, _bytes) => {
-                                        if sender != peer.info.id {
-                                            // TODO: encode and send the data over UDP
-                                        }
-                                    }
-                                }
-                            },
-                            Err(_e) => {},
-                        }
+                        let msg = match recv_result {
+                            Ok(msg) => msg,
+                            // Lagging just means this peer misses a few
+                            Err(broadcast::RecvError::Lagged(_)) => continue,
+                            Err(broadcast::RecvError::Closed) => {
+                                ended = Some(TransportError::Detached);
+                                continue;
+                            }
+                        };
+                        ended = peer.forward(msg).await.err();
                     },
                 };
             }
-            print!("Peer disconnecting {}:", peer.info.id);
+            match &ended {
+                None => debug!("Closed connection to {}", peer.id),
+                Some(TransportError::Closed) => debug!("{} closed the connection", peer.id),
+                Some(e) => info!(
+                    "Lost connection to {} ({}): {}",
+                    peer.info.nickname, peer.id, e
+                ),
+            }
+            peer.closed.store(true, Ordering::SeqCst);
 
             // Send the server a message that we are disconnecting
-            if let Err(_err) = peer.server_send(Message::Disconnect(peer.info.id)).await {
-                return;
-            }
+            let _ = peer
+                .server_send(Message::Disconnect(peer.id, peer.info.nickname.clone()))
+                .await;
         });
     }
+
+    // Passes something from the rest of the app on to the peer, if it's
+    // something they should hear about
+    async fn forward(&mut self, msg: Message) -> Result<(), TransportError> {
+        match msg {
+            Message::Connect(_, _) => {}
+            Message::Disconnect(_, _) => {}
+            Message::IdentityChanged(_, _) => {}
+            Message::RoomChanged(_) => {}
+            Message::TextChat(sender, text) => {
+                if self.should_forward(sender) {
+                    self.send_tcp(&PeerMessageTcp::ChatEvent(sender, text))
+                        .await?;
+                }
+            }
+            Message::NicknameChanged(sender, nickname) => {
+                if self.should_forward(sender) {
+                    self.send_tcp(&PeerMessageTcp::NicknameChanged(sender, nickname))
+                        .await?;
+                }
+            }
+            Message::VoiceChat(sender, packet) => {
+                if self.should_forward(sender)
+                    && self
+                        .agreement
+                        .capabilities
+                        .contains(Capabilities::VOICE_OPUS)
+                {
+                    // Voice that doesn't make it is gone either way
+                    if let Err(e) = self
+                        .send_udp(&PeerMessageUdp::VoiceData(sender, packet))
+                        .await
+                    {
+                        debug!("Error sending voice to {}: {}", self.id, e);
+                    }
+                }
+            }
+            Message::KnownPeers(known) => {
+                // No need to tell the peer about itself. An empty list
+                // still goes out, since a relay needs to know we've lost
+                // the rest.
+                let known: Vec<KnownPeer> = known.into_iter().filter(|k| k.id != self.id).collect();
+                self.send_tcp(&PeerMessageTcp::KnownPeers(known)).await?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 enum PeerMessageTcp {
     Ping,
     Pong,
-    ChatEvent(Uuid, String),
+    ChatEvent(Uuid, ChatText),
+    NicknameChanged(Uuid, String),
+    KnownPeers(Vec<KnownPeer>),
+    Goodbye,
 }
 
 #[derive(Deserialize, Serialize, Clone)]
 enum PeerMessageUdp {
     Ping,
     Pong,
-    VoiceData(Uuid, Vec<u8>),
+    VoiceData(Uuid, VoicePacket),
 }
 
 impl PeerMessageUdp {
-    fn new_from_read(read: io::Result<usize>, bytes: &[u8]) -> Result<Self, ()> {
-        let count = match read {
-            Ok(c) => c,
-            Err(e) => {
-                println!("Error reading TCP: {}", e);
-                return Err(());
-            }
-        };
+    fn new_from_read(
+        read: io::Result<usize>,
+        bytes: &[u8],
+        session: &mut Session,
+    ) -> Result<Self, TransportError> {
+        let count = read?;
         if count < 1 {
-            return Err(());
+            return Err(TransportError::Closed);
         }
 
-        if let Ok(message) = bincode::deserialize::<PeerMessageUdp>(&bytes[..count]) {
-            return Ok(message);
+        // Forged, replayed and stale datagrams all fail here
+        let plaintext = session.open_datagram(&bytes[..count])?;
+        Ok(bincode::deserialize::<PeerMessageUdp>(&plaintext)?)
+    }
+}
+
+#[cfg(test)]
+impl Peer {
+    // A peer with real sockets but nobody on the other end and no poll
+    // loop, for testing the things that keep track of peers
+    pub async fn unconnected(id: Uuid, local_id: Uuid, outbound: bool) -> Self {
+        use crate::coffee_network::crypto::KeyExchange;
+        use tokio::net::TcpListener;
+
+        let loopback: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let mut listener = TcpListener::bind(loopback).await.unwrap();
+        let listen_address = listener.local_addr().unwrap();
+        let tcp_stream = TcpStream::connect(listen_address).await.unwrap();
+        let _ = listener.accept().await.unwrap();
+        let udp_socket = UdpSocket::bind(loopback).await.unwrap();
+        let remote = KeyExchange::new().public_key();
+        let session = KeyExchange::new().finish(remote, b"", outbound).unwrap();
+        let (server_tx, _) = mpsc::channel(1);
+        let (_, broadcast_rx) = broadcast::channel(1);
+
+        Peer {
+            id,
+            identity_key: [0; IDENTITY_KEY_SIZE],
+            info: PeerInfo {
+                nickname: "test".to_string(),
+                udp_port: 0,
+                listen_port: listen_address.port(),
+                relay: false,
+            },
+            agreement: Agreement {
+                version: handshake::PROTOCOL_VERSION,
+                capabilities: Capabilities::supported(),
+            },
+            local_id,
+            outbound,
+            relaying: false,
+            direct_peers: Arc::new(Mutex::new(HashSet::new())),
+            our_peers: ConnectedPeers::default(),
+            trusted_relay: Arc::new(AtomicBool::new(false)),
+            listen_address,
+            shutdown: Arc::new(Notify::new()),
+            closed: Arc::new(AtomicBool::new(false)),
+            said_goodbye: Arc::new(AtomicBool::new(false)),
+            timeout: Duration::from_secs(10),
+            last_seen: Arc::new(Mutex::new(LastSeen {
+                tcp: Instant::now(),
+                udp: Instant::now(),
+            })),
+            session: Arc::new(Mutex::new(session)),
+            tcp_stream: Arc::new(RwLock::new(tcp_stream)),
+            udp_socket: Arc::new(RwLock::new(udp_socket)),
+            udp_pong_ok: Arc::new(RwLock::new(false)),
+            broadcast_rx: Arc::new(RwLock::new(broadcast_rx)),
+            server_tx: Arc::new(RwLock::new(server_tx)),
         }
+    }
+
+    // What the poll loop does on its way out
+    pub fn mark_closed(&self) {
+        self.closed.store(true, Ordering::SeqCst);
+    }
+}
 
-        Err(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_biggest_voice_message_fits_the_receive_buffer() {
+        let packet = VoicePacket {
+            sequence: u32::MAX,
+            data: vec![0xff; MAX_VOICE_FRAME_SIZE],
+            redundant: Some(vec![0xff; MAX_VOICE_FRAME_SIZE]),
+        };
+        let message = PeerMessageUdp::VoiceData(Uuid::new_v4(), packet);
+        let plaintext = bincode::serialize(&message).unwrap();
+        assert!(plaintext.len() <= 2 * MAX_VOICE_FRAME_SIZE + UDP_ENVELOPE_SIZE);
+        assert!(plaintext.len() + DATAGRAM_HEADER_SIZE + TAG_SIZE <= UDP_BUFFER_SIZE);
     }
 }
@@ -0,0 +1,262 @@
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use log::debug;
+use uuid::Uuid;
+
+use crate::coffee_network::identity::IDENTITY_KEY_SIZE;
+use crate::coffee_network::peer::Peer;
+use crate::coffee_network::KnownPeer;
+
+/// The ids of everyone we're connected to, shared with each connection so
+/// it can tell whether a message a relay passed on claims to be from
+/// someone we'd have heard from ourselves.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectedPeers(Arc<Mutex<HashSet<Uuid>>>);
+
+impl ConnectedPeers {
+    fn lock(&self) -> MutexGuard<'_, HashSet<Uuid>> {
+        // Always replaced whole, so never seen half-updated
+        match self.0.lock() {
+            Ok(guard) => guard,
+            Err(poisoned) => poisoned.into_inner(),
+        }
+    }
+
+    pub fn contains(&self, id: Uuid) -> bool {
+        self.lock().contains(&id)
+    }
+}
+
+/// Everyone we currently have a connection to. Keeps at most one live
+/// connection per peer, and tells the caller when someone joins or leaves
+/// the room so that it can let the rest of the app know.
+#[derive(Debug)]
+pub struct PeerRegistry {
+    local_id: Uuid,
+    peers: Vec<Peer>,
+    connected: ConnectedPeers,
+}
+
+impl PeerRegistry {
+    pub fn new(local_id: Uuid) -> Self {
+        PeerRegistry {
+            local_id,
+            peers: vec![],
+            connected: ConnectedPeers::default(),
+        }
+    }
+
+    /// Adds a freshly connected peer, closing it instead if it turns out to
+    /// be a second connection to someone we already have. Returns true if
+    /// the peer is new to the room.
+    ///
+    /// When two peers dial each other at the same time both sides end up
+    /// with two connections, so both sides need to agree on which one to
+    /// keep: the one dialed by the peer with the lower Uuid.
+    pub fn add(&mut self, peer: Peer) -> bool {
+        if peer.id() == self.local_id {
+            debug!("Refusing to connect to ourselves");
+            peer.close();
+            return false;
+        }
+        if peer.is_closed() {
+            // Went away before we even got to it
+            return false;
+        }
+
+        let local_id = self.local_id;
+        let preferred = |p: &Peer| p.is_outbound() == (local_id < p.id());
+        let index = self
+            .peers
+            .iter()
+            .position(|p| p.id() == peer.id() && !p.is_closed());
+        if let Some(index) = index {
+            if preferred(&self.peers[index]) || !preferred(&peer) {
+                debug!("Dropping duplicate connection to {}", peer.id());
+                peer.drop_duplicate();
+            } else {
+                debug!("Replacing connection to {}", peer.id());
+                self.peers.remove(index).drop_duplicate();
+                self.peers.push(peer);
+            }
+            return false;
+        }
+
+        self.peers.push(peer);
+        self.update_connected();
+        true
+    }
+
+    /// Forgets connections that have closed, returning the peers that are
+    /// now gone entirely. A duplicate connection closing doesn't count.
+    pub fn remove_closed(&mut self) -> Vec<Peer> {
+        let (closed, open): (Vec<Peer>, Vec<Peer>) =
+            self.peers.drain(..).partition(|p| p.is_closed());
+        self.peers = open;
+        self.update_connected();
+
+        let mut left: Vec<Peer> = vec![];
+        for peer in closed {
+            if !self.is_connected(peer.id()) && !left.iter().any(|p| p.id() == peer.id()) {
+                left.push(peer);
+            }
+        }
+        left
+    }
+
+    // A connection that has closed stays in the set until it's removed,
+    // which only means a relay's word is doubted a little longer than it
+    // need be
+    fn update_connected(&self) {
+        *self.connected.lock() = self.peers.iter().map(Peer::id).collect();
+    }
+
+    /// Who we're connected to, kept up to date as peers come and go.
+    pub fn connected(&self) -> ConnectedPeers {
+        self.connected.clone()
+    }
+
+    /// Decides whether the peer with `identity_key` may pass on messages
+    /// from others, if it offers to.
+    pub fn set_trusted_relay(&self, identity_key: &[u8; IDENTITY_KEY_SIZE], trusted: bool) {
+        for peer in self
+            .peers
+            .iter()
+            .filter(|p| p.identity_key() == *identity_key)
+        {
+            peer.set_trusted_relay(trusted);
+        }
+    }
+
+    /// Gives every connection to a peer its new nickname.
+    pub fn rename(&mut self, id: Uuid, nickname: &str) {
+        for peer in self.peers.iter_mut().filter(|p| p.id() == id) {
+            peer.set_nickname(nickname);
+        }
+    }
+
+    /// The identity key of a peer we're connected to.
+    pub fn identity_key(&self, id: Uuid) -> Option<[u8; IDENTITY_KEY_SIZE]> {
+        self.peers
+            .iter()
+            .find(|p| p.id() == id && !p.is_closed())
+            .map(Peer::identity_key)
+    }
+
+    pub fn is_connected(&self, id: Uuid) -> bool {
+        self.peers.iter().any(|p| p.id() == id && !p.is_closed())
+    }
+
+    /// Live connections, for when we need to talk to the peers themselves.
+    pub fn connections(&self) -> Vec<Peer> {
+        self.peers
+            .iter()
+            .filter(|p| !p.is_closed())
+            .cloned()
+            .collect()
+    }
+
+    /// The peers we're connected to right now.
+    pub fn snapshot(&self) -> Vec<KnownPeer> {
+        self.peers
+            .iter()
+            .filter(|p| !p.is_closed())
+            .map(|p| p.known_peer())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn low() -> Uuid {
+        Uuid::from_u128(1)
+    }
+
+    fn high() -> Uuid {
+        Uuid::from_u128(2)
+    }
+
+    // Which connection `local` keeps to `remote` after both of them dialed
+    // each other, with the two connections turning up in the given order.
+    // Returns whether the one kept was dialed by `local`.
+    async fn kept_after_crossed_dials(local: Uuid, remote: Uuid, outbound_first: bool) -> bool {
+        let mut registry = PeerRegistry::new(local);
+        let outbound = Peer::unconnected(remote, local, true).await;
+        let inbound = Peer::unconnected(remote, local, false).await;
+        let (first, second) = if outbound_first {
+            (outbound, inbound)
+        } else {
+            (inbound, outbound)
+        };
+        assert!(registry.add(first));
+        assert!(!registry.add(second));
+
+        let kept = registry.connections();
+        assert_eq!(kept.len(), 1);
+        kept[0].is_outbound()
+    }
+
+    #[tokio::test]
+    async fn both_sides_keep_the_connection_the_lower_id_dialed() {
+        for outbound_first in &[true, false] {
+            // The lower id keeps the one it dialed...
+            assert!(kept_after_crossed_dials(low(), high(), *outbound_first).await);
+            // ...and the higher id keeps the one it was dialed on, which is
+            // the same connection seen from the other end
+            assert!(!kept_after_crossed_dials(high(), low(), *outbound_first).await);
+        }
+    }
+
+    #[tokio::test]
+    async fn a_second_connection_from_the_same_side_is_dropped() {
+        let mut registry = PeerRegistry::new(low());
+        assert!(registry.add(Peer::unconnected(high(), low(), false).await));
+        assert!(!registry.add(Peer::unconnected(high(), low(), false).await));
+        assert_eq!(registry.connections().len(), 1);
+        assert_eq!(registry.snapshot().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn refuses_ourselves_and_peers_already_gone() {
+        let mut registry = PeerRegistry::new(low());
+        assert!(!registry.add(Peer::unconnected(low(), low(), true).await));
+        let gone = Peer::unconnected(high(), low(), true).await;
+        gone.mark_closed();
+        assert!(!registry.add(gone));
+        assert!(registry.connections().is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove_closed_reports_only_peers_that_left() {
+        let mut registry = PeerRegistry::new(low());
+        let connected = registry.connected();
+        let leaving = Peer::unconnected(high(), low(), true).await;
+        assert!(registry.add(leaving.clone()));
+        assert!(connected.contains(high()));
+
+        leaving.mark_closed();
+        let left = registry.remove_closed();
+        assert_eq!(left.len(), 1);
+        assert_eq!(left[0].id(), high());
+        assert!(!registry.is_connected(high()));
+        assert!(!connected.contains(high()));
+        assert!(registry.remove_closed().is_empty());
+    }
+
+    #[tokio::test]
+    async fn remove_closed_ignores_a_connection_already_replaced() {
+        let mut registry = PeerRegistry::new(low());
+        let old = Peer::unconnected(high(), low(), true).await;
+        assert!(registry.add(old.clone()));
+        old.mark_closed();
+        // They came back before we noticed the old connection go
+        registry.add(Peer::unconnected(high(), low(), false).await);
+
+        assert!(registry.remove_closed().is_empty());
+        assert!(registry.is_connected(high()));
+        assert_eq!(registry.connections().len(), 1);
+    }
+}
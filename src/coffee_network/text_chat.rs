@@ -1 +1,525 @@
 // A controller for receiving all chat events and broadcasting updates
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, RwLock};
+use uuid::Uuid;
+
+use crate::coffee_network::chat_history::ChatHistory;
+use crate::coffee_network::{Message, NetworkController, RoomKey};
+
+// How much of the room's chat is kept in memory; the oldest goes first
+const DEFAULT_CHAT_CAPACITY: usize = 10_000;
+// How many messages' worth of ids are remembered for spotting copies, as a
+// multiple of how many messages are kept
+const SEEN_PER_MESSAGE: usize = 4;
+// How far subscribers can fall behind before they start missing messages
+const SUBSCRIBER_BACKLOG: usize = 256;
+
+/// Something someone said, as it goes over the wire. The id stays the same
+/// however many ways the message reaches us, so copies can be spotted.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ChatText {
+    pub id: Uuid,
+    pub body: String,
+    /// When it was sent, by the sender's clock
+    pub sent_at: SystemTime,
+}
+
+impl ChatText {
+    pub fn new(body: String) -> Self {
+        ChatText {
+            id: Uuid::new_v4(),
+            body,
+            sent_at: SystemTime::now(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChatMessageKind {
+    /// Something the sender said
+    Text,
+    Joined,
+    Left,
+    /// The sender changed nickname; the body is the new one
+    Renamed,
+    /// The sender's identity key isn't the one we saw last time
+    IdentityChanged,
+}
+
+/// One line of the room's chat: something said, or something that
+/// happened to someone in the room.
+#[derive(Serialize, Deserialize, Clone, Debug, Eq, PartialEq)]
+pub struct ChatMessage {
+    pub id: Uuid,
+    /// Who said it, or who it happened to
+    pub sender: Uuid,
+    /// What the sender went by at the time, if we knew
+    pub nickname: Option<String>,
+    /// When it was said, by the sender's clock, or when it happened
+    pub timestamp: SystemTime,
+    /// When it reached us, by our own clock, which unlike the sender's we
+    /// can vouch for
+    pub received_at: SystemTime,
+    pub body: String,
+    pub kind: ChatMessageKind,
+}
+
+impl ChatMessage {
+    // Something that just happened to `sender`, rather than anything said
+    fn event(sender: Uuid, nickname: Option<String>, kind: ChatMessageKind, body: String) -> Self {
+        let now = SystemTime::now();
+        ChatMessage {
+            id: Uuid::new_v4(),
+            sender,
+            nickname,
+            timestamp: now,
+            received_at: now,
+            body,
+            kind,
+        }
+    }
+}
+
+/// What to look for in the chat. Everything given has to match; case
+/// doesn't matter. Times go by when messages reached us, since anyone can
+/// say they sent something whenever they like.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct ChatQuery {
+    /// Part of what was said
+    pub text: Option<String>,
+    /// Part of the sender's nickname, or the start of their id
+    pub sender: Option<String>,
+    pub since: Option<SystemTime>,
+    pub until: Option<SystemTime>,
+}
+
+impl ChatQuery {
+    pub fn matches(&self, message: &ChatMessage) -> bool {
+        let contains =
+            |haystack: &str, needle: &str| haystack.to_lowercase().contains(&needle.to_lowercase());
+        if let Some(text) = &self.text {
+            if !contains(&message.body, text) {
+                return false;
+            }
+        }
+        if let Some(sender) = &self.sender {
+            let by_nickname = match &message.nickname {
+                Some(nickname) => contains(nickname, sender),
+                None => false,
+            };
+            let by_id = message
+                .sender
+                .to_string()
+                .starts_with(&sender.to_lowercase());
+            if !by_nickname && !by_id {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if message.received_at < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if message.received_at >= until {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+/// The room's chat in the order it reached us, keeping only the most
+/// recent `capacity` messages. Each message is only taken once, however
+/// many times it arrives: ids are remembered for several times as many
+/// messages as are kept, well after the messages themselves have been
+/// dropped, so even a copy that turns up very late isn't taken for
+/// something new.
+#[derive(Debug)]
+pub struct ChatLog {
+    messages: VecDeque<ChatMessage>,
+    seen: HashSet<Uuid>,
+    // The same ids as `seen`, oldest first, so the oldest can be forgotten
+    seen_order: VecDeque<Uuid>,
+    capacity: usize,
+}
+
+impl ChatLog {
+    pub fn new(capacity: usize) -> Self {
+        ChatLog {
+            messages: VecDeque::new(),
+            seen: HashSet::new(),
+            seen_order: VecDeque::new(),
+            capacity: capacity.max(1),
+        }
+    }
+
+    /// Adds a message to the end, returning false if we already had it.
+    pub fn insert(&mut self, message: ChatMessage) -> bool {
+        if !self.seen.insert(message.id) {
+            return false;
+        }
+        self.seen_order.push_back(message.id);
+        if self.seen_order.len() > self.capacity * SEEN_PER_MESSAGE {
+            if let Some(oldest) = self.seen_order.pop_front() {
+                self.seen.remove(&oldest);
+            }
+        }
+        if self.messages.len() == self.capacity {
+            self.messages.pop_front();
+        }
+        self.messages.push_back(message);
+        true
+    }
+
+    /// Up to `count` messages from just before the one with id `before`,
+    /// oldest first, or the latest ones if `before` is None. Empty if
+    /// `before` isn't (or is no longer) in the log.
+    pub fn page(&self, before: Option<Uuid>, count: usize) -> Vec<ChatMessage> {
+        let end = match before {
+            Some(id) => match self.messages.iter().position(|m| m.id == id) {
+                Some(index) => index,
+                None => return vec![],
+            },
+            None => self.messages.len(),
+        };
+        let start = end.saturating_sub(count);
+        self.messages.range(start..end).cloned().collect()
+    }
+
+    /// The message with id `id`, up to `before` messages from just ahead of
+    /// it, and everything after it, oldest first. Empty if it isn't (or is
+    /// no longer) in the log.
+    pub fn around(&self, id: Uuid, before: usize) -> Vec<ChatMessage> {
+        match self.messages.iter().position(|m| m.id == id) {
+            Some(index) => {
+                let start = index.saturating_sub(before);
+                self.messages.range(start..).cloned().collect()
+            }
+            None => vec![],
+        }
+    }
+
+    /// Every message that matches `query`, oldest first.
+    pub fn search(&self, query: &ChatQuery) -> Vec<ChatMessage> {
+        self.messages
+            .iter()
+            .filter(|m| query.matches(m))
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for ChatLog {
+    fn default() -> Self {
+        ChatLog::new(DEFAULT_CHAT_CAPACITY)
+    }
+}
+
+/// What subscribers to the chat hear about.
+#[derive(Clone, Debug)]
+pub enum ChatUpdate {
+    /// A message that wasn't in the chat before
+    Message(ChatMessage),
+    /// We've moved to another room, and the log now holds its chat instead
+    RoomChanged,
+}
+
+#[derive(Debug)]
+struct TextChatControllerPrivate {
+    log: ChatLog,
+    // Where the room's chat is kept between runs, if anywhere
+    history_dir: Option<PathBuf>,
+    history: ChatHistory,
+    // What everyone in the room last went by, so a rename can say who
+    // they used to be
+    nicknames: HashMap<Uuid, String>,
+    broadcast_tx: broadcast::Sender<ChatUpdate>,
+}
+
+/// Keeps the room's chat, turning what the network hears into
+/// `ChatMessage`s for anything that wants to show or record them. With a
+/// `history_dir`, each room's chat is recorded there and picked up again
+/// whenever we're back in that room.
+#[derive(Clone, Debug)]
+pub struct TextChatController {
+    net: NetworkController,
+    inner: Arc<RwLock<TextChatControllerPrivate>>,
+}
+
+impl TextChatController {
+    pub fn new(net: NetworkController, history_dir: Option<PathBuf>) -> Self {
+        let (btx, _) = broadcast::channel(SUBSCRIBER_BACKLOG);
+        let controller = TextChatController {
+            net,
+            inner: Arc::new(RwLock::new(TextChatControllerPrivate {
+                log: ChatLog::default(),
+                history_dir,
+                history: ChatHistory::in_memory(),
+                nicknames: HashMap::new(),
+                broadcast_tx: btx,
+            })),
+        };
+        controller.start_receiver();
+        controller
+    }
+
+    /// Says something to the room. It comes back through the subscription
+    /// like everything else.
+    pub fn send(&self, body: String) {
+        self.net.send_text_message(body);
+    }
+
+    /// Hears about every new message from now on.
+    pub async fn subscribe(&self) -> broadcast::Receiver<ChatUpdate> {
+        self.inner.read().await.broadcast_tx.subscribe()
+    }
+
+    /// The latest `count` messages, along with a subscription that starts
+    /// right after them, so nothing is missed or seen twice in between.
+    pub async fn subscribe_with_history(
+        &self,
+        count: usize,
+    ) -> (Vec<ChatMessage>, broadcast::Receiver<ChatUpdate>) {
+        let inner = self.inner.read().await;
+        (inner.log.page(None, count), inner.broadcast_tx.subscribe())
+    }
+
+    /// Like `subscribe_with_history`, but with the message with id `id`,
+    /// up to `before` messages ahead of it, and everything since. No
+    /// messages if it isn't in the log.
+    pub async fn subscribe_around(
+        &self,
+        id: Uuid,
+        before: usize,
+    ) -> (Vec<ChatMessage>, broadcast::Receiver<ChatUpdate>) {
+        let inner = self.inner.read().await;
+        (inner.log.around(id, before), inner.broadcast_tx.subscribe())
+    }
+
+    /// See `ChatLog::search`.
+    pub async fn search(&self, query: &ChatQuery) -> Vec<ChatMessage> {
+        self.inner.read().await.log.search(query)
+    }
+
+    /// Adds a message to the chat, records it and tells the subscribers,
+    /// unless we've already got it.
+    pub async fn add_message(&self, message: ChatMessage) {
+        let inner = &mut *self.inner.write().await;
+        let nickname = match message.kind {
+            ChatMessageKind::Renamed => Some(&message.body),
+            // Not a nickname they get to keep
+            ChatMessageKind::IdentityChanged => None,
+            _ => message.nickname.as_ref(),
+        };
+        if let Some(nickname) = nickname {
+            inner.nicknames.insert(message.sender, nickname.clone());
+        }
+        if inner.log.insert(message.clone()) {
+            if let Err(e) = inner.history.append(&message) {
+                warn!("Unable to record chat message: {}", e);
+            }
+            // Nobody listening is fine; it's all in the log
+            let _ = inner.broadcast_tx.send(ChatUpdate::Message(message));
+        }
+    }
+
+    // Swaps the chat for that of the room with `room_key`, as recorded
+    // last time we were there
+    async fn change_room(&self, room_key: Option<RoomKey>) {
+        let inner = &mut *self.inner.write().await;
+        inner.history = match &inner.history_dir {
+            Some(dir) => ChatHistory::open(dir, room_key.as_ref(), DEFAULT_CHAT_CAPACITY),
+            None => ChatHistory::in_memory(),
+        };
+        inner.log = ChatLog::default();
+        match inner.history.load() {
+            Ok(messages) => {
+                for message in messages {
+                    inner.log.insert(message);
+                }
+            }
+            Err(e) => warn!("Unable to load chat history: {}", e),
+        }
+        let _ = inner.broadcast_tx.send(ChatUpdate::RoomChanged);
+    }
+
+    fn start_receiver(&self) {
+        let chat = self.clone();
+        tokio::spawn(async move {
+            let mut receiver = chat.net.get_broadcast_receiver().await;
+            // Whatever room we're in to start with
+            chat.change_room(chat.net.get_room_key().await).await;
+            loop {
+                match receiver.recv().await {
+                    Ok(Message::RoomChanged(room_key)) => chat.change_room(room_key).await,
+                    Ok(msg) => {
+                        if let Some(message) = chat.to_chat_message(msg).await {
+                            chat.add_message(message).await;
+                        }
+                    }
+                    Err(broadcast::RecvError::Lagged(count)) => {
+                        warn!("Chat missed {} network messages", count)
+                    }
+                    Err(broadcast::RecvError::Closed) => break,
+                }
+            }
+        });
+    }
+
+    // What a network message means for the chat, if anything
+    async fn to_chat_message(&self, msg: Message) -> Option<ChatMessage> {
+        let message = match msg {
+            Message::TextChat(sender, text) => ChatMessage {
+                id: text.id,
+                sender,
+                nickname: self.net.get_nickname(sender).await,
+                timestamp: text.sent_at,
+                received_at: SystemTime::now(),
+                body: text.body,
+                kind: ChatMessageKind::Text,
+            },
+            Message::Connect(id, nickname) => {
+                ChatMessage::event(id, Some(nickname), ChatMessageKind::Joined, String::new())
+            }
+            Message::Disconnect(id, nickname) => {
+                ChatMessage::event(id, Some(nickname), ChatMessageKind::Left, String::new())
+            }
+            Message::NicknameChanged(id, nickname) => {
+                let old = self.inner.read().await.nicknames.get(&id).cloned();
+                ChatMessage::event(id, old, ChatMessageKind::Renamed, nickname)
+            }
+            Message::IdentityChanged(id, nickname) => ChatMessage::event(
+                id,
+                Some(nickname),
+                ChatMessageKind::IdentityChanged,
+                String::new(),
+            ),
+            Message::VoiceChat(_, _) | Message::KnownPeers(_) | Message::RoomChanged(_) => {
+                return None
+            }
+        };
+        Some(message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn message(body: &str) -> ChatMessage {
+        ChatMessage::event(
+            Uuid::new_v4(),
+            None,
+            ChatMessageKind::Text,
+            body.to_string(),
+        )
+    }
+
+    fn bodies(messages: &[ChatMessage]) -> Vec<String> {
+        messages.iter().map(|m| m.body.clone()).collect()
+    }
+
+    #[test]
+    fn keeps_only_the_latest_messages() {
+        let mut log = ChatLog::new(2);
+        for body in ["one", "two", "three"].iter() {
+            assert!(log.insert(message(body)));
+        }
+        assert_eq!(bodies(&log.page(None, 10)), vec!["two", "three"]);
+    }
+
+    #[test]
+    fn spots_copies_long_after_the_original_was_dropped() {
+        let mut log = ChatLog::new(2);
+        let first = message("first");
+        assert!(log.insert(first.clone()));
+        assert!(!log.insert(first.clone()));
+        // Long gone from the log, but not from memory
+        for i in 0..5 {
+            log.insert(message(&i.to_string()));
+        }
+        assert!(!log.insert(first));
+        assert_eq!(bodies(&log.page(None, 10)), vec!["3", "4"]);
+    }
+
+    #[test]
+    fn around_keeps_what_came_before() {
+        let mut log = ChatLog::new(10);
+        let picked = message("picked");
+        for body in ["one", "two", "three"].iter() {
+            log.insert(message(body));
+        }
+        log.insert(picked.clone());
+        log.insert(message("after"));
+        assert_eq!(
+            bodies(&log.around(picked.id, 2)),
+            vec!["two", "three", "picked", "after"]
+        );
+        assert_eq!(bodies(&log.around(picked.id, 10)).len(), 5);
+        assert!(log.around(Uuid::new_v4(), 2).is_empty());
+    }
+
+    #[test]
+    fn forgets_ids_once_far_enough_behind() {
+        let mut log = ChatLog::new(2);
+        let first = message("first");
+        log.insert(first.clone());
+        // Remembered for as long as there's room for its id...
+        for i in 0..2 * SEEN_PER_MESSAGE - 1 {
+            log.insert(message(&i.to_string()));
+        }
+        assert!(!log.insert(first.clone()));
+        assert_eq!(log.seen.len(), 2 * SEEN_PER_MESSAGE);
+        // ...and no longer
+        log.insert(message("one more"));
+        assert_eq!(log.seen.len(), 2 * SEEN_PER_MESSAGE);
+        assert_eq!(log.seen_order.len(), log.seen.len());
+        assert!(log.insert(first));
+    }
+
+    #[test]
+    fn dates_go_by_when_messages_reached_us() {
+        let now = SystemTime::now();
+        let day = Duration::from_secs(24 * 60 * 60);
+        // Claims to be from last year, but only just got here
+        let mut backdated = message("hello");
+        backdated.timestamp = now - 365 * day;
+        backdated.received_at = now;
+
+        let today = ChatQuery {
+            since: Some(now - day),
+            until: Some(now + day),
+            ..Default::default()
+        };
+        assert!(today.matches(&backdated));
+        let last_year = ChatQuery {
+            until: Some(now - day),
+            ..Default::default()
+        };
+        assert!(!last_year.matches(&backdated));
+    }
+
+    #[test]
+    fn matches_text_and_sender_regardless_of_case() {
+        let mut said = message("Fresh COFFEE is ready");
+        said.nickname = Some("Barista".to_string());
+        let query = |text: &str, sender: &str| ChatQuery {
+            text: Some(text.to_string()),
+            sender: Some(sender.to_string()),
+            ..Default::default()
+        };
+        assert!(query("coffee", "bari").matches(&said));
+        assert!(query("coffee", &said.sender.to_string()[..8]).matches(&said));
+        assert!(!query("tea", "bari").matches(&said));
+        assert!(!query("coffee", "alice").matches(&said));
+    }
+}
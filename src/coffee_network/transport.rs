@@ -0,0 +1,75 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::coffee_network::crypto::CryptoError;
+use crate::coffee_network::framing::FrameError;
+use crate::coffee_network::handshake::HandshakeError;
+
+/// Why a connection to a peer couldn't be made, or stopped working.
+#[derive(Debug)]
+pub enum TransportError {
+    /// Not an address we know how to connect to
+    BadAddress(String),
+    /// A socket couldn't be opened, or the peer couldn't be reached
+    Io(io::Error),
+    Handshake(HandshakeError),
+    /// A frame or message that doesn't decode
+    Frame(FrameError),
+    /// A message that couldn't be sealed or opened; the connection can't
+    /// be trusted any more
+    Crypto(CryptoError),
+    /// The peer said goodbye, or hung up
+    Closed,
+    /// Nothing heard on one of the channels for longer than the timeout
+    TimedOut(&'static str),
+    /// The rest of the app stopped listening to this connection
+    Detached,
+}
+
+impl fmt::Display for TransportError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransportError::BadAddress(address) => write!(f, "bad address \"{}\"", address),
+            TransportError::Io(e) => write!(f, "network I/O error: {}", e),
+            TransportError::Handshake(e) => write!(f, "handshake failed: {}", e),
+            TransportError::Frame(e) => write!(f, "bad message: {}", e),
+            TransportError::Crypto(e) => write!(f, "encryption error: {}", e),
+            TransportError::Closed => write!(f, "connection closed"),
+            TransportError::TimedOut(channel) => write!(f, "timed out on {}", channel),
+            TransportError::Detached => write!(f, "nothing is listening to the connection"),
+        }
+    }
+}
+
+impl Error for TransportError {}
+
+impl From<io::Error> for TransportError {
+    fn from(e: io::Error) -> Self {
+        TransportError::Io(e)
+    }
+}
+
+impl From<HandshakeError> for TransportError {
+    fn from(e: HandshakeError) -> Self {
+        TransportError::Handshake(e)
+    }
+}
+
+impl From<FrameError> for TransportError {
+    fn from(e: FrameError) -> Self {
+        TransportError::Frame(e)
+    }
+}
+
+impl From<bincode::Error> for TransportError {
+    fn from(e: bincode::Error) -> Self {
+        TransportError::Frame(FrameError::Bincode(e))
+    }
+}
+
+impl From<CryptoError> for TransportError {
+    fn from(e: CryptoError) -> Self {
+        TransportError::Crypto(e)
+    }
+}
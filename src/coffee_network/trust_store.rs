@@ -0,0 +1,220 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::coffee_network::check_nickname;
+use crate::coffee_network::identity::IDENTITY_KEY_SIZE;
+
+const TRUST_FILE: &str = "known_identities";
+
+/// What we make of a key someone presented along with their nickname.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Trust {
+    /// Never seen this nickname before; it's remembered as this key's from
+    /// now on
+    FirstSeen,
+    /// This key has gone by this nickname before
+    Known,
+    /// This nickname used to come with a different key. Either they've
+    /// reinstalled, or it's someone else entirely.
+    Changed,
+}
+
+/// The nicknames we've seen each identity key go by, trusted on first use.
+/// One line per key and nickname in the file: the key in hex, then the
+/// nickname. Only nicknames that pass `check_nickname` are ever written, so
+/// one can't spill onto a line of its own.
+#[derive(Debug)]
+pub struct TrustStore {
+    // Where to save; None keeps everything in memory
+    path: Option<PathBuf>,
+    known: HashMap<[u8; IDENTITY_KEY_SIZE], BTreeSet<String>>,
+}
+
+impl TrustStore {
+    pub fn in_memory() -> Self {
+        TrustStore {
+            path: None,
+            known: HashMap::new(),
+        }
+    }
+
+    /// Loads the store kept in `dir`, starting an empty one if there isn't
+    /// one yet. Lines that don't make sense are skipped.
+    pub fn load(dir: &Path) -> io::Result<Self> {
+        let path = dir.join(TRUST_FILE);
+        let mut known: HashMap<_, BTreeSet<String>> = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let mut parts = line.splitn(2, ' ');
+                    let key = parts.next().and_then(decode_key);
+                    match (key, parts.next()) {
+                        (Some(key), Some(nickname)) if check_nickname(nickname).is_ok() => {
+                            known.entry(key).or_default().insert(nickname.to_string());
+                        }
+                        _ => warn!("Skipping bad line in {}: {}", path.display(), line),
+                    }
+                }
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        Ok(TrustStore {
+            path: Some(path),
+            known,
+        })
+    }
+
+    /// Whether any key has been seen going by `nickname`.
+    pub fn knows(&self, nickname: &str) -> bool {
+        self.known.values().any(|names| names.contains(nickname))
+    }
+
+    /// Checks `key` against the keys we've seen go by `nickname`,
+    /// remembering it if there aren't any. A changed key is never saved
+    /// alongside the old one; that's the whole point. Nor is a nickname
+    /// that fails `check_nickname`, though callers should have turned those
+    /// away already.
+    pub fn check(&mut self, nickname: &str, key: &[u8; IDENTITY_KEY_SIZE]) -> Trust {
+        if matches!(self.known.get(key), Some(names) if names.contains(nickname)) {
+            return Trust::Known;
+        }
+        if self.knows(nickname) {
+            return Trust::Changed;
+        }
+        if check_nickname(nickname).is_err() {
+            return Trust::FirstSeen;
+        }
+        self.known
+            .entry(*key)
+            .or_default()
+            .insert(nickname.to_string());
+        if let Err(e) = self.save() {
+            warn!("Unable to save known identities: {}", e);
+        }
+        Trust::FirstSeen
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match &self.path {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut lines: Vec<String> = self
+            .known
+            .iter()
+            .flat_map(|(key, names)| {
+                let key = hex::encode(key);
+                names
+                    .iter()
+                    .map(move |nickname| format!("{} {}\n", key, nickname))
+            })
+            .collect();
+        lines.sort();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, lines.concat())
+    }
+}
+
+fn decode_key(text: &str) -> Option<[u8; IDENTITY_KEY_SIZE]> {
+    let mut key = [0u8; IDENTITY_KEY_SIZE];
+    hex::decode_to_slice(text, &mut key).ok()?;
+    Some(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ALICE: [u8; IDENTITY_KEY_SIZE] = [1; IDENTITY_KEY_SIZE];
+    const MALLORY: [u8; IDENTITY_KEY_SIZE] = [2; IDENTITY_KEY_SIZE];
+
+    // A store in a directory of its own, gone once the test is done with it
+    struct TempStore {
+        dir: PathBuf,
+    }
+
+    impl TempStore {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!(
+                "coffeeshop-trust-{}-{}",
+                name,
+                std::process::id()
+            ));
+            let _ = fs::remove_dir_all(&dir);
+            TempStore { dir }
+        }
+
+        fn load(&self) -> TrustStore {
+            TrustStore::load(&self.dir).unwrap()
+        }
+    }
+
+    impl Drop for TempStore {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn trusts_on_first_use_and_spots_a_new_key() {
+        let mut trust = TrustStore::in_memory();
+        assert_eq!(trust.check("alice", &ALICE), Trust::FirstSeen);
+        assert_eq!(trust.check("alice", &ALICE), Trust::Known);
+        assert_eq!(trust.check("alice", &MALLORY), Trust::Changed);
+        // Still alice's after the impostor showed up
+        assert_eq!(trust.check("alice", &ALICE), Trust::Known);
+    }
+
+    #[test]
+    fn a_key_can_go_by_several_nicknames() {
+        let mut trust = TrustStore::in_memory();
+        trust.check("alice", &ALICE);
+        assert_eq!(trust.check("al", &ALICE), Trust::FirstSeen);
+        assert_eq!(trust.check("alice", &ALICE), Trust::Known);
+        assert_eq!(trust.check("al", &MALLORY), Trust::Changed);
+    }
+
+    #[test]
+    fn remembers_across_loads() {
+        let temp = TempStore::new("reload");
+        temp.load().check("alice", &ALICE);
+        temp.load().check("al ice", &ALICE);
+        let mut trust = temp.load();
+        assert_eq!(trust.check("alice", &ALICE), Trust::Known);
+        assert_eq!(trust.check("al ice", &ALICE), Trust::Known);
+        assert_eq!(trust.check("alice", &MALLORY), Trust::Changed);
+    }
+
+    #[test]
+    fn a_nickname_cant_add_lines_to_the_file() {
+        let temp = TempStore::new("inject");
+        let injected = format!("x\n{} bob", hex::encode(MALLORY));
+        temp.load().check(&injected, &MALLORY);
+        assert!(!temp.load().knows("bob"));
+        assert!(!temp.load().knows(&injected));
+    }
+
+    #[test]
+    fn skips_lines_that_dont_make_sense() {
+        let temp = TempStore::new("bad-lines");
+        fs::create_dir_all(&temp.dir).unwrap();
+        let contents = format!(
+            "not-a-key alice\n{}\n{} bob\n{} \u{7}\n",
+            hex::encode(ALICE),
+            hex::encode(ALICE),
+            hex::encode(MALLORY)
+        );
+        fs::write(temp.dir.join(TRUST_FILE), contents).unwrap();
+        let mut trust = temp.load();
+        assert!(!trust.knows("alice"));
+        assert_eq!(trust.check("bob", &ALICE), Trust::Known);
+        assert!(!trust.knows("\u{7}"));
+    }
+}
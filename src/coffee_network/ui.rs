@@ -1,5 +1,13 @@
+pub mod bookmark_dialog;
 pub mod chat_view;
 pub mod connect_dialog;
+pub mod search_dialog;
+pub mod user_list;
 
+pub use bookmark_dialog::launch_bookmarks_dialog;
 pub use chat_view::ChatView;
-pub use connect_dialog::{launch_connect_dialog, launch_info_dialog};
+pub use connect_dialog::{
+    connect_to, launch_connect_dialog, launch_info_dialog, launch_invite_dialog,
+    launch_join_dialog, launch_nickname_dialog, launch_passphrase_dialog,
+};
+pub use search_dialog::launch_search_dialog;
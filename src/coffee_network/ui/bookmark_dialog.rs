@@ -0,0 +1,111 @@
+use cursive::traits::*;
+use cursive::views::{Button, Dialog, EditView, LinearLayout, ResizedView, SelectView, TextView};
+use cursive::Cursive;
+
+use crate::coffee_app::{Bookmark, ConfigStore};
+use crate::coffee_network::ui::connect_to;
+use crate::coffee_network::NetworkController;
+
+fn bookmark_label(bookmark: &Bookmark) -> String {
+    format!("{} ({})", bookmark.name, bookmark.address)
+}
+
+pub fn launch_bookmarks_dialog(siv: &mut Cursive, net: NetworkController, config: ConfigStore) {
+    let bookmarks = SelectView::<Bookmark>::new()
+        .with_all(
+            config
+                .get()
+                .bookmarks
+                .into_iter()
+                .map(|bookmark| (bookmark_label(&bookmark), bookmark)),
+        )
+        .on_submit(move |s, bookmark: &Bookmark| {
+            s.pop_layer();
+            connect_to(s, &net, bookmark.address.clone());
+        })
+        .with_name("bookmarks");
+
+    let buttons = {
+        let add_config = config.clone();
+        LinearLayout::horizontal()
+            .child(Button::new("Add", move |s| {
+                launch_add_bookmark_dialog(s, add_config.clone())
+            }))
+            .child(Button::new("Remove", move |s| {
+                let removed = s.call_on_name("bookmarks", |v: &mut SelectView<Bookmark>| {
+                    let index = v.selected_id()?;
+                    let bookmark = v.selection()?.as_ref().clone();
+                    v.remove_item(index);
+                    Some(bookmark)
+                });
+                if let Some(Some(bookmark)) = removed {
+                    config.update(|c| c.bookmarks.retain(|b| *b != bookmark));
+                }
+            }))
+            .child(Button::new("Close", |s| {
+                s.pop_layer();
+            }))
+    };
+
+    siv.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(ResizedView::with_min_height(
+                    4,
+                    ResizedView::with_min_width(40, bookmarks.scrollable()),
+                ))
+                .child(buttons),
+        )
+        .title("Bookmarks"),
+    );
+}
+
+fn launch_add_bookmark_dialog(siv: &mut Cursive, config: ConfigStore) {
+    let add = move |s: &mut Cursive| {
+        let text_of = |s: &mut Cursive, name: &str| {
+            s.call_on_name(name, |view: &mut EditView| {
+                view.get_content().trim().to_string()
+            })
+            .unwrap_or_default()
+        };
+        let address = text_of(s, "bookmark_address");
+        if address.is_empty() {
+            return;
+        }
+        let name = match text_of(s, "bookmark_name") {
+            name if name.is_empty() => address.clone(),
+            name => name,
+        };
+        let bookmark = Bookmark { name, address };
+        config.update(|c| c.bookmarks.push(bookmark.clone()));
+        s.pop_layer();
+        s.call_on_name("bookmarks", |v: &mut SelectView<Bookmark>| {
+            v.add_item(bookmark_label(&bookmark), bookmark);
+        });
+    };
+
+    let field = |label: &str, name: &str| {
+        let add = add.clone();
+        LinearLayout::horizontal()
+            .child(TextView::new(label))
+            .child(ResizedView::with_min_width(
+                32,
+                EditView::new()
+                    .on_submit(move |s, _st| add(s))
+                    .with_name(name),
+            ))
+    };
+
+    let main_layout = LinearLayout::vertical()
+        .child(field("Name:", "bookmark_name"))
+        .child(field("Address:", "bookmark_address"))
+        .child(
+            LinearLayout::horizontal()
+                .child(Button::new("Cancel", |s| {
+                    s.pop_layer();
+                }))
+                .child(Button::new("Add", add)),
+        );
+
+    siv.add_layer(Dialog::around(main_layout).title("Add Bookmark"));
+}
@@ -1,11 +1,33 @@
 use std::sync::{Arc, Mutex, MutexGuard};
 
+use chrono::{DateTime, Local};
+
+use cursive::theme::{Effect, PaletteColor, Style};
 use cursive::traits::*;
+use cursive::utils::lines::spans::LinesIterator;
+use cursive::utils::markup::StyledString;
+use cursive::view::scroll::Scroller;
 use cursive::view::Scrollable;
-use cursive::views::{Button, EditView, LinearLayout, Panel, ResizedView, TextContent, TextView};
-use cursive::Cursive;
+use cursive::views::{
+    Button, EditView, LinearLayout, Panel, ResizedView, ScrollView, TextContent, TextView,
+};
+use cursive::{CbSink, Cursive};
+use log::warn;
+use tokio::sync::{broadcast, mpsc};
+use uuid::Uuid;
+
+use crate::coffee_app::ConfigStore;
+use crate::coffee_audio::AudioController;
+use crate::coffee_network::ui::user_list::user_list_panel;
+use crate::coffee_network::{
+    ChatMessage, ChatMessageKind, ChatUpdate, NetworkController, TextChatController,
+};
 
-use crate::coffee_network::{Message, NetworkController};
+// How much of the chat so far to show when the view opens or we move to
+// another room, and how much from before a message we jump to
+const HISTORY_SHOWN: usize = 500;
+// What's shown for a room with nothing said in it yet
+const NEW_CHAT: &str = "[new chat started]\n";
 
 // Internal-only struct for wrapping the Arc<Mutex<...>> around
 struct ChatViewInner {
@@ -15,82 +37,163 @@ struct ChatViewInner {
 #[derive(Clone)]
 pub struct ChatView {
     inner: Arc<Mutex<ChatViewInner>>,
+    // Messages to bring into view, for the task keeping the view up to date
+    jump_tx: mpsc::UnboundedSender<Uuid>,
 }
 
 impl ChatView {
-    fn lock_ref(&self) -> MutexGuard<ChatViewInner> {
+    fn lock_ref(&self) -> MutexGuard<'_, ChatViewInner> {
         // TODO: handle lock errors (what causes a lock error?)
         self.inner.lock().unwrap()
     }
     fn get_text_content(&self) -> TextContent {
         self.lock_ref().chat_content.clone()
     }
+
+    fn append_message(&self, message: &ChatMessage, local_id: Uuid) {
+        let mut line = stamped_message(message, local_id);
+        line.append_plain("\n");
+        self.get_text_content().append(line);
+    }
+
+    // Replaces everything shown with `messages`, picking out the one with
+    // id `highlighted`
+    fn show_messages(&self, messages: &[ChatMessage], local_id: Uuid, highlighted: Option<Uuid>) {
+        let mut text = if messages.is_empty() {
+            StyledString::plain(NEW_CHAT)
+        } else {
+            StyledString::new()
+        };
+        for message in messages {
+            let mut line = stamped_message(message, local_id);
+            if Some(message.id) == highlighted {
+                line = StyledString::styled(line.source(), Effect::Reverse);
+            }
+            text.append(line);
+            text.append_plain("\n");
+        }
+        self.get_text_content().set_content(text);
+    }
+
+    /// Brings the message with id `id` into view: the chat scrolls to put it
+    /// at the top, picked out, with what came before still above it. Does
+    /// nothing if it's no longer in the chat.
+    pub fn jump_to(&self, id: Uuid) {
+        // Only fails once the view has gone
+        let _ = self.jump_tx.send(id);
+    }
+}
+
+// Scrolls the chat so that the message after the `above` lines is at the
+// top, or as near as it can get
+fn scroll_below(view: &mut ScrollView<TextView>, above: &[StyledString]) {
+    // The text has only just been replaced, so lay it out again before
+    // working out how far down anything is
+    let size = view.get_scroller().last_size();
+    view.layout(size);
+    let width = view.content_viewport().width().max(1);
+    let rows: usize = above
+        .iter()
+        .map(|line| LinesIterator::new(line, width).count().max(1))
+        .sum();
+    view.set_offset((0, rows));
+}
+
+// A message as a line of the chat, stamped with the local time it happened
+fn stamped_message(message: &ChatMessage, local_id: Uuid) -> StyledString {
+    let time: DateTime<Local> = message.timestamp.into();
+    let mut line = StyledString::plain(time.format("[%H:%M] ").to_string());
+    line.append(format_message(message, local_id));
+    line
+}
+
+// What to call someone we haven't heard a nickname for, like a peer behind
+// a relay that hasn't told us about them yet
+fn unknown_sender(id: Uuid) -> String {
+    let id = id.to_string();
+    format!("unknown ({})", &id[..8])
+}
+
+pub(super) fn format_message(message: &ChatMessage, local_id: Uuid) -> StyledString {
+    let name = message
+        .nickname
+        .clone()
+        .unwrap_or_else(|| unknown_sender(message.sender));
+    match message.kind {
+        ChatMessageKind::Text => {
+            let mut line = if message.nickname.is_some() {
+                StyledString::styled(name, Effect::Bold)
+            } else {
+                StyledString::styled(name, Effect::Italic)
+            };
+            line.append_plain(format!(": {}", message.body));
+            if message.sender == local_id {
+                // Our own words stand out from everyone else's
+                line = StyledString::styled(
+                    line.source(),
+                    Style::from(PaletteColor::Secondary).combine(Effect::Bold),
+                );
+            }
+            line
+        }
+        ChatMessageKind::Joined => StyledString::plain(format!("{} joined", name)),
+        ChatMessageKind::Left => StyledString::plain(format!("{} disconnected...", name)),
+        ChatMessageKind::Renamed => {
+            StyledString::plain(format!("{} is now known as {}", name, message.body))
+        }
+        ChatMessageKind::IdentityChanged => StyledString::plain(format!(
+            "WARNING: {} is using a different key than before; they may not be who they say",
+            name
+        )),
+    }
 }
 
 impl ChatView {
-    pub fn new(siv: &mut Cursive, net: NetworkController) -> Self {
+    pub fn new(
+        siv: &mut Cursive,
+        net: NetworkController,
+        chat: TextChatController,
+        audio: AudioController,
+        config: ConfigStore,
+    ) -> Self {
+        let (jump_tx, jump_rx) = mpsc::unbounded_channel();
         let cv = ChatView {
             inner: Arc::new(Mutex::new(ChatViewInner {
-                chat_content: TextContent::new("[new chat started]\n"),
+                chat_content: TextContent::new(""),
             })),
+            jump_tx,
         };
+        cv.start_updating(siv.cb_sink().clone(), net.clone(), chat.clone(), jump_rx);
 
-        {
-            let cv = cv.clone();
-            let net = net.clone();
-            tokio::spawn(async move {
-                let mut receiver = net.get_broadcast_receiver().await;
-                loop {
-                    match receiver.recv().await {
-                        Ok(msg) => match msg {
-                            Message::TextChat(sender, text) => {
-                                // TODO: check sender and prepend username or something
-                                cv.get_text_content()
-                                    .append(format!("{}: {}\n", sender, text));
-                            }
-                            Message::Disconnect(sender) => {
-                                cv.get_text_content()
-                                    .append(format!("{} disconnected...\n", sender));
-                            }
-                            _ => unimplemented!(),
-                        },
-                        Err(_e) => {
-                            // TODO: Log error
-                        }
-                    }
-                }
-            });
-        }
-
-        let user_list_panel =
-            Panel::new(LinearLayout::vertical().child(TextView::new("all"))).title("users");
-        let chat_view = TextView::new_with_content(cv.get_text_content()).scrollable();
+        let user_list_panel = user_list_panel(siv, net, audio, config);
+        let chat_view = TextView::new_with_content(cv.get_text_content())
+            .scrollable()
+            .with_name("chat_scroll");
         let typing_box = {
             let edit_view = {
-                let net = net.clone();
+                let chat = chat.clone();
                 ResizedView::with_full_width(
                     EditView::new()
                         .on_submit_mut(move |s, _text| {
-                            let net = net.clone();
+                            let chat = chat.clone();
                             s.call_on_name("message_text_edit", move |view: &mut EditView| {
                                 // println!("Sending!");
                                 let text = view.get_content().to_string();
                                 view.set_content("");
-                                net.send_text_message(text);
+                                chat.send(text);
                             });
                         })
                         .with_name("message_text_edit"),
                 )
             };
             let submit_btn = {
-                let net = net;
                 Button::new("Send", move |s| {
-                    let net = net.clone();
+                    let chat = chat.clone();
                     s.call_on_name("message_text_edit", move |view: &mut EditView| {
                         // println!("Sending!");
                         let text = view.get_content().to_string();
                         view.set_content("");
-                        net.send_text_message(text);
+                        chat.send(text);
                     });
                 })
             };
@@ -111,4 +214,56 @@ impl ChatView {
         ));
         cv
     }
+
+    // Keeps the view up to date with the chat. Moving to another room or
+    // jumping to a message starts a fresh subscription along with what's
+    // shown, so nothing is missed or shown twice.
+    fn start_updating(
+        &self,
+        cb_sink: CbSink,
+        net: NetworkController,
+        chat: TextChatController,
+        mut jump_rx: mpsc::UnboundedReceiver<Uuid>,
+    ) {
+        let cv = self.clone();
+        tokio::spawn(async move {
+            let local_id = net.get_local_id().await;
+            let (history, mut receiver) = chat.subscribe_with_history(HISTORY_SHOWN).await;
+            cv.show_messages(&history, local_id, None);
+            loop {
+                tokio::select! {
+                    update = receiver.recv() => match update {
+                        Ok(ChatUpdate::Message(message)) => cv.append_message(&message, local_id),
+                        Ok(ChatUpdate::RoomChanged) => {
+                            let (history, fresh) = chat.subscribe_with_history(HISTORY_SHOWN).await;
+                            cv.show_messages(&history, local_id, None);
+                            receiver = fresh;
+                        }
+                        Err(broadcast::RecvError::Lagged(count)) => {
+                            warn!("Chat view missed {} messages", count)
+                        }
+                        Err(broadcast::RecvError::Closed) => break,
+                    },
+                    Some(id) = jump_rx.recv() => {
+                        let (messages, fresh) = chat.subscribe_around(id, HISTORY_SHOWN).await;
+                        if messages.is_empty() {
+                            continue;
+                        }
+                        cv.show_messages(&messages, local_id, Some(id));
+                        receiver = fresh;
+                        let above: Vec<StyledString> = messages
+                            .iter()
+                            .take_while(|m| m.id != id)
+                            .map(|m| stamped_message(m, local_id))
+                            .collect();
+                        let _ = cb_sink.send(Box::new(move |s: &mut Cursive| {
+                            s.call_on_name("chat_scroll", |v: &mut ScrollView<TextView>| {
+                                scroll_below(v, &above)
+                            });
+                        }));
+                    }
+                }
+            }
+        });
+    }
 }
@@ -1,7 +1,38 @@
-use crate::coffee_network::NetworkController;
+use crate::coffee_app::ConfigStore;
+use crate::coffee_network::{check_nickname, Invite, NetworkController, RoomKey, TransportError};
 use cursive::traits::*;
 use cursive::views::{Button, Dialog, EditView, LinearLayout, ResizedView, TextContent, TextView};
 use cursive::Cursive;
+use log::warn;
+use std::future::Future;
+
+// Runs a connection attempt in the background, popping up what went wrong
+// if it fails
+fn spawn_connect<F>(siv: &mut Cursive, address: String, connect: F)
+where
+    F: Future<Output = Result<(), TransportError>> + Send + 'static,
+{
+    let cb_sink = siv.cb_sink().clone();
+    tokio::spawn(async move {
+        if let Err(e) = connect.await {
+            warn!("Unable to connect to {}: {}", address, e);
+            let _ = cb_sink.send(Box::new(move |s: &mut Cursive| {
+                s.add_layer(Dialog::info(format!(
+                    "Unable to connect to {}: {}",
+                    address, e
+                )));
+            }));
+        }
+    });
+}
+
+/// Connects to the peer at `address`, letting the user know if it fails.
+pub fn connect_to(siv: &mut Cursive, net: &NetworkController, address: String) {
+    let net = net.clone();
+    spawn_connect(siv, address.clone(), async move {
+        net.connect_to(address).await
+    });
+}
 
 pub fn launch_info_dialog(siv: &mut Cursive, net: NetworkController) {
     let address_content = TextContent::new("[loading]");
@@ -31,8 +62,8 @@ pub fn launch_connect_dialog(siv: &mut Cursive, net: NetworkController) {
                 32,
                 EditView::new()
                     .on_submit(move |s, st| {
-                        net.connect_to(st.to_string());
                         s.pop_layer();
+                        connect_to(s, &net, st.to_string());
                     })
                     .with_name("addr"),
             ))
@@ -46,14 +77,177 @@ pub fn launch_connect_dialog(siv: &mut Cursive, net: NetworkController) {
                     s.pop_layer();
                 }))
                 .child(Button::new("Connect", move |s| {
-                    s.call_on_name("addr", |view: &mut EditView| {
-                        let addr = view.get_content().to_string();
-                        net.connect_to(addr);
-                    });
+                    let addr = s
+                        .call_on_name("addr", |view: &mut EditView| view.get_content().to_string())
+                        .unwrap_or_default();
                     s.pop_layer();
+                    connect_to(s, &net, addr);
                 })),
         )
     };
 
     siv.add_layer(Dialog::around(main_layout));
 }
+
+pub fn launch_passphrase_dialog(siv: &mut Cursive, net: NetworkController) {
+    let set_passphrase = move |s: &mut Cursive| {
+        let passphrase = s
+            .call_on_name("passphrase", |view: &mut EditView| {
+                view.get_content().to_string()
+            })
+            .unwrap_or_default();
+        // An empty passphrase opens the room back up
+        let room_key = if passphrase.is_empty() {
+            None
+        } else {
+            Some(RoomKey::from_passphrase(&passphrase))
+        };
+        let net = net.clone();
+        tokio::spawn(async move { net.set_room_key(room_key).await });
+        s.pop_layer();
+    };
+
+    let main_layout = LinearLayout::vertical()
+        .child(TextView::new(
+            "Everyone in the room needs the same passphrase.\nLeave it empty to let anyone in.",
+        ))
+        .child(
+            LinearLayout::horizontal()
+                .child(TextView::new("Passphrase:"))
+                .child(ResizedView::with_min_width(
+                    32,
+                    EditView::new()
+                        .secret()
+                        .on_submit({
+                            let set_passphrase = set_passphrase.clone();
+                            move |s, _st| set_passphrase(s)
+                        })
+                        .with_name("passphrase"),
+                )),
+        )
+        .child(
+            LinearLayout::horizontal()
+                .child(Button::new("Cancel", |s| {
+                    s.pop_layer();
+                }))
+                .child(Button::new("Set", set_passphrase)),
+        );
+
+    siv.add_layer(Dialog::around(main_layout).title("Room Passphrase"));
+}
+
+pub fn launch_nickname_dialog(siv: &mut Cursive, net: NetworkController, config: ConfigStore) {
+    let cb_sink = siv.cb_sink().clone();
+    tokio::spawn(async move {
+        let current = net.get_local_nick().await;
+        let _ = cb_sink.send(Box::new(move |s: &mut Cursive| {
+            let set_nickname = move |s: &mut Cursive| {
+                let nickname = s
+                    .call_on_name("nickname", |view: &mut EditView| {
+                        view.get_content().trim().to_string()
+                    })
+                    .unwrap_or_default();
+                if let Err(reason) = check_nickname(&nickname) {
+                    s.add_layer(Dialog::info(format!("Can't use that nickname: {}", reason)));
+                    return;
+                }
+                config.update(|c| c.nickname = Some(nickname.clone()));
+                let net = net.clone();
+                tokio::spawn(async move { net.set_local_nick(nickname).await });
+                s.pop_layer();
+            };
+
+            let main_layout = LinearLayout::vertical()
+                .child(
+                    LinearLayout::horizontal()
+                        .child(TextView::new("Nickname:"))
+                        .child(ResizedView::with_min_width(
+                            32,
+                            EditView::new()
+                                .content(current)
+                                .on_submit({
+                                    let set_nickname = set_nickname.clone();
+                                    move |s, _st| set_nickname(s)
+                                })
+                                .with_name("nickname"),
+                        )),
+                )
+                .child(
+                    LinearLayout::horizontal()
+                        .child(Button::new("Cancel", |s| {
+                            s.pop_layer();
+                        }))
+                        .child(Button::new("Change", set_nickname)),
+                );
+
+            s.add_layer(Dialog::around(main_layout).title("Nickname"));
+        }));
+    });
+}
+
+pub fn launch_invite_dialog(siv: &mut Cursive, net: NetworkController) {
+    let invite_content = TextContent::new("[loading]");
+    tokio::spawn({
+        let content = invite_content.clone();
+        async move {
+            let invite = net.create_invite().await;
+            let note = if invite.room_key.is_some() {
+                "Anyone with this invite can join, so share it privately."
+            } else {
+                "This room is open; set a passphrase to keep strangers out."
+            };
+            content.set_content(format!("{}\n\n{}", invite, note));
+        }
+    });
+    siv.add_layer(
+        Dialog::around(TextView::new_with_content(invite_content))
+            .title("Invite")
+            .dismiss_button("Okay"),
+    );
+}
+
+pub fn launch_join_dialog(siv: &mut Cursive, net: NetworkController) {
+    let join = move |s: &mut Cursive| {
+        let text = s
+            .call_on_name("invite", |view: &mut EditView| {
+                view.get_content().to_string()
+            })
+            .unwrap_or_default();
+        match text.parse::<Invite>() {
+            Ok(invite) => {
+                let net = net.clone();
+                s.pop_layer();
+                spawn_connect(s, invite.address.to_string(), async move {
+                    net.join(invite).await
+                });
+            }
+            Err(e) => {
+                s.add_layer(Dialog::info(format!("Unable to use invite: {}", e)));
+            }
+        }
+    };
+
+    let main_layout = LinearLayout::vertical()
+        .child(
+            LinearLayout::horizontal()
+                .child(TextView::new("Invite:"))
+                .child(ResizedView::with_min_width(
+                    48,
+                    EditView::new()
+                        .on_submit({
+                            let join = join.clone();
+                            move |s, _st| join(s)
+                        })
+                        .with_name("invite"),
+                )),
+        )
+        .child(
+            LinearLayout::horizontal()
+                .child(Button::new("Cancel", |s| {
+                    s.pop_layer();
+                }))
+                .child(Button::new("Join", join)),
+        );
+
+    siv.add_layer(Dialog::around(main_layout).title("Join Room"));
+}
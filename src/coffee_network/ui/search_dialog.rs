@@ -0,0 +1,169 @@
+use std::time::SystemTime;
+
+use chrono::{DateTime, Duration, Local, NaiveDate, TimeZone};
+use cursive::traits::*;
+use cursive::utils::markup::StyledString;
+use cursive::views::{
+    Dialog, EditView, LinearLayout, ResizedView, SelectView, TextContent, TextView,
+};
+use cursive::Cursive;
+use uuid::Uuid;
+
+use crate::coffee_network::ui::chat_view::format_message;
+use crate::coffee_network::ui::ChatView;
+use crate::coffee_network::{ChatQuery, NetworkController, TextChatController};
+
+// Most results listed at once; older matches are left off
+const MAX_RESULTS: usize = 200;
+const DATE_FORMAT: &str = "%Y-%m-%d";
+// Longest the clocks ever jump forward by
+const MAX_CLOCK_GAP_MINUTES: i64 = 180;
+
+// The moment `date` starts, in local time. Where the clocks go forward at
+// midnight, there's no midnight that day, so it starts when they land.
+fn start_of(date: NaiveDate) -> Result<SystemTime, String> {
+    let midnight = date.and_hms(0, 0, 0);
+    (0..=MAX_CLOCK_GAP_MINUTES)
+        .find_map(|minutes| {
+            Local
+                .from_local_datetime(&(midnight + Duration::minutes(minutes)))
+                .earliest()
+        })
+        .map(SystemTime::from)
+        .ok_or_else(|| format!("{} doesn't seem to start in local time", date))
+}
+
+// The query filled in on the dialog, or what's wrong with it
+fn read_query(siv: &mut Cursive) -> Result<ChatQuery, String> {
+    let text_of = |s: &mut Cursive, name: &str| {
+        s.call_on_name(name, |view: &mut EditView| {
+            view.get_content().trim().to_string()
+        })
+        .filter(|text| !text.is_empty())
+    };
+    let date_of = |s: &mut Cursive, name: &str| match text_of(s, name) {
+        Some(text) => NaiveDate::parse_from_str(&text, DATE_FORMAT)
+            .map(Some)
+            .map_err(|_| format!("\"{}\" isn't a date like 2020-06-30", text)),
+        None => Ok(None),
+    };
+    let since = date_of(siv, "search_from")?.map(start_of).transpose()?;
+    // The whole of the last day counts
+    let until = date_of(siv, "search_to")?
+        .map(|to| start_of(to + Duration::days(1)))
+        .transpose()?;
+    if let (Some(since), Some(until)) = (since, until) {
+        if since >= until {
+            return Err("\"From\" needs to be no later than \"To\"".to_string());
+        }
+    }
+    Ok(ChatQuery {
+        text: text_of(siv, "search_text"),
+        sender: text_of(siv, "search_sender"),
+        since,
+        until,
+    })
+}
+
+// Looks for messages matching the dialog's query, listing the latest first
+fn search(
+    siv: &mut Cursive,
+    net: &NetworkController,
+    chat: &TextChatController,
+    summary: &TextContent,
+) {
+    let query = match read_query(siv) {
+        Ok(query) => query,
+        Err(e) => {
+            siv.add_layer(Dialog::info(e));
+            return;
+        }
+    };
+    summary.set_content("Searching...");
+    let cb_sink = siv.cb_sink().clone();
+    let net = net.clone();
+    let chat = chat.clone();
+    let summary = summary.clone();
+    tokio::spawn(async move {
+        let local_id = net.get_local_id().await;
+        let found = chat.search(&query).await;
+        summary.set_content(match found.len() {
+            0 => "Nothing found".to_string(),
+            1 => "1 message found".to_string(),
+            n if n > MAX_RESULTS => {
+                format!("{} messages found, showing the latest {}", n, MAX_RESULTS)
+            }
+            n => format!("{} messages found", n),
+        });
+        let results: Vec<(StyledString, Uuid)> = found
+            .iter()
+            .rev()
+            .take(MAX_RESULTS)
+            .map(|message| {
+                // What the dates were matched against
+                let time: DateTime<Local> = message.received_at.into();
+                let mut label = StyledString::plain(time.format("[%Y-%m-%d %H:%M] ").to_string());
+                label.append(format_message(message, local_id));
+                (label, message.id)
+            })
+            .collect();
+        let _ = cb_sink.send(Box::new(move |s: &mut Cursive| {
+            s.call_on_name("search_results", |list: &mut SelectView<Uuid>| {
+                list.clear();
+                list.add_all(results);
+            });
+        }));
+    });
+}
+
+/// Finds messages in the chat by what was said, who said it or when.
+/// Picking one brings it into view in the chat.
+pub fn launch_search_dialog(
+    siv: &mut Cursive,
+    net: NetworkController,
+    chat: TextChatController,
+    chat_view: ChatView,
+) {
+    let summary = TextContent::new("Dates are YYYY-MM-DD; blank fields match everything");
+    let search = {
+        let summary = summary.clone();
+        move |s: &mut Cursive| search(s, &net, &chat, &summary)
+    };
+
+    let field = |label: &str, name: &str| {
+        let search = search.clone();
+        LinearLayout::horizontal()
+            .child(ResizedView::with_fixed_width(8, TextView::new(label)))
+            .child(ResizedView::with_min_width(
+                32,
+                EditView::new()
+                    .on_submit(move |s, _text| search(s))
+                    .with_name(name),
+            ))
+    };
+
+    let results = SelectView::<Uuid>::new()
+        .on_submit(move |s, id: &Uuid| {
+            s.pop_layer();
+            chat_view.jump_to(*id);
+        })
+        .with_name("search_results");
+
+    siv.add_layer(
+        Dialog::around(
+            LinearLayout::vertical()
+                .child(field("Text:", "search_text"))
+                .child(field("Sender:", "search_sender"))
+                .child(field("From:", "search_from"))
+                .child(field("To:", "search_to"))
+                .child(TextView::new_with_content(summary))
+                .child(ResizedView::with_min_height(
+                    8,
+                    ResizedView::with_min_width(60, results.scrollable()),
+                )),
+        )
+        .title("Search Chat")
+        .button("Search", search)
+        .dismiss_button("Close"),
+    );
+}
@@ -0,0 +1,207 @@
+use std::time::Duration;
+
+use cursive::traits::*;
+use cursive::views::{Dialog, Panel, SelectView, TextView};
+use cursive::{CbSink, Cursive};
+use tokio::sync::broadcast;
+
+use crate::coffee_app::ConfigStore;
+use crate::coffee_audio::ui as audio_ui;
+use crate::coffee_audio::AudioController;
+use crate::coffee_network::{ConnectionState, Message, NetworkController, PeerStatus};
+
+// How often speaking indicators (and anyone dropping out) are brought up
+// to date. Joins and leaves show up straight away.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(200);
+
+// One line of the user list
+#[derive(Clone, Debug, PartialEq)]
+struct UserEntry {
+    status: PeerStatus,
+    speaking: bool,
+    muted: bool,
+}
+
+impl UserEntry {
+    fn label(&self) -> String {
+        let mut label = format!(
+            "{} {}",
+            if self.speaking { "●" } else { "○" },
+            self.status.peer.nickname
+        );
+        if self.muted {
+            label.push_str(" [muted]");
+        }
+        if self.status.trusted_relay {
+            label.push_str(" (relay)");
+        } else if self.status.relay {
+            label.push_str(" (untrusted relay)");
+        }
+        if self.status.state == ConnectionState::Reconnecting {
+            label.push_str(" (reconnecting)");
+        }
+        label
+    }
+}
+
+/// The list of everyone in the room, kept up to date for as long as the UI
+/// runs. Selecting someone brings up what can be done about them.
+pub fn user_list_panel(
+    siv: &mut Cursive,
+    net: NetworkController,
+    audio: AudioController,
+    config: ConfigStore,
+) -> impl View {
+    start_refreshing(siv.cb_sink().clone(), net.clone(), audio.clone());
+    let list = SelectView::<UserEntry>::new()
+        .on_submit(move |s, entry: &UserEntry| {
+            launch_user_dialog(s, net.clone(), audio.clone(), config.clone(), entry.clone())
+        })
+        .with_name("user_list");
+    Panel::new(list.scrollable()).title("users")
+}
+
+fn start_refreshing(cb_sink: CbSink, net: NetworkController, audio: AudioController) {
+    tokio::spawn(async move {
+        let mut receiver = net.get_broadcast_receiver().await;
+        let mut ticks = tokio::time::interval(REFRESH_INTERVAL);
+        let mut shown: Vec<UserEntry> = vec![];
+        loop {
+            tokio::select! {
+                _ = ticks.tick() => {}
+                msg = receiver.recv() => match msg {
+                    Ok(Message::Connect(..)) | Ok(Message::Disconnect(..)) => {}
+                    Err(broadcast::RecvError::Closed) => break,
+                    _ => continue,
+                },
+            }
+
+            let entries = user_entries(&net, &audio).await;
+            if entries == shown {
+                continue;
+            }
+            shown = entries.clone();
+            let update = cb_sink.send(Box::new(move |s: &mut Cursive| {
+                s.call_on_name("user_list", |list: &mut SelectView<UserEntry>| {
+                    show_entries(list, entries)
+                });
+            }));
+            if update.is_err() {
+                // The UI has gone away
+                break;
+            }
+        }
+    });
+}
+
+async fn user_entries(net: &NetworkController, audio: &AudioController) -> Vec<UserEntry> {
+    let speaking = audio.get_speaking_peers().await;
+    let settings = audio.get_settings().await;
+    let mut entries: Vec<UserEntry> = net
+        .get_peer_statuses()
+        .await
+        .into_iter()
+        .map(|status| UserEntry {
+            speaking: speaking.contains(&status.peer.id),
+            muted: settings.is_muted(status.peer.id),
+            status,
+        })
+        .collect();
+    entries.sort_by_key(|e| (e.status.peer.nickname.to_lowercase(), e.status.peer.id));
+    entries
+}
+
+fn show_entries(list: &mut SelectView<UserEntry>, entries: Vec<UserEntry>) {
+    // Whoever was selected stays selected, wherever they end up
+    let selected = list.selection().map(|e| e.status.peer.id);
+    list.clear();
+    for entry in entries {
+        list.add_item(entry.label(), entry);
+    }
+    let index = selected.and_then(|id| list.iter().position(|(_, e)| e.status.peer.id == id));
+    if let Some(index) = index {
+        list.set_selection(index);
+    }
+}
+
+// Believes (or stops believing) a relay about who the chat and voice it
+// passes on is from, from now on and next time
+fn set_relay_trusted(
+    net: &NetworkController,
+    config: &ConfigStore,
+    status: &PeerStatus,
+    trusted: bool,
+) {
+    let key = hex::encode(status.identity_key);
+    config.update(|c| {
+        c.trusted_relays.retain(|k| !k.eq_ignore_ascii_case(&key));
+        if trusted {
+            c.trusted_relays.push(key);
+        }
+    });
+    let net = net.clone();
+    let identity_key = status.identity_key;
+    tokio::spawn(async move { net.trust_relay(identity_key, trusted).await });
+}
+
+fn launch_user_dialog(
+    siv: &mut Cursive,
+    net: NetworkController,
+    audio: AudioController,
+    config: ConfigStore,
+    entry: UserEntry,
+) {
+    let status = entry.status;
+    let id = status.peer.id;
+    let state = match status.state {
+        ConnectionState::Connected if status.trusted_relay => "Connected (relay)",
+        ConnectionState::Connected if status.relay => "Connected (untrusted relay)",
+        ConnectionState::Connected => "Connected",
+        ConnectionState::Reconnecting => "Reconnecting",
+    };
+    let details = format!(
+        "{}\nAddress: {}\nKey: {}\nId: {}",
+        state, status.peer.address, status.fingerprint, id
+    );
+
+    let muted = entry.muted;
+    let mute = {
+        let audio = audio.clone();
+        let config = config.clone();
+        move |s: &mut Cursive| {
+            audio_ui::set_peer_muted(&audio, &config, id, !muted);
+            s.pop_layer();
+        }
+    };
+    let nickname = status.peer.nickname.clone();
+    let mut dialog = Dialog::around(TextView::new(details))
+        .title(status.peer.nickname.clone())
+        .button(if muted { "Unmute" } else { "Mute" }, mute)
+        .button("Volume", {
+            let config = config.clone();
+            move |s| {
+                s.pop_layer();
+                audio_ui::launch_peer_volume_dialog(
+                    s,
+                    audio.clone(),
+                    config.clone(),
+                    id,
+                    &nickname,
+                );
+            }
+        });
+    // Only worth asking about for someone offering to relay
+    if status.relay {
+        let trusted = status.trusted_relay;
+        let label = if trusted {
+            "Stop trusting relay"
+        } else {
+            "Trust as relay"
+        };
+        dialog.add_button(label, move |s| {
+            set_relay_trusted(&net, &config, &status, !trusted);
+            s.pop_layer();
+        });
+    }
+    siv.add_layer(dialog.dismiss_button("Close"));
+}
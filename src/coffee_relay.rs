@@ -0,0 +1,133 @@
+use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+use log::{info, warn};
+use structopt::StructOpt;
+use tokio::sync::broadcast;
+
+use crate::coffee_app;
+use crate::coffee_network::{
+    check_nickname, parse_identity_key, ChatMessageKind, ChatUpdate, NetworkController, RoomKey,
+    TextChatController, TransportError, IDENTITY_KEY_SIZE,
+};
+
+#[derive(StructOpt, Debug)]
+pub struct RelayOptions {
+    /// Address to accept peers on
+    #[structopt(short, long, default_value = "0.0.0.0")]
+    bind: IpAddr,
+    /// Port to accept peers on
+    #[structopt(short, long)]
+    port: u16,
+    /// Name the relay shows up as
+    #[structopt(short, long, default_value = "relay", parse(try_from_str = parse_nickname))]
+    nickname: String,
+    /// Only let in peers with this room passphrase
+    #[structopt(long)]
+    passphrase: Option<String>,
+    /// Peers to connect to on startup
+    #[structopt(short, long, number_of_values = 1)]
+    connect: Vec<String>,
+    /// Identity key (in hex) of another relay to believe about who the
+    /// chat and voice it passes on is from
+    #[structopt(long, number_of_values = 1, parse(try_from_str = parse_identity_key))]
+    trust_relay: Vec<[u8; IDENTITY_KEY_SIZE]>,
+    /// Seconds a peer can go unheard before it's dropped
+    #[structopt(long, default_value = "10")]
+    peer_timeout: u64,
+    /// Where to keep the relay's identity [default: "relay" in the app's
+    /// config directory]
+    #[structopt(long, parse(from_os_str))]
+    identity_dir: Option<PathBuf>,
+}
+
+fn parse_nickname(text: &str) -> Result<String, String> {
+    check_nickname(text).map(|()| text.to_string())
+}
+
+/// Runs a relay with no UI: it sits in the room, passing chat and voice on
+/// between peers that can't reach each other, until it's told to stop.
+/// Fails if it can't listen for peers.
+pub async fn run(options: RelayOptions) -> Result<(), TransportError> {
+    // Kept apart from the app's own, so a relay can run alongside it
+    let identity_dir = options
+        .identity_dir
+        .or_else(|| coffee_app::config_dir().map(|dir| dir.join("relay")));
+    let (identity, trust) = coffee_app::load_identity(identity_dir.as_deref());
+    let address = SocketAddr::new(options.bind, options.port);
+    let timeout = Duration::from_secs(options.peer_timeout);
+    // Others need this to trust the relay
+    info!("Relay identity key: {}", hex::encode(identity.public_key()));
+    let trusted_relays = options.trust_relay.into_iter().collect();
+    let net = NetworkController::new(
+        address,
+        options.nickname,
+        identity,
+        trust,
+        trusted_relays,
+        timeout,
+    )?;
+    net.set_relay(true).await;
+    if let Some(passphrase) = &options.passphrase {
+        net.set_room_key(Some(RoomKey::from_passphrase(passphrase)))
+            .await;
+    }
+    for address in options.connect {
+        let net = net.clone();
+        tokio::spawn(async move {
+            if let Err(e) = net.connect_to(address.clone()).await {
+                warn!("Unable to connect to {}: {}", address, e);
+            }
+        });
+    }
+
+    // The log is all the history a relay needs
+    tokio::spawn(log_chat(TextChatController::new(net.clone(), None)));
+    wait_for_shutdown().await;
+
+    info!("Shutting down relay");
+    net.leave().await;
+    Ok(())
+}
+
+// Puts the room's chat in the log too; comings, goings and renames are
+// already there
+async fn log_chat(chat: TextChatController) {
+    let mut receiver = chat.subscribe().await;
+    loop {
+        match receiver.recv().await {
+            Ok(ChatUpdate::Message(message)) if message.kind == ChatMessageKind::Text => info!(
+                "[chat] {} ({}): {}",
+                message.nickname.unwrap_or_default(),
+                message.sender,
+                message.body
+            ),
+            Ok(_) => {}
+            Err(broadcast::RecvError::Lagged(count)) => warn!("Missed {} messages", count),
+            Err(broadcast::RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(unix)]
+async fn wait_for_shutdown() {
+    use tokio::signal::unix::{signal, SignalKind};
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {},
+                _ = terminate.recv() => {},
+            }
+        }
+        Err(e) => {
+            warn!("Unable to listen for SIGTERM: {}", e);
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn wait_for_shutdown() {
+    let _ = tokio::signal::ctrl_c().await;
+}
@@ -0,0 +1,59 @@
+use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use log::{info, warn};
+use structopt::StructOpt;
+
+use crate::coffee_app::Config;
+use crate::coffee_audio::backends::{AudioBackend, NullBackend, WavBackend};
+use crate::coffee_audio::layers::PassthroughLayer;
+use crate::coffee_audio::sources::{FileSource, FilteredSource};
+
+#[derive(StructOpt, Debug)]
+pub struct RenderOptions {
+    /// Sound file to play through the effects
+    #[structopt(parse(from_os_str))]
+    input: PathBuf,
+    /// WAV file to write the result to [default: throw it away]
+    #[structopt(short, long, parse(from_os_str))]
+    output: Option<PathBuf>,
+    /// Leave out the effects, for a reference render to compare against
+    #[structopt(long)]
+    dry: bool,
+}
+
+/// Plays a sound file through the output effects in the config at
+/// `config_file`, as if it were the room, and writes what comes out to a
+/// WAV file. Handy for hearing what the effects do without anyone else
+/// around, and for comparing against a known-good render.
+pub fn run(options: RenderOptions, config_file: Option<&Path>) -> Result<(), Box<dyn Error>> {
+    let config = match config_file.map(Config::load) {
+        Some(Ok(Some((config, _)))) => config,
+        Some(Err(e)) => {
+            warn!("Rendering without the config: {}", e);
+            Config::default()
+        }
+        _ => Config::default(),
+    };
+    let mut source = FilteredSource::new(FileSource::new(&options.input.to_string_lossy()));
+    if options.dry {
+        source.add_filter(PassthroughLayer {});
+    } else {
+        source.set_filters(config.audio.layers.build());
+    }
+    let frames = match &options.output {
+        Some(path) => WavBackend::new(path, None).run(&mut source)?,
+        None => NullBackend::new(None).run(&mut source)?,
+    };
+    if frames == 0 {
+        let message = format!("nothing could be read from {}", options.input.display());
+        return Err(io::Error::new(io::ErrorKind::InvalidData, message).into());
+    }
+    info!(
+        "Rendered {} frames from {}",
+        frames,
+        options.input.display()
+    );
+    Ok(())
+}
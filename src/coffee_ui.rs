@@ -4,52 +4,77 @@ use cursive::traits::*;
 use cursive::views::{Button, Dialog, EditView, LinearLayout, ResizedView, TextView};
 use cursive::Cursive;
 
-use std::sync::{Arc, Mutex};
+use log::{error, warn};
 
-use crate::coffee_app::CoffeeAppContext;
+use crate::coffee_app::{AppOptions, CoffeeAppContext, ConfigStore};
+use crate::coffee_audio::ui as audio_ui;
 use crate::coffee_network::ui::{self, ChatView};
+use crate::coffee_network::{check_nickname, NetworkController};
 
-struct MainUiState {
-    chat_view: Arc<Mutex<ChatView>>,
-}
-
-impl MainUiState {}
-
-pub fn start_ui() {
-    // Create a starup dialog...
+pub fn start_ui(options: AppOptions) {
     let mut siv = Cursive::default();
     siv.set_fps(5);
+    let config = ConfigStore::load(options.config_file());
 
-    let start_fn = |s: &mut Cursive| {
-        // Get username
-        let mut username = "Default User".to_string();
-        s.call_on_name("_usr_nick", |v: &mut EditView| {
-            username = v.get_content().to_string();
-        });
-        // Get port number
-        let mut port_num = 0u16;
-        s.call_on_name("_use_port", |v: &mut EditView| {
-            let port_string = v.get_content().to_string();
-            if let Ok(p) = port_string.parse::<u16>() {
-                port_num = p;
-                println!("Parse port number: {}", p);
-            } else {
-                println!("Couldn't parse port number: {}", port_string);
+    // Nothing to ask about if it was all given on the command line
+    if let (Some(username), Some(port_num)) = (options.nickname.clone(), options.port) {
+        start_app(&mut siv, &options, config, username, port_num);
+    } else {
+        launch_start_dialog(&mut siv, options, config);
+    }
+
+    siv.run();
+}
+
+// Asks for anything we need to start that we weren't given, filled in from
+// last time
+fn launch_start_dialog(siv: &mut Cursive, options: AppOptions, config: ConfigStore) {
+    let saved = config.get();
+    let start_fn = {
+        let options = options.clone();
+        move |s: &mut Cursive| {
+            // Get username
+            let mut username = "Default User".to_string();
+            s.call_on_name("_usr_nick", |v: &mut EditView| {
+                username = v.get_content().to_string();
+            });
+            // Get port number
+            let mut port_num = 0u16;
+            s.call_on_name("_use_port", |v: &mut EditView| {
+                let port_string = v.get_content().to_string();
+                if let Ok(p) = port_string.parse::<u16>() {
+                    port_num = p;
+                } else {
+                    warn!("Couldn't parse port number: {}", port_string);
+                }
+            });
+            s.pop_layer();
+            // Only worth remembering if it worked
+            if start_app(s, &options, config.clone(), username.clone(), port_num) {
+                config.update(|c| {
+                    c.nickname = Some(username);
+                    c.port = Some(port_num);
+                });
             }
-        });
-        s.pop_layer();
-        // Construct the main app context binding and launch the main UI
-        let coffee_app = CoffeeAppContext::construct(port_num, username);
-        launch_main_view(s, coffee_app);
+        }
     };
     let username_line = LinearLayout::horizontal()
         .child(TextView::new("Nickname:"))
         .child(ResizedView::with_min_width(
             32,
             EditView::new()
-                .content("Default Name")
-                .on_submit(move |s, _st| {
-                    start_fn(s);
+                .content(
+                    options
+                        .nickname
+                        .clone()
+                        .or(saved.nickname)
+                        .unwrap_or_else(|| "Default Name".to_string()),
+                )
+                .on_submit({
+                    let start_fn = start_fn.clone();
+                    move |s, _st| {
+                        start_fn(s);
+                    }
                 })
                 .with_name("_usr_nick"),
         ));
@@ -58,9 +83,12 @@ pub fn start_ui() {
         .child(ResizedView::with_min_width(
             32,
             EditView::new()
-                .content("0")
-                .on_submit(move |s, _st| {
-                    start_fn(s);
+                .content(options.port.or(saved.port).unwrap_or(0).to_string())
+                .on_submit({
+                    let start_fn = start_fn.clone();
+                    move |s, _st| {
+                        start_fn(s);
+                    }
                 })
                 .with_name("_use_port"),
         ));
@@ -80,23 +108,82 @@ pub fn start_ui() {
         )
         .title("Coffee Chat Start Options"),
     );
+}
 
-    siv.run();
+// Constructs the main app context binding, launches the main UI, and
+// connects to any peers we were asked to. If we can't start, says why and
+// offers to try again; returns whether we started.
+fn start_app(
+    siv: &mut Cursive,
+    options: &AppOptions,
+    config: ConfigStore,
+    username: String,
+    port_num: u16,
+) -> bool {
+    let address = options.address(&config.get(), port_num);
+    let started = check_nickname(&username)
+        .map_err(|reason| format!("Can't use that nickname: {}", reason))
+        .and_then(|()| {
+            CoffeeAppContext::construct(address, username, options.peer_timeout(), config.clone())
+                .map_err(|e| format!("Unable to start on {}: {}", address, e))
+        });
+    let coffee_app = match started {
+        Ok(coffee_app) => coffee_app,
+        Err(e) => {
+            error!("{}", e);
+            let options = options.clone();
+            siv.add_layer(
+                Dialog::text(e)
+                    .title("Error")
+                    .button("Back", move |s| {
+                        s.pop_layer();
+                        launch_start_dialog(s, options.clone(), config.clone());
+                    })
+                    .button("Quit", |s| s.quit()),
+            );
+            return false;
+        }
+    };
+    for peer in options.connect.iter() {
+        ui::connect_to(siv, coffee_app.get_net_controller(), peer.clone());
+    }
+    launch_main_view(siv, coffee_app);
+    true
 }
 
-fn launch_main_view(mut siv: &mut Cursive, coffee_app: CoffeeAppContext) {
+fn launch_main_view(siv: &mut Cursive, coffee_app: CoffeeAppContext) {
     // Initialize the main Cursive controller
-    let ui_state = Arc::new(Mutex::new(MainUiState {
-        chat_view: Arc::new(Mutex::new(ChatView::new(
-            &mut siv,
-            coffee_app.get_net_controller().clone(),
-        ))),
-    }));
+    let chat_view = ChatView::new(
+        siv,
+        coffee_app.get_net_controller().clone(),
+        coffee_app.get_chat_controller().clone(),
+        coffee_app.get_audio_controller().clone(),
+        coffee_app.get_config().clone(),
+    );
 
     // Create menu
     {
         let mut file_menu = MenuTree::new();
-        file_menu.add_leaf("Quit (Ctrl+Q)", move |s| s.quit());
+        {
+            let net = coffee_app.get_net_controller().clone();
+            let config = coffee_app.get_config().clone();
+            file_menu.add_leaf("Nickname", move |s| {
+                ui::launch_nickname_dialog(s, net.clone(), config.clone())
+            });
+        }
+        let search = {
+            let net = coffee_app.get_net_controller().clone();
+            let chat = coffee_app.get_chat_controller().clone();
+            move |s: &mut Cursive| {
+                ui::launch_search_dialog(s, net.clone(), chat.clone(), chat_view.clone())
+            }
+        };
+        file_menu.add_leaf("Search Chat (Ctrl+F)", search.clone());
+        file_menu.add_leaf("Log (Ctrl+L)", |s| s.toggle_debug_console());
+        {
+            let net = coffee_app.get_net_controller().clone();
+            file_menu.add_leaf("Quit (Ctrl+Q)", move |s| quit(s, &net));
+        }
 
         let mut network_menu = MenuTree::new();
         {
@@ -111,11 +198,87 @@ fn launch_main_view(mut siv: &mut Cursive, coffee_app: CoffeeAppContext) {
                 ui::launch_connect_dialog(s, net.clone())
             });
         }
+        {
+            let net = coffee_app.get_net_controller().clone();
+            let config = coffee_app.get_config().clone();
+            network_menu.add_leaf("Bookmarks", move |s| {
+                ui::launch_bookmarks_dialog(s, net.clone(), config.clone())
+            });
+        }
+
+        network_menu.add_delimiter();
+        {
+            let net = coffee_app.get_net_controller().clone();
+            network_menu.add_leaf("Room Passphrase", move |s| {
+                ui::launch_passphrase_dialog(s, net.clone())
+            });
+        }
+        {
+            let net = coffee_app.get_net_controller().clone();
+            network_menu.add_leaf("Create Invite", move |s| {
+                ui::launch_invite_dialog(s, net.clone())
+            });
+        }
+        {
+            let net = coffee_app.get_net_controller().clone();
+            network_menu.add_leaf("Join Invite", move |s| {
+                ui::launch_join_dialog(s, net.clone())
+            });
+        }
+
+        let mut audio_menu = MenuTree::new();
+        {
+            let audio = coffee_app.get_audio_controller().clone();
+            let net = coffee_app.get_net_controller().clone();
+            let config = coffee_app.get_config().clone();
+            audio_menu.add_leaf("Volume", move |s| {
+                audio_ui::launch_volume_dialog(s, audio.clone(), net.clone(), config.clone())
+            });
+        }
+        {
+            let audio = coffee_app.get_audio_controller().clone();
+            let config = coffee_app.get_config().clone();
+            audio_menu.add_leaf("Effects", move |s| {
+                audio_ui::launch_effects_dialog(s, audio.clone(), config.clone())
+            });
+        }
+        {
+            let audio = coffee_app.get_audio_controller().clone();
+            let config = coffee_app.get_config().clone();
+            audio_menu.add_leaf("Microphone", move |s| {
+                audio_ui::launch_input_dialog(s, audio.clone(), config.clone())
+            });
+        }
+        {
+            let audio = coffee_app.get_audio_controller().clone();
+            let config = coffee_app.get_config().clone();
+            audio_menu.add_leaf("Voice Quality", move |s| {
+                audio_ui::launch_voice_quality_dialog(s, audio.clone(), config.clone())
+            });
+        }
 
         siv.menubar()
             .add_subtree("File", file_menu)
-            .add_subtree("Network", network_menu);
+            .add_subtree("Network", network_menu)
+            .add_subtree("Audio", audio_menu);
         siv.set_autohide_menu(false);
-        siv.add_global_callback(Event::CtrlChar('q'), |s| s.quit());
+        let net = coffee_app.get_net_controller().clone();
+        siv.add_global_callback(Event::CtrlChar('q'), move |s| quit(s, &net));
+        siv.add_global_callback(Event::CtrlChar('l'), |s| s.toggle_debug_console());
+        siv.add_global_callback(Event::CtrlChar('f'), search);
     }
 }
+
+// Lets our peers know we're leaving on purpose, so they don't sit there
+// trying to reconnect to us, before shutting down the UI (and the app).
+fn quit(siv: &mut Cursive, net: &NetworkController) {
+    let (done_tx, done_rx) = std::sync::mpsc::channel();
+    let net = net.clone();
+    tokio::spawn(async move {
+        net.leave().await;
+        let _ = done_tx.send(());
+    });
+    // Leaving has its own timeout, so this won't hang
+    let _ = done_rx.recv();
+    siv.quit();
+}
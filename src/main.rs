@@ -1,6 +1,9 @@
 mod coffee_app;
 mod coffee_audio;
+mod coffee_log;
 mod coffee_network;
+mod coffee_relay;
+mod coffee_render;
 mod coffee_ui;
 
 // use sfml::audio::{SoundStatus, SoundStreamPlayer};
@@ -10,17 +13,64 @@ mod coffee_ui;
 
 use std::error::Error;
 
+use structopt::StructOpt;
+
+use coffee_app::AppOptions;
+use coffee_log::Console;
+use coffee_relay::RelayOptions;
+use coffee_render::RenderOptions;
+
+#[derive(StructOpt, Debug)]
+#[structopt(about = "Voice and text chat for a small room of friends")]
+struct Options {
+    #[structopt(flatten)]
+    app: AppOptions,
+    #[structopt(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+enum Command {
+    /// Run without the UI, relaying chat and voice between peers
+    Relay(RelayOptions),
+    /// Play a sound file through the audio effects, writing the result to
+    /// a WAV file
+    Render(RenderOptions),
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error>> {
-    println!("Hello, world!");
+    let options = Options::from_args();
+    // Without the UI there's nowhere to show the log, and only a log file
+    // when asked for one
+    let (log_file, console) = match &options.command {
+        Some(Command::Relay(_)) => (options.app.log_file.clone(), Console::Stdout),
+        Some(Command::Render(_)) => (options.app.log_file.clone(), Console::Stderr),
+        None => (options.app.log_file(), Console::Panel),
+    };
+    coffee_log::init(options.app.log_level, log_file.as_deref(), console)?;
+    log::info!("Starting coffeeshop {}", env!("CARGO_PKG_VERSION"));
+
+    match options.command {
+        Some(Command::Relay(relay_options)) => {
+            coffee_relay::run(relay_options).await?;
+            return Ok(());
+        }
+        Some(Command::Render(render_options)) => {
+            coffee_render::run(render_options, options.app.config_file().as_deref())?;
+            return Ok(());
+        }
+        None => {}
+    }
 
     // The UI module is in charge of constructing the app context binding.
     // This is mostly because we need to know username and port number
-    // before starting a server, and the UI shows an initial popup for
-    // that purpose. In the future, it would be nice to construct the app
-    // context separately and feed it into the UI instead, but that's
-    // more work on that code than I'm wanting to put in right now.
-    coffee_ui::start_ui();
+    // before starting a server, and unless they were given on the command
+    // line the UI shows an initial popup for that purpose. In the future,
+    // it would be nice to construct the app context separately and feed it
+    // into the UI instead, but that's more work on that code than I'm
+    // wanting to put in right now.
+    coffee_ui::start_ui(options.app);
 
     // Show the default audio input device so we know we have something, at least
     // let default_audio_in_device = sfml::audio::capture::default_device();